@@ -26,15 +26,21 @@ use syn::{parse_quote, Error, Result};
 /// in inherent and trait impls.
 ///
 /// # Kinds of arguments
-/// Memoized functions can take three different kinds of arguments:
+/// Memoized functions can take four different kinds of arguments:
 ///
 /// - _Hashed:_ This is the default. These arguments are hashed into a
-///   high-quality 128-bit hash, which is used as a cache key.
+///   high-quality 128-bit hash, which is used as a cache key. Arguments are
+///   streamed into the hasher through their [`Hash`](std::hash::Hash)
+///   implementation. Comemo never clones, converts to owned or collects an
+///   argument to compute the key, so hashing a large borrowed slice does not
+///   allocate a copy of it.
 ///
 /// - _Immutably tracked:_ The argument is of the form `Tracked<T>`. These
 ///   arguments enjoy fine-grained access tracking. This allows cache hits to
 ///   occur even if the value of `T` is different than previously as long as the
-///   difference isn't observed.
+///   difference isn't observed. Such arguments may also be optional, i.e. of
+///   the form `Option<Tracked<T>>`. Calls with `None` and `Some` never share
+///   cache entries.
 ///
 /// - _Mutably tracked:_  The argument is of the form `TrackedMut<T>`. Through
 ///   this type, you can safely mutate an argument from within a memoized
@@ -42,6 +48,11 @@ use syn::{parse_quote, Error, Result};
 ///   Mutable tracked methods can also have return values that are tracked just
 ///   like immutable methods.
 ///
+/// - _Callback sinks:_ The argument is of the form `&mut dyn FnMut(T)`. Such a
+///   callback may only be used to emit output. Comemo records the sequence of
+///   emitted values and replays it by calling the callback again when there is
+///   a cache hit. The emitted values must implement [`Clone`].
+///
 /// # Restrictions
 /// The following restrictions apply to memoized functions:
 ///
@@ -51,18 +62,43 @@ use syn::{parse_quote, Error, Result};
 ///   invalidly.
 ///
 /// - The **only obversable impurity memoized functions may exhibit are
-///   mutations through `TrackedMut<T>` arguments.** Comemo stops you from using
-///   basic mutable arguments, but it cannot determine all sources of impurity,
-///   so this is your responsibility.
+///   mutations through `TrackedMut<T>` arguments and emissions into callback
+///   sinks.** Comemo stops you from using basic mutable arguments, but it
+///   cannot determine all sources of impurity, so this is your responsibility.
 ///
 /// - The output of a memoized function must be `Send` and `Sync` because it is
-///   stored in the global cache.
+///   stored in the global cache. Otherwise, compilation fails with an error
+///   pointing at the return type, unless the function is marked with
+///   [`assert_single_threaded`](#outputs-that-arent-thread-safe).
 ///
 /// Furthermore, memoized functions cannot use destructuring patterns in their
 /// arguments.
 ///
+/// # Generic functions
+/// All instantiations of a generic memoized function share one cache. To keep
+/// their results apart, the [`TypeId`](std::any::TypeId)s of the function's
+/// type parameters are part of the cache key, so the type parameters must be
+/// `'static`. As the cache is a `static` item, the types of the arguments and
+/// the output can't mention the type parameters.
+///
+/// # Expensive outputs
+/// Every cache hit returns a [`Clone`] of the cached output and a miss clones
+/// the computed output once to insert it into the cache. No other clones
+/// happen. For outputs that are expensive to clone, like large vectors, return
+/// an [`Arc`](std::sync::Arc) instead: A cache hit then only bumps the
+/// reference count and all hits share the same allocation.
+///
 /// # Example
 /// ```
+/// # use std::collections::HashMap;
+/// # use std::path::{Path, PathBuf};
+/// # struct Files(HashMap<PathBuf, String>);
+/// # #[comemo::track]
+/// # impl Files {
+/// #     fn read(&self, path: &str) -> String {
+/// #         self.0.get(Path::new(path)).cloned().unwrap_or_default()
+/// #     }
+/// # }
 /// /// Evaluate a `.calc` script.
 /// #[comemo::memoize]
 /// fn evaluate(script: &str, files: comemo::Tracked<Files>) -> i32 {
@@ -87,20 +123,528 @@ use syn::{parse_quote, Error, Result};
 ///
 /// By default, all functions are unconditionally memoized. To disable
 /// memoization conditionally, you must specify an `enabled = <expr>` attribute.
-/// The expression can use the parameters and must evaluate to a boolean value.
-/// If the expression is `false`, the function will be executed without hashing
+/// The expression can use the parameters, including `self` in methods, and
+/// must evaluate to a boolean value. It is evaluated before any of them are
+/// moved, so it can also borrow parameters that are taken by value. If the
+/// expression is `false`, the function will be executed without hashing
 /// and caching.
 ///
 /// ## Example
 /// ```
-/// /// Compute the sum of a slice of floats, but only memoize if the slice is
+/// /// Compute the sum of a slice of integers, but only memoize if the slice is
 /// /// longer than 1024 elements.
 /// #[comemo::memoize(enabled = add.len() > 1024)]
-/// fn evaluate(add: &[f32]) -> f32 {
+/// fn evaluate(add: &[u64]) -> u64 {
 ///     add.iter().copied().sum()
 /// }
 /// ```
 ///
+/// # Hashing arguments upfront
+/// With the `prehash(<args>)` attribute, the named hashed arguments are hashed
+/// once when the function is entered and the key is built from these hashes.
+/// This is the same as taking a [`Prehashed`](../comemo/struct.Prehashed.html)
+/// argument, but without changing the function's signature: Within the body,
+/// the arguments are available with their original types. The arguments must
+/// implement [`Hash`](std::hash::Hash). Note that the hash is computed once per
+/// call. To reuse a hash across calls, pass a `Prehashed` value instead.
+///
+/// ## Example
+/// ```
+/// # #[derive(Hash)]
+/// # struct Document(String);
+/// # impl Document {
+/// #     fn text(&self) -> &str { &self.0 }
+/// # }
+/// #[comemo::memoize(prehash(document))]
+/// fn word_count(document: &Document) -> usize {
+///     document.text().split_whitespace().count()
+/// }
+/// ```
+///
+/// If your arguments already carry a high-quality hash, for example from a
+/// content-addressed store, you can use `content_hash(<args>)` instead. The
+/// named arguments must implement `comemo::ContentHash` and their content hash
+/// is used for the key instead of hashing them through
+/// [`Hash`](std::hash::Hash), which they then needn't implement.
+///
+/// # Ignoring arguments
+/// Arguments that don't affect the output, like a logger, can be left out of
+/// the cache key with the `ignore = <arg>` attribute, which can be repeated.
+/// Such arguments needn't implement [`Hash`](std::hash::Hash). **The function
+/// must not depend on them:** On a cache hit, the result of a call with
+/// different values for these arguments is reused. Tracked arguments and
+/// callback sinks can't be ignored.
+///
+/// ## Example
+/// ```
+/// # #[derive(Clone)]
+/// # struct Ast;
+/// # struct Logger;
+/// #[comemo::memoize(ignore = logger)]
+/// fn parse(text: &str, logger: &Logger) -> Ast {
+///     # /*
+///     ...
+///     # */ Ast
+/// }
+/// ```
+///
+/// # Custom key hashers
+/// The hashed arguments are streamed into a SipHash-1-3 hasher to compute the
+/// cache key. For many cheap calls, a faster hasher may be preferable. With
+/// the `hasher = <type>` attribute, the key is computed with the given type,
+/// which must implement `comemo::KeyHasher`. As calls whose keys collide may
+/// share cache entries, the hasher must still produce high-quality 128-bit
+/// hashes.
+///
+/// ## Example
+/// ```
+/// # use std::hash::Hasher;
+/// # type GlyphId = u16;
+/// # #[derive(Default)]
+/// # struct Xxh3(std::collections::hash_map::DefaultHasher);
+/// # impl Hasher for Xxh3 {
+/// #     fn write(&mut self, bytes: &[u8]) { self.0.write(bytes) }
+/// #     fn finish(&self) -> u64 { self.0.finish() }
+/// # }
+/// # impl comemo::KeyHasher for Xxh3 {
+/// #     fn finish128(self) -> u128 { self.0.finish().into() }
+/// # }
+/// #[comemo::memoize(hasher = Xxh3)]
+/// fn width(glyph: GlyphId) -> f64 {
+///     # /*
+///     ...
+///     # */ f64::from(glyph)
+/// }
+/// #
+/// # assert_eq!(width(3), 3.0);
+/// ```
+///
+/// # Asynchronous functions
+/// An `async fn` can be memoized, too. Only the resolved output is cached: On a
+/// miss, the body is awaited and its output inserted into the cache, while a
+/// hit resolves immediately with a clone of the cached output. No lock is held
+/// while the body is awaited. Impurity detection doesn't apply to asynchronous
+/// functions. They cannot be combined with `cached_eq`, `result`, `outputs` or
+/// `assert_single_threaded`.
+///
+/// ## Example
+/// ```
+/// # use comemo::Tracked;
+/// # type Bytes = Vec<u8>;
+/// # struct Store;
+/// # #[comemo::track]
+/// # impl Store {
+/// #     fn get(&self, url: &str) -> Bytes { url.as_bytes().to_vec() }
+/// # }
+/// #[comemo::memoize]
+/// async fn fetch(url: &str, cache: Tracked<'_, Store>) -> Bytes {
+///     # /*
+///     ...
+///     # */ cache.get(url)
+/// }
+/// ```
+///
+/// # Concurrency
+/// Memoized functions can be called from multiple threads at once. Cache
+/// lookups only take a shared lock and the function itself is executed
+/// without holding any lock, so that different threads can compute different
+/// results in parallel. If multiple threads miss the cache for the same
+/// hashed arguments at the same time, only the first one executes the
+/// function while the others wait for its result. As the waiting threads'
+/// tracked arguments may not fulfill the first thread's constraints, they
+/// might still have to execute the function themselves afterwards. To rule
+/// out deadlocks, a thread never waits while it executes a memoized function
+/// itself, so nested calls may still be executed in parallel. Since memoized
+/// functions are pure, the cache stays consistent either way: Prewarming a
+/// cache from multiple threads results in the same cache hits as prewarming
+/// it sequentially.
+///
+/// # Eviction policy
+/// Which results are removed from a function's cache during
+/// [`evict`](../comemo/fn.evict.html) is decided by the global eviction policy.
+/// To use a different policy for a specific function, specify a `policy =
+/// <expr>` attribute. The expression must evaluate to a value implementing
+/// `comemo::EvictionPolicy` and is evaluated during each eviction.
+///
+/// ## Example
+/// ```
+/// # #[derive(Clone)]
+/// # struct Ast;
+/// #[comemo::memoize(policy = comemo::LfuPolicy { min_hits: 3 })]
+/// fn parse(text: &str) -> Ast {
+///     # /*
+///     ...
+///     # */ Ast
+/// }
+/// ```
+///
+/// # Retention per function
+/// With the `max_age = <expr>` attribute, a function retains its results for
+/// its own number of evictions when evicting with
+/// [`evict_configured`](../comemo/fn.evict_configured.html). Functions without
+/// the attribute use the default set with
+/// [`set_default_max_age`](../comemo/fn.set_default_max_age.html). Calls to
+/// [`evict`](../comemo/fn.evict.html) with an explicit maximum age apply it to
+/// all functions alike.
+///
+/// ## Example
+/// ```
+/// # use std::path::Path;
+/// # #[derive(Clone)]
+/// # struct Font;
+/// #[comemo::memoize(max_age = 30)]
+/// fn load_font(path: &Path) -> Font {
+///     # /*
+///     ...
+///     # */ Font
+/// }
+///
+/// comemo::evict_configured();
+/// ```
+///
+/// # Bounding the cache
+/// With the `capacity = <expr>` attribute, the cache of a function holds at
+/// most the given number of results. Once it is full, inserting a new result
+/// replaces the least recently inserted or hit one. A capacity of zero means
+/// unbounded, which is the default. Calls to [`evict`](../comemo/fn.evict.html)
+/// still apply as usual.
+///
+/// ## Example
+/// ```
+/// # #[derive(Hash)]
+/// # struct Image;
+/// # #[derive(Clone)]
+/// # struct Thumbnail;
+/// #[comemo::memoize(capacity = 256)]
+/// fn thumbnail(image: &Image) -> Thumbnail {
+///     # /*
+///     ...
+///     # */ Thumbnail
+/// }
+/// ```
+///
+/// # Observing evicted results
+/// With the `on_evict = <path>` attribute, the given function is called with a
+/// reference to each cached output before it is removed, be it by an eviction,
+/// by clearing the cache or because a bounded cache is full. This is useful
+/// to account for or explicitly release resources held by the outputs. The
+/// function must have the signature `fn(&Output)`. For [fallible
+/// functions](#caching-only-successful-results), it receives the `Ok` value.
+/// It is called while the cache is locked, so it must not call the memoized
+/// function itself.
+///
+/// ## Example
+/// ```
+/// # use std::sync::Arc;
+/// # #[derive(Hash)]
+/// # struct Mesh;
+/// # struct GpuBuffer;
+/// #[comemo::memoize(on_evict = release_buffer)]
+/// fn upload(mesh: &Mesh) -> Arc<GpuBuffer> {
+///     # /*
+///     ...
+///     # */ Arc::new(GpuBuffer)
+/// }
+///
+/// fn release_buffer(buffer: &Arc<GpuBuffer>) {
+///     # /*
+///     ...
+///     # */
+/// }
+/// ```
+///
+/// # Evicting a single function's cache
+/// With the `handle` attribute, an additional function named `<name>_cache`
+/// is generated. It returns a `comemo::CacheHandle`, through which the cache
+/// of this function alone can be evicted or cleared, leaving the caches of
+/// all other functions untouched.
+///
+/// ## Example
+/// ```
+/// # #[derive(Hash)]
+/// # struct Content;
+/// # #[derive(Clone)]
+/// # struct Frame;
+/// #[comemo::memoize(handle)]
+/// fn layout(content: &Content) -> Frame {
+///     # /*
+///     ...
+///     # */ Frame
+/// }
+///
+/// layout_cache().evict(2);
+/// ```
+///
+/// # Priming the cache
+/// With the `prime` attribute, an additional function named `<name>_prime` is
+/// generated. It takes the function's arguments plus the output and inserts
+/// the output into the cache without executing the function, so that a
+/// subsequent call with the same arguments is a hit. Since the constraints on
+/// tracked arguments cannot be reconstructed, this attribute cannot be applied
+/// to functions with tracked arguments or sinks.
+///
+/// ## Example
+/// ```
+/// # #[derive(Hash)]
+/// # struct Font;
+/// # #[derive(Debug, Clone, PartialEq)]
+/// # struct Glyphs(usize);
+/// #[comemo::memoize(prime)]
+/// fn shape(font: &Font, text: &str) -> Glyphs {
+///     # /*
+///     ...
+///     # */ Glyphs(text.len())
+/// }
+///
+/// # let font = Font;
+/// # let precomputed = Glyphs(1);
+/// shape_prime(&font, "Hello", precomputed);
+/// # assert_eq!(shape(&font, "Hello"), Glyphs(1));
+/// ```
+///
+/// # Peeking into the cache
+/// With the `peek` attribute, an additional function named `<name>_peek` is
+/// generated. It takes the same arguments and returns the cached output if
+/// there is one that is valid for them, or `None` otherwise. It never executes
+/// the function or modifies the cache. Tracked arguments are still validated
+/// against the recorded constraints, but mutations are not replayed.
+///
+/// ## Example
+/// ```
+/// # use comemo::{Track, Tracked};
+/// # #[derive(Hash)]
+/// # struct Par;
+/// # #[derive(Clone)]
+/// # struct Frame;
+/// # struct Fonts;
+/// # #[comemo::track]
+/// # impl Fonts {
+/// #     fn family(&self) -> String { String::new() }
+/// # }
+/// #[comemo::memoize(peek)]
+/// fn layout(par: &Par, fonts: Tracked<Fonts>) -> Frame {
+///     # /*
+///     ...
+///     # */ Frame
+/// }
+///
+/// # let (par, fonts) = (Par, Fonts);
+/// if layout_peek(&par, fonts.track()).is_none() {
+///     // Schedule the layout.
+/// }
+/// ```
+///
+/// # Detecting cycles
+/// A memoized function that calls itself with the same arguments recurses
+/// forever. With the `detect_cycles` attribute, such a call panics with a
+/// descriptive message instead. Only the hashed arguments are compared, so a
+/// recursive call that merely differs in a tracked argument counts as a
+/// cycle, too. This attribute cannot be applied to `async` functions.
+///
+/// ## Example
+/// ```
+/// # use std::collections::HashMap;
+/// # use comemo::{Track, Tracked};
+/// # struct Files(HashMap<String, String>);
+/// # #[comemo::track]
+/// # impl Files {
+/// #     fn read(&self, path: &str) -> String {
+/// #         self.0.get(path).cloned().unwrap_or_default()
+/// #     }
+/// # }
+/// # fn eval(script: &str, _: Tracked<Files>) -> i32 {
+/// #     script.len() as i32
+/// # }
+/// #[comemo::memoize(detect_cycles)]
+/// fn eval_path(path: &str, files: Tracked<Files>) -> i32 {
+///     // Panics instead of overflowing the stack if `a.calc` evaluates
+///     // `a.calc`.
+///     eval(&files.read(path), files)
+/// }
+/// ```
+///
+/// # Detecting unchanged outputs
+/// When the `cached_eq` attribute is specified, an additional function named
+/// `<name>_cached_eq` with the same arguments is generated. It returns the
+/// output alongside a boolean that indicates whether the output changed: It is
+/// `false` for cache hits and for recomputations whose output is equal to the
+/// output of the entries they replace, which are the entries that were valid
+/// for the same arguments. Entries for other tracked state are never
+/// compared with. This is useful to prune the propagation of unchanged
+/// results. The output type must implement [`PartialEq`]. The twin function
+/// shares its cache with the function itself.
+///
+/// ## Example
+/// ```
+/// # use std::collections::HashMap;
+/// # use comemo::{Track, Tracked};
+/// # struct Files(HashMap<String, String>);
+/// # #[comemo::track]
+/// # impl Files {
+/// #     fn read(&self, path: &str) -> String {
+/// #         self.0.get(path).cloned().unwrap_or_default()
+/// #     }
+/// # }
+/// #[comemo::memoize(cached_eq)]
+/// fn line_count(path: &str, files: Tracked<Files>) -> usize {
+///     files.read(path).lines().count()
+/// }
+///
+/// # let files = Files(HashMap::from([("a.txt".into(), "a\nb".into())]));
+/// let (count, changed) = line_count_cached_eq("a.txt", files.track());
+/// # assert_eq!((count, changed), (2, true));
+/// # assert_eq!(line_count_cached_eq("a.txt", files.track()), (2, false));
+/// ```
+///
+/// # Caching only successful results
+/// For functions returning `Result<T, E>`, you can specify the `result`
+/// attribute to only cache `Ok` values. Errors are recomputed on every call.
+/// Only `T` is stored in the cache, so `E` needn't fulfill the bounds for
+/// cached outputs and may, for instance, not implement [`Clone`]. The
+/// attribute can also be spelled `cache_errors = false`.
+///
+/// ## Example
+/// ```
+/// # #[derive(Clone)]
+/// # struct Ast;
+/// # struct ParseError;
+/// #[comemo::memoize(result)]
+/// fn parse(text: &str) -> Result<Ast, ParseError> {
+///     # /*
+///     ...
+///     # */ if text.is_empty() { Err(ParseError) } else { Ok(Ast) }
+/// }
+/// ```
+///
+/// # Functions with multiple outputs
+/// Functions returning a tuple can name its fields with the `outputs(<names>)`
+/// attribute. For each name, an additional function named `<name>_<output>`
+/// with the same arguments is generated, which returns only that field. The
+/// function itself and all of these accessors share one cache for the whole
+/// tuple, so the body runs only once per set of arguments, but only the
+/// requested field is cloned out of the cache. The fields must implement
+/// [`Clone`]. This attribute cannot be combined with `cached_eq` and `result`.
+///
+/// ## Example
+/// ```
+/// # #[derive(Clone)]
+/// # struct Ast;
+/// # #[derive(Clone)]
+/// # struct Diagnostic;
+/// #[comemo::memoize(outputs(ast, diagnostics))]
+/// fn parse(text: &str) -> (Ast, Vec<Diagnostic>) {
+///     # /*
+///     ...
+///     # */ (Ast, vec![])
+/// }
+///
+/// # let text = "1 + 2";
+/// let diagnostics = parse_diagnostics(text);
+/// let ast = parse_ast(text); // Cache hit.
+/// ```
+///
+/// # Returning iterators
+/// Lazy iterators can't be cached as they borrow from the arguments. With the
+/// `collect` attribute, a function may instead return `impl IntoIterator<Item
+/// = T>` or `impl Iterator<Item = T>`. Its result is then collected into an
+/// `Arc<[T]>`, which is what the memoized function returns and what is cached.
+/// This attribute cannot be combined with `result`, `outputs` and `async`.
+///
+/// ## Example
+/// ```
+/// # use std::sync::Arc;
+/// #[comemo::memoize(collect)]
+/// fn words(text: &str) -> impl IntoIterator<Item = String> {
+///     text.split_whitespace().map(str::to_owned)
+/// }
+///
+/// let words: Arc<[String]> = words("hello world");
+/// # assert_eq!(*words, ["hello", "world"]);
+/// ```
+///
+/// # Refreshing stale results
+/// Comemo can only detect changes to dependencies that are hashed or tracked.
+/// If you know that a cached result is stale because of a dependency that
+/// couldn't be modelled, you can specify the `refresh` attribute. It generates
+/// an additional function named `<name>_refresh` with the same signature,
+/// which always executes the function and replaces all cached results that
+/// would have produced a hit for the given arguments. Only the call itself is
+/// refreshed, nested memoized calls may still produce hits.
+///
+/// ## Example
+/// ```no_run
+/// #[comemo::memoize(refresh)]
+/// fn load(path: &str) -> String {
+///     std::fs::read_to_string(path).unwrap()
+/// }
+///
+/// // The file changed on disk.
+/// let text = load_refresh("data.txt");
+/// ```
+///
+/// # Inspecting cache keys
+/// With the `key` attribute, an additional function named `<name>_key` with
+/// the same arguments is generated. It returns the 128-bit key under which a
+/// call is cached, computed exactly like in the memoized function, but
+/// without executing it or accessing the cache. Tracked arguments don't
+/// contribute to the key. This is useful to correlate cache behaviour across
+/// runs, e.g. by logging the keys.
+///
+/// ## Example
+/// ```
+/// # #[derive(Clone)]
+/// # struct Ast;
+/// #[comemo::memoize(key)]
+/// fn parse(source: &str) -> Ast {
+///     # /*
+///     ...
+///     # */ Ast
+/// }
+///
+/// # let source = "1 + 2";
+/// println!("parsing with key {:032x}", parse_key(source));
+/// ```
+///
+/// # Outputs that aren't thread-safe
+/// The cache of a memoized function is shared between all threads, so its
+/// output must normally be `Send + Sync`. If your program only calls the
+/// function from a single thread, you can specify the `assert_single_threaded`
+/// attribute to memoize functions with other `'static` outputs, like `Rc`.
+/// The cache is then bound to the first thread that calls the function and
+/// calling it from any other thread panics. Evictions triggered from other
+/// threads skip the cache.
+///
+/// ## Example
+/// ```
+/// # use std::rc::Rc;
+/// # struct Ast;
+/// # impl Ast {
+/// #     fn parse(_: &str) -> Self { Ast }
+/// # }
+/// #[comemo::memoize(assert_single_threaded)]
+/// fn parse(source: &str) -> Rc<Ast> {
+///     Rc::new(Ast::parse(source))
+/// }
+/// ```
+///
+/// # Generating an unmemoized twin
+/// To test that memoization doesn't change a function's behaviour, it is
+/// useful to compare it against an unmemoized reference version. With the
+/// `emit_unmemoized = <name>` attribute, such a twin with the given name is
+/// generated. It has the same signature and shares the body with the memoized
+/// function, but executes it without any caching. Note that recursive calls
+/// in the body still go to the memoized function.
+///
+/// ## Example
+/// ```
+/// #[comemo::memoize(emit_unmemoized = fib_raw)]
+/// fn fib(n: u64) -> u64 {
+///     if n <= 2 { 1 } else { fib(n - 1) + fib(n - 2) }
+/// }
+///
+/// assert_eq!(fib(20), fib_raw(20));
+/// ```
+///
 #[proc_macro_attribute]
 pub fn memoize(args: BoundaryStream, stream: BoundaryStream) -> BoundaryStream {
     let args = syn::parse_macro_input!(args as TokenStream);
@@ -112,8 +656,9 @@ pub fn memoize(args: BoundaryStream, stream: BoundaryStream) -> BoundaryStream {
 
 /// Make a type trackable.
 ///
-/// This attribute can be applied to an inherent implementation block or trait
-/// definition. It implements the `Track` trait for the type or trait object.
+/// This attribute can be applied to an inherent implementation block, a trait
+/// definition or a struct. It implements the `Track` trait for the type or
+/// trait object.
 ///
 /// # Tracking immutably and mutably
 /// This allows you to
@@ -130,10 +675,19 @@ pub fn memoize(args: BoundaryStream, stream: BoundaryStream) -> BoundaryStream {
 /// [`Clone`] so that comemo can roll back attempted mutations which did not
 /// result in a cache hit.
 ///
+/// Mutable methods may also return values, like `fn next(&mut self) -> u64`.
+/// Just like for immutable methods, the return values are validated. On a
+/// cache hit, the mutations are replayed in order, but their return values are
+/// discarded as the memoized output already reflects them.
+///
 /// # Restrictions
-/// Tracked impl blocks or traits may not be generic and may only contain
-/// methods. Just like with memoized functions, certain restrictions apply to
-/// tracked methods:
+/// Tracked traits may not be generic, while tracked impl blocks may be generic
+/// over lifetimes, types and consts. Type parameters must be `'static`. Both
+/// may only contain methods. Methods of tracked traits may have default
+/// bodies. Calls through a `Tracked` dispatch to the implementation of the
+/// tracked value, so an overriding implementation is tracked just like the
+/// default one. Just like with memoized functions, certain
+/// restrictions apply to tracked methods:
 ///
 /// - The **only obversable impurity tracked methods may exhibit are mutations
 ///   through `&mut self`.** Comemo stops you from using basic mutable arguments
@@ -153,13 +707,19 @@ pub fn memoize(args: BoundaryStream, stream: BoundaryStream) -> BoundaryStream {
 /// Furthermore:
 /// - Tracked methods cannot be generic.
 /// - They cannot be `unsafe`, `async` or `const`.
-/// - They must take an `&self` or `&mut self` parameter.
-/// - Their arguments must implement [`ToOwned`].
+/// - They must take an `&self` or `&mut self` parameter. In particular, they
+///   cannot take `self: Pin<&Self>` (see below) or smart pointer receivers
+///   like `self: &Arc<Self>` because a `Tracked<T>` only holds a `&T`.
+/// - Their arguments must implement [`ToOwned`]. For borrowed arguments, the
+///   `ToOwned` and `Borrow` implementations must round-trip, which is checked
+///   in debug builds.
 /// - Their return values must implement [`Hash`](std::hash::Hash).
 /// - They cannot use destructuring patterns in their arguments.
 ///
 /// # Example
 /// ```
+/// # use std::collections::HashMap;
+/// # use std::path::{Path, PathBuf};
 /// /// File storage.
 /// struct Files(HashMap<PathBuf, String>);
 ///
@@ -178,6 +738,201 @@ pub fn memoize(args: BoundaryStream, stream: BoundaryStream) -> BoundaryStream {
 ///     }
 /// }
 /// ```
+///
+/// # Tracking iterators
+/// Tracked methods that return `impl Iterator<Item = T>` can't be tracked
+/// directly because iterators don't implement [`Hash`](std::hash::Hash). By
+/// annotating such a method with `#[track(collect_hash)]`, comemo eagerly
+/// drives the iterator and hashes the yielded items, which must implement
+/// `Hash`. Note that this changes the return type on the tracked surface: A
+/// `Tracked<T>` returns a collected `Vec<T>` instead of the iterator. This is
+/// only supported for immutable methods in impl blocks.
+///
+/// ```
+/// # use std::collections::BTreeMap;
+/// # #[derive(Hash)]
+/// # struct Value(u32);
+/// # struct Settings(BTreeMap<String, Value>);
+/// #[comemo::track]
+/// impl Settings {
+///     #[track(collect_hash)]
+///     fn entries(&self) -> impl Iterator<Item = (&str, &Value)> {
+///         self.0.iter().map(|(k, v)| (k.as_str(), v))
+///     }
+/// }
+/// ```
+///
+/// # Content-hashed return values
+/// By annotating a method with `#[track(content_hash)]`, its return value is
+/// hashed through `comemo::ContentHash` instead of
+/// [`Hash`](std::hash::Hash). This avoids hashing values twice that already
+/// carry a hash, e.g. from a content-addressed store.
+///
+/// ```
+/// # use std::collections::HashMap;
+/// # type BlobId = u64;
+/// # #[derive(Clone)]
+/// # struct Blob(u128);
+/// # impl comemo::ContentHash for Blob {
+/// #     fn content_hash(&self) -> u128 { self.0 }
+/// # }
+/// # struct Store {
+/// #     blobs: HashMap<BlobId, Blob>,
+/// # }
+/// #[comemo::track]
+/// impl Store {
+///     #[track(content_hash)]
+///     fn blob(&self, id: BlobId) -> Blob {
+///         self.blobs[&id].clone()
+///     }
+/// }
+/// ```
+///
+/// # Rolling hashes
+/// Hashing a large return value on each call and during each validation can
+/// be expensive, for instance for a growing, append-only log. If the tracked
+/// type maintains its own hash of the data, you can annotate a method with
+/// `#[track(rolling_hash = <expr>)]`. The expression may access `self` and
+/// its value is hashed instead of the return value, regardless of the
+/// method's arguments. **The hash must be collision-resistant and kept in
+/// sync with the data:** If the data changes, but the hash doesn't, stale
+/// results are reused. Methods with a rolling hash must take `&self`.
+///
+/// ```
+/// # struct Log {
+/// #     data: Vec<u8>,
+/// #     hash: u128,
+/// # }
+/// #[comemo::track]
+/// impl Log {
+///     #[track(rolling_hash = self.hash)]
+///     fn entries(&self) -> &[u8] {
+///         &self.data
+///     }
+/// }
+/// ```
+///
+/// # Untracked methods
+/// Calls to a method annotated with `#[track(untracked)]` are not recorded in
+/// the constraints of memoized calls, so its results never invalidate them.
+/// This is useful for methods that are only called for side purposes, like
+/// logging. **Use this with care:** If a memoized function's output depends on
+/// the result of an untracked method, it may be reused even though it's stale.
+/// Untracked methods must take `&self`.
+///
+/// ```
+/// # use std::cell::Cell;
+/// # struct Files {
+/// #     accesses: Cell<usize>,
+/// # }
+/// #[comemo::track]
+/// impl Files {
+///     #[track(untracked)]
+///     fn access_count(&self) -> usize {
+///         self.accesses.get()
+///     }
+/// }
+/// ```
+///
+/// # Default arguments
+/// Trailing arguments of a tracked method can be given a default with
+/// `#[track(default = <expr>)]`. For such a method, the `Tracked` additionally
+/// offers a method named `<name>_default` that leaves these arguments out and
+/// calls the method with their defaults. The call is recorded with the
+/// resolved arguments, exactly as if they were passed explicitly. Default
+/// arguments are not supported in tracked traits.
+///
+/// ```
+/// # use comemo::Track;
+/// # #[derive(Clone, Hash, PartialEq)]
+/// # enum Encoding {
+/// #     Utf8,
+/// # }
+/// # struct Files;
+/// #[comemo::track]
+/// impl Files {
+///     fn read(&self, path: &str, #[track(default = None)] encoding: Option<Encoding>) -> String {
+///         # /*
+///         ...
+///         # */ path.into()
+///     }
+/// }
+///
+/// # let files = Files;
+/// # let files = files.track();
+/// let text = files.read_default("main.rs"); // Same as `read("main.rs", None)`.
+/// ```
+///
+/// # Interned handles
+/// Tracked methods may return `&'static` references, for example to resolve
+/// interned symbols. Like all return values, they are hashed by content, so
+/// the constraint for a `&'static str` hashes the string's bytes once per
+/// call. For short, interned strings, this is cheap. If the referenced values
+/// are large, consider returning a `Copy` handle that identifies them instead.
+///
+/// ```
+/// # use std::collections::HashMap;
+/// # #[derive(Clone, Copy, Hash, PartialEq)]
+/// # struct SymbolId(u32);
+/// # struct Interner {
+/// #     ids: HashMap<String, SymbolId>,
+/// #     names: Vec<&'static str>,
+/// # }
+/// #[comemo::track]
+/// impl Interner {
+///     fn symbol(&self, name: &str) -> SymbolId {
+///         self.ids[name]
+///     }
+///
+///     fn resolve(&self, id: SymbolId) -> &'static str {
+///         self.names[id.0 as usize]
+///     }
+/// }
+/// ```
+///
+/// # Tracked structs
+/// Applied to a struct with named fields, `#[track]` generates a tracked
+/// getter for each non-private field, with the field's name and visibility,
+/// that returns a reference to the field. Private fields are skipped, so they
+/// can't be accessed through a `Tracked`. To generate getters for all fields,
+/// derive [`Track`](derive@Track) instead.
+///
+/// ```
+/// # use std::path::PathBuf;
+/// #[comemo::track]
+/// struct Config {
+///     pub width: u32,
+///     pub height: u32,
+///     cache_dir: PathBuf,
+/// }
+/// ```
+///
+/// # Pinned types
+/// Types that are `!Unpin` or self-referential and can only be accessed
+/// through `Pin<&Self>` cannot be tracked directly because a `Tracked<T>` only
+/// holds a plain `&T`, which does not guarantee that the value stays pinned.
+/// For this reason, methods with a `self: Pin<&Self>` receiver are rejected:
+/// Pinning the `&T` to call them would be unsound, as neither the tracked
+/// value nor the values that constraints are later validated against need to
+/// be pinned. Similarly, `self: &Arc<Self>` receivers are rejected because the
+/// `Arc` isn't reachable from the `&T`. Instead, you can track a thin wrapper
+/// around the pinned reference:
+///
+/// ```
+/// # use std::pin::Pin;
+/// # struct State(u32);
+/// # impl State {
+/// #     fn current(&self) -> u32 { self.0 }
+/// # }
+/// struct PinnedState<'a>(Pin<&'a State>);
+///
+/// #[comemo::track]
+/// impl<'a> PinnedState<'a> {
+///     fn current(&self) -> u32 {
+///         self.0.as_ref().current()
+///     }
+/// }
+/// ```
 #[proc_macro_attribute]
 pub fn track(_: BoundaryStream, stream: BoundaryStream) -> BoundaryStream {
     let block = syn::parse_macro_input!(stream as syn::Item);
@@ -185,3 +940,35 @@ pub fn track(_: BoundaryStream, stream: BoundaryStream) -> BoundaryStream {
         .unwrap_or_else(|err| err.to_compile_error())
         .into()
 }
+
+/// Make a struct trackable by generating a tracked getter for each field.
+///
+/// For each field, this generates a method with the field's name and
+/// visibility that returns a reference to the field. The methods are tracked
+/// just like a hand-written [`#[track]`](macro@track) impl block, so accesses
+/// are tracked with field granularity. All fields must implement
+/// [`Hash`](std::hash::Hash). The same restrictions as for `#[track]` apply.
+///
+/// # Example
+/// ```
+/// # use comemo::Tracked;
+/// #[derive(comemo::Track)]
+/// struct Image {
+///     width: u32,
+///     height: u32,
+///     pixels: Vec<u8>,
+/// }
+///
+/// #[comemo::memoize]
+/// fn area(image: Tracked<Image>) -> u32 {
+///     // Changes to `pixels` don't invalidate this result.
+///     image.width() * image.height()
+/// }
+/// ```
+#[proc_macro_derive(Track)]
+pub fn derive_track(stream: BoundaryStream) -> BoundaryStream {
+    let item = syn::parse_macro_input!(stream as syn::DeriveInput);
+    track::derive(&item)
+        .unwrap_or_else(|err| err.to_compile_error())
+        .into()
+}
@@ -1,4 +1,4 @@
-use utils::parse_key_value;
+use utils::{parse_flag, parse_key_value, parse_list};
 
 use super::*;
 
@@ -20,17 +20,105 @@ struct Function {
     item: syn::ItemFn,
     args: Vec<Argument>,
     output: syn::Type,
+    ok: Option<syn::Type>,
+    outputs: Vec<(syn::Ident, syn::Type)>,
     enabled: Option<syn::Expr>,
+    cached_eq: bool,
+    refresh: bool,
+    key: bool,
+    single_threaded: bool,
+    handle: bool,
+    prime: bool,
+    peek: bool,
+    detect_cycles: bool,
+    unmemoized: Option<syn::Ident>,
+    policy: Option<syn::Expr>,
+    max_age: Option<syn::Expr>,
+    capacity: Option<syn::Expr>,
+    on_evict: Option<syn::Expr>,
+    hasher: Option<syn::Type>,
 }
 
 /// Additional metadata for a memoized function.
+#[derive(Default)]
 struct Meta {
     enabled: Option<syn::Expr>,
+    cached_eq: bool,
+    result: bool,
+    collect: bool,
+    refresh: bool,
+    key: bool,
+    single_threaded: bool,
+    handle: bool,
+    prime: bool,
+    peek: bool,
+    detect_cycles: bool,
+    unmemoized: Option<syn::Ident>,
+    policy: Option<syn::Expr>,
+    max_age: Option<syn::Expr>,
+    capacity: Option<syn::Expr>,
+    on_evict: Option<syn::Expr>,
+    hasher: Option<syn::Type>,
+    cache_errors: Option<syn::LitBool>,
+    prehash: Vec<syn::Ident>,
+    content_hash: Vec<syn::Ident>,
+    ignore: Vec<syn::Ident>,
+    outputs: Vec<syn::Ident>,
 }
 
 impl syn::parse::Parse for Meta {
     fn parse(input: syn::parse::ParseStream) -> Result<Self> {
-        Ok(Self { enabled: parse_key_value::<kw::enabled, _>(input)? })
+        let mut meta = Self::default();
+        while !input.is_empty() {
+            if let Some(enabled) = parse_key_value::<kw::enabled, _>(input)? {
+                meta.enabled = Some(enabled);
+            } else if parse_flag::<kw::cached_eq>(input)? {
+                meta.cached_eq = true;
+            } else if parse_flag::<kw::result>(input)? {
+                meta.result = true;
+            } else if parse_flag::<kw::collect>(input)? {
+                meta.collect = true;
+            } else if let Some(cache) = parse_key_value::<kw::cache_errors, _>(input)? {
+                meta.cache_errors = Some(cache);
+            } else if parse_flag::<kw::refresh>(input)? {
+                meta.refresh = true;
+            } else if parse_flag::<kw::key>(input)? {
+                meta.key = true;
+            } else if parse_flag::<kw::assert_single_threaded>(input)? {
+                meta.single_threaded = true;
+            } else if parse_flag::<kw::handle>(input)? {
+                meta.handle = true;
+            } else if parse_flag::<kw::prime>(input)? {
+                meta.prime = true;
+            } else if parse_flag::<kw::peek>(input)? {
+                meta.peek = true;
+            } else if parse_flag::<kw::detect_cycles>(input)? {
+                meta.detect_cycles = true;
+            } else if let Some(name) = parse_key_value::<kw::emit_unmemoized, _>(input)? {
+                meta.unmemoized = Some(name);
+            } else if let Some(policy) = parse_key_value::<kw::policy, _>(input)? {
+                meta.policy = Some(policy);
+            } else if let Some(max_age) = parse_key_value::<kw::max_age, _>(input)? {
+                meta.max_age = Some(max_age);
+            } else if let Some(capacity) = parse_key_value::<kw::capacity, _>(input)? {
+                meta.capacity = Some(capacity);
+            } else if let Some(on_evict) = parse_key_value::<kw::on_evict, _>(input)? {
+                meta.on_evict = Some(on_evict);
+            } else if let Some(hasher) = parse_key_value::<kw::hasher, _>(input)? {
+                meta.hasher = Some(hasher);
+            } else if let Some(names) = parse_list::<kw::prehash, _>(input)? {
+                meta.prehash.extend(names);
+            } else if let Some(names) = parse_list::<kw::content_hash, _>(input)? {
+                meta.content_hash.extend(names);
+            } else if let Some(name) = parse_key_value::<kw::ignore, _>(input)? {
+                meta.ignore.push(name);
+            } else if let Some(names) = parse_list::<kw::outputs, _>(input)? {
+                meta.outputs.extend(names);
+            } else {
+                return Err(input.error("comemo: unknown memoize argument"));
+            }
+        }
+        Ok(meta)
     }
 }
 
@@ -38,27 +126,181 @@ impl syn::parse::Parse for Meta {
 enum Argument {
     Receiver(syn::Token![self]),
     Ident(Box<syn::Type>, Option<syn::Token![mut]>, syn::Ident),
+    Sink(Box<syn::Type>, syn::Ident),
+    Optional(Box<syn::Type>, Option<syn::Token![mut]>, syn::Ident),
+    Prehashed(Box<syn::Type>, Option<syn::Token![mut]>, syn::Ident, bool),
+    Ignored(Box<syn::Type>, Option<syn::Token![mut]>, syn::Ident),
 }
 
 /// Preprocess and validate a function.
 fn prepare(attrs: TokenStream, function: &syn::ItemFn) -> Result<Function> {
-    let meta = syn::parse2::<Meta>(attrs.clone())?;
+    let mut meta = syn::parse2::<Meta>(attrs.clone())?;
 
     let mut args = vec![];
     for input in &function.sig.inputs {
         args.push(prepare_arg(input)?);
     }
 
+    // Hash the requested arguments upfront, possibly with their content hash.
+    let prehashed = meta.prehash.iter().map(|name| (name, false));
+    let content_hashed = meta.content_hash.iter().map(|name| (name, true));
+    for (name, content) in prehashed.chain(content_hashed) {
+        let Some(arg) = args.iter_mut().find(|arg| match arg {
+            Argument::Ident(_, _, ident) => ident == name,
+            _ => false,
+        }) else {
+            bail!(name, "`prehash` and `content_hash` must name a hashed argument");
+        };
+        if let Argument::Ident(ty, mutability, ident) = arg {
+            *arg = Argument::Prehashed(ty.clone(), *mutability, ident.clone(), content);
+        }
+    }
+
+    // Leave the requested arguments out of the key.
+    for name in &meta.ignore {
+        let Some(arg) = args.iter_mut().find(|arg| match arg {
+            Argument::Ident(_, _, ident)
+            | Argument::Sink(_, ident)
+            | Argument::Optional(_, _, ident) => ident == name,
+            _ => false,
+        }) else {
+            bail!(name, "`ignore` must name an argument");
+        };
+        match arg {
+            Argument::Ident(ty, mutability, ident) if !is_tracked(ty) => {
+                *arg = Argument::Ignored(ty.clone(), *mutability, ident.clone());
+            }
+            _ => bail!(name, "`ignore` cannot be applied to tracked arguments and sinks"),
+        }
+    }
+
+    // Only calls without tracked arguments can be primed because the
+    // constraints on tracked arguments cannot be reconstructed.
+    if meta.prime {
+        for arg in &args {
+            let ty = match arg {
+                Argument::Ident(ty, _, _) if is_tracked(ty) => ty,
+                Argument::Sink(ty, _) | Argument::Optional(ty, _, _) => ty,
+                _ => continue,
+            };
+            bail!(ty, "`prime` cannot be applied to tracked arguments and sinks");
+        }
+    }
+
+    // Only the `Ok` value of fallible functions is cached. `cache_errors =
+    // false` is an alternative spelling of `result`.
+    if let Some(cache) = &meta.cache_errors {
+        if cache.value && meta.result {
+            bail!(cache, "`result` cannot be combined with `cache_errors = true`");
+        }
+        meta.result |= !cache.value;
+    }
+
+    // Iterators are collected into a shared slice, which is what is cached.
+    let mut function = function.clone();
+    if meta.collect {
+        let item = match &function.sig.output {
+            syn::ReturnType::Type(_, ty) => {
+                utils::iterator_item(ty, &["Iterator", "IntoIterator"])
+            }
+            syn::ReturnType::Default => None,
+        };
+        let Some(item) = item else {
+            bail!(
+                function.sig,
+                "`collect` requires a function returning `impl IntoIterator<Item = T>`"
+            );
+        };
+        if let Some(asyncness) = &function.sig.asyncness {
+            bail!(asyncness, "`async` functions cannot be combined with `collect`");
+        }
+        if meta.result || !meta.outputs.is_empty() {
+            bail!(
+                function.sig,
+                "`collect` cannot be combined with `result` or `outputs`"
+            );
+        }
+
+        let block = &function.block;
+        function.sig.output = parse_quote! { -> ::std::sync::Arc<[#item]> };
+        function.block = parse_quote! { {
+            ::core::iter::Iterator::collect(
+                ::core::iter::IntoIterator::into_iter((|| #block)())
+            )
+        } };
+    }
+
     let output = match &function.sig.output {
         syn::ReturnType::Default => parse_quote! { () },
         syn::ReturnType::Type(_, ty) => ty.as_ref().clone(),
     };
 
+    let mut ok = None;
+    if meta.result {
+        let Some(ty) = result_ok(&output) else {
+            bail!(output, "`result` requires a function returning `Result<T, E>`");
+        };
+        if meta.cached_eq {
+            bail!(output, "`result` cannot be combined with `cached_eq`");
+        }
+        ok = Some(ty);
+    }
+
+    // Each named output is a field of the returned tuple.
+    let mut outputs = vec![];
+    if !meta.outputs.is_empty() {
+        let syn::Type::Tuple(tuple) = &output else {
+            bail!(output, "`outputs` requires a function returning a tuple");
+        };
+        if tuple.elems.len() != meta.outputs.len() {
+            bail!(output, "`outputs` must name each field of the returned tuple");
+        }
+        if meta.cached_eq || meta.result {
+            bail!(output, "`outputs` cannot be combined with `cached_eq` or `result`");
+        }
+        outputs = meta.outputs.into_iter().zip(tuple.elems.iter().cloned()).collect();
+    }
+
+    // Asynchronous functions only cache their resolved output.
+    if let Some(asyncness) = &function.sig.asyncness {
+        if meta.cached_eq || meta.result || !outputs.is_empty() {
+            bail!(
+                asyncness,
+                "`async` functions cannot be combined with `cached_eq`, `result` or `outputs`"
+            );
+        }
+        if meta.single_threaded {
+            bail!(
+                asyncness,
+                "`async` functions cannot be combined with `assert_single_threaded`"
+            );
+        }
+        if meta.detect_cycles {
+            bail!(asyncness, "`async` functions cannot be combined with `detect_cycles`");
+        }
+    }
+
     Ok(Function {
-        item: function.clone(),
+        item: function,
         args,
         output,
+        ok,
+        outputs,
         enabled: meta.enabled,
+        cached_eq: meta.cached_eq,
+        refresh: meta.refresh,
+        key: meta.key,
+        single_threaded: meta.single_threaded,
+        handle: meta.handle,
+        prime: meta.prime,
+        peek: meta.peek,
+        detect_cycles: meta.detect_cycles,
+        unmemoized: meta.unmemoized,
+        policy: meta.policy,
+        max_age: meta.max_age,
+        capacity: meta.capacity,
+        on_evict: meta.on_evict,
+        hasher: meta.hasher,
     })
 }
 
@@ -84,6 +326,18 @@ fn prepare_arg(input: &syn::FnArg) -> Result<Argument> {
                 bail!(typed.pat, "only simple identifiers are supported");
             };
 
+            if let Some(event) = sink_event(&typed.ty) {
+                return Ok(Argument::Sink(Box::new(event), ident.clone()));
+            }
+
+            if let Some(tracked) = optional_tracked(&typed.ty) {
+                return Ok(Argument::Optional(
+                    Box::new(tracked),
+                    *mutability,
+                    ident.clone(),
+                ));
+            }
+
             if let syn::Type::Reference(syn::TypeReference {
                 mutability: Some(_), ..
             }) = typed.ty.as_ref()
@@ -96,45 +350,421 @@ fn prepare_arg(input: &syn::FnArg) -> Result<Argument> {
     })
 }
 
+/// Extract the `T` type from a `Result<T, E>`.
+fn result_ok(ty: &syn::Type) -> Option<syn::Type> {
+    let syn::Type::Path(path) = ty else { return None };
+    let segment = path.path.segments.last()?;
+    if segment.ident != "Result" {
+        return None;
+    }
+
+    let syn::PathArguments::AngleBracketed(args) = &segment.arguments else {
+        return None;
+    };
+
+    match args.args.first() {
+        Some(syn::GenericArgument::Type(ok)) => Some(ok.clone()),
+        _ => None,
+    }
+}
+
+/// Whether a type is a `Tracked<T>` or `TrackedMut<T>`.
+fn is_tracked(ty: &syn::Type) -> bool {
+    let syn::Type::Path(path) = ty else { return false };
+    path.path.segments.last().is_some_and(|segment| {
+        segment.ident == "Tracked" || segment.ident == "TrackedMut"
+    })
+}
+
+/// Extract the `Tracked<T>` type from an `Option<Tracked<T>>`.
+fn optional_tracked(ty: &syn::Type) -> Option<syn::Type> {
+    let syn::Type::Path(path) = ty else { return None };
+    let segment = path.path.segments.last()?;
+    if segment.ident != "Option" {
+        return None;
+    }
+
+    let syn::PathArguments::AngleBracketed(args) = &segment.arguments else {
+        return None;
+    };
+
+    let Some(syn::GenericArgument::Type(inner)) = args.args.first() else {
+        return None;
+    };
+
+    let syn::Type::Path(inner_path) = inner else { return None };
+    let is_tracked = inner_path
+        .path
+        .segments
+        .last()
+        .is_some_and(|segment| segment.ident == "Tracked");
+    (args.args.len() == 1 && is_tracked).then(|| inner.clone())
+}
+
+/// Extract the event type from a `&mut dyn FnMut(T)` callback sink.
+fn sink_event(ty: &syn::Type) -> Option<syn::Type> {
+    let syn::Type::Reference(syn::TypeReference { mutability: Some(_), elem, .. }) = ty
+    else {
+        return None;
+    };
+
+    let syn::Type::TraitObject(object) = elem.as_ref() else { return None };
+    let mut traits = object.bounds.iter().filter_map(|bound| match bound {
+        syn::TypeParamBound::Trait(bound) => Some(bound),
+        _ => None,
+    });
+
+    let bound = traits.next()?;
+    if traits.next().is_some() {
+        return None;
+    }
+
+    let segment = bound.path.segments.last()?;
+    if segment.ident != "FnMut" {
+        return None;
+    }
+
+    let syn::PathArguments::Parenthesized(args) = &segment.arguments else {
+        return None;
+    };
+
+    if args.inputs.len() != 1 || !matches!(args.output, syn::ReturnType::Default) {
+        return None;
+    }
+
+    args.inputs.first().cloned()
+}
+
 /// Rewrite a function's body to memoize it.
 fn process(function: &Function) -> Result<TokenStream> {
-    // Construct assertions that the arguments fulfill the necessary bounds.
-    let bounds = function.args.iter().map(|arg| {
-        let val = match arg {
-            Argument::Receiver(token) => quote! { #token },
-            Argument::Ident(_, _, ident) => quote! { #ident },
-        };
-        quote_spanned! { function.item.span() =>
-            ::comemo::internal::assert_hashable_or_trackable(&#val);
-        }
+    let wrapped = rewrite(function);
+
+    // Produce accessors for the individual outputs.
+    let outputs = outputs(function);
+
+    // Produce twins that report whether the output changed or that replace
+    // stale entries.
+    let twins = twins(function);
+
+    // Produce a function that computes the cache key of a call.
+    let key = function.key.then(|| key(function));
+
+    // Produce an unmemoized twin with the same body.
+    let unmemoized = function.unmemoized.as_ref().map(|name| {
+        let mut raw = function.item.clone();
+        raw.sig.ident = name.clone();
+        raw
     });
 
-    // Construct a tuple from all arguments.
+    Ok(quote! {
+        #wrapped
+        #(#outputs)*
+        #(#twins)*
+        #key
+        #unmemoized
+    })
+}
+
+/// Produce the function itself and its `<name>_cached_eq` and
+/// `<name>_refresh` twins, which all share the cache of a hidden memoized
+/// function. They tell it whether to refresh the call and whether to compare
+/// the output with the entries it replaces.
+///
+/// With multiple outputs, the hidden function is the one that memoizes the
+/// whole tuple and the function itself is produced with the accessors.
+fn twins(function: &Function) -> Vec<syn::ItemFn> {
+    if !function.refresh && !function.cached_eq {
+        return vec![];
+    }
+
+    let name = &function.item.sig.ident;
+    let output = &function.output;
+    let call = |refresh: bool, compare: bool| {
+        let (inner, field) = if function.outputs.is_empty() {
+            (quote::format_ident!("__{name}_memoized"), None)
+        } else {
+            (quote::format_ident!("__{name}_outputs"), Some(quote! { |output| output }))
+        };
+        let extra = field
+            .into_iter()
+            .chain(function.refresh.then(|| quote! { #refresh }))
+            .chain(function.cached_eq.then(|| quote! { #compare }));
+        forward(function, &inner, extra)
+    };
+
+    // Only the `cached_eq` twin keeps the flag whether the output changed.
+    let only_output = function.cached_eq.then(|| quote! { .0 });
+
+    let mut items = vec![];
+    if function.outputs.is_empty() {
+        let mut plain = forwarder(function, name.clone());
+        let call = call(false, false);
+        plain.block = parse_quote! { { #call #only_output } };
+        items.push(plain);
+    }
+
+    if function.cached_eq {
+        let mut twin = forwarder(function, quote::format_ident!("{name}_cached_eq"));
+        let call = call(false, true);
+        twin.sig.output = parse_quote! { -> (#output, bool) };
+        twin.block = parse_quote! { { #call } };
+        items.push(twin);
+    }
+
+    if function.refresh {
+        let mut refresh = forwarder(function, quote::format_ident!("{name}_refresh"));
+        let call = call(true, false);
+        refresh.block = parse_quote! { { #call #only_output } };
+        items.push(refresh);
+    }
+
+    items
+}
+
+/// Produce the function itself and the `<name>_<output>` accessors, which all
+/// share the cache of a hidden function that memoizes the whole tuple.
+fn outputs(function: &Function) -> Vec<syn::ItemFn> {
+    if function.outputs.is_empty() {
+        return vec![];
+    }
+
+    let name = &function.item.sig.ident;
+    let inner = quote::format_ident!("__{name}_outputs");
+    let refresh = function.refresh.then(|| quote! { false });
+
+    let mut whole = forwarder(function, name.clone());
+    let field = quote! { |output| output };
+    let call = forward(function, &inner, [field].into_iter().chain(refresh.clone()));
+    whole.block = parse_quote! { { #call } };
+
+    let mut items = vec![whole];
+    for (i, (output, ty)) in function.outputs.iter().enumerate() {
+        let index = syn::Index::from(i);
+        let mut accessor = forwarder(function, quote::format_ident!("{name}_{output}"));
+        let field = quote! { |output| &output.#index };
+        let call = forward(function, &inner, [field].into_iter().chain(refresh.clone()));
+        accessor.sig.output = parse_quote! { -> #ty };
+        accessor.block = parse_quote! { { #call } };
+        items.push(accessor);
+    }
+
+    items
+}
+
+/// Produce a tuple of the given elements. As `Args` supports at most twelve
+/// elements, the elements beyond the eleventh are nested in a wrapped tuple.
+fn nest(
+    mut elems: Vec<TokenStream>,
+    wrap: fn(TokenStream) -> TokenStream,
+) -> TokenStream {
+    if elems.len() > 12 {
+        let rest = elems.split_off(11);
+        elems.push(wrap(nest(rest, wrap)));
+    }
+    quote! { (#(#elems,)*) }
+}
+
+/// Produce a `<name>_key` function that computes the cache key of a call
+/// without accessing the cache.
+fn key(function: &Function) -> syn::ItemFn {
+    let name = &function.item.sig.ident;
+    let arg_tuple = arg_tuple(function);
+    let hasher = match &function.hasher {
+        Some(hasher) => quote! { #hasher },
+        None => quote! { ::comemo::internal::SipHasher13 },
+    };
+
+    let doc = format!(" The cache key of a call to [`{name}`].");
+    let mut key = forwarder(function, quote::format_ident!("{name}_key"));
+    key.attrs.retain(|attr| !attr.path().is_ident("doc"));
+    key.attrs.push(parse_quote! { #[doc = #doc] });
+    key.sig.asyncness = None;
+    key.sig.output = parse_quote! { -> u128 };
+    key.block = parse_quote! { {
+        let input = ::comemo::internal::Args(#arg_tuple);
+        ::comemo::internal::key::<_, #hasher>(&input)
+    } };
+
+    key
+}
+
+/// Produce a copy of the function's signature with the given name, whose body
+/// is yet to be filled in.
+fn forwarder(function: &Function, name: syn::Ident) -> syn::ItemFn {
+    let mut item = function.item.clone();
+    item.sig.ident = name;
+    for arg in item.sig.inputs.iter_mut() {
+        let syn::FnArg::Typed(typed) = arg else { continue };
+        let syn::Pat::Ident(ident) = typed.pat.as_mut() else { continue };
+        ident.mutability = None;
+    }
+    item
+}
+
+/// Produce a call to the function with the given name that forwards all
+/// arguments, plus the given extra ones.
+fn forward(
+    function: &Function,
+    name: &syn::Ident,
+    extra: impl IntoIterator<Item = TokenStream>,
+) -> TokenStream {
+    let args = function
+        .args
+        .iter()
+        .map(|arg| match arg {
+            Argument::Receiver(token) => quote! { #token },
+            Argument::Ident(_, _, ident)
+            | Argument::Sink(_, ident)
+            | Argument::Optional(_, _, ident)
+            | Argument::Prehashed(_, _, ident, _)
+            | Argument::Ignored(_, _, ident) => quote! { #ident },
+        })
+        .chain(extra);
+
+    // Type parameters are passed on explicitly as they needn't be inferable.
+    let type_params: Vec<_> = function
+        .item
+        .sig
+        .generics
+        .type_params()
+        .map(|param| &param.ident)
+        .collect();
+    let turbofish = (!type_params.is_empty()).then(|| quote! { ::<#(#type_params),*> });
+
+    // Asynchronous functions must be awaited.
+    let await_ = function.item.sig.asyncness.map(|_| quote! { .await });
+
+    // Methods must be called through `Self`.
+    if function.args.iter().any(|arg| matches!(arg, Argument::Receiver(_))) {
+        quote! { Self::#name #turbofish(#(#args),*) #await_ }
+    } else {
+        quote! { #name #turbofish(#(#args),*) #await_ }
+    }
+}
+
+/// Produce the tuple of key parts and tracked values for the arguments of a
+/// function, which is wrapped in `Args` to form its input.
+fn arg_tuple(function: &Function) -> TokenStream {
     let args = function.args.iter().map(|arg| match arg {
         Argument::Receiver(token) => quote! {
             ::comemo::internal::hash(&#token)
         },
         Argument::Ident(_, _, ident) => quote! { #ident },
+        Argument::Sink(_, ident) => quote! {
+            ::comemo::internal::Sink::new(#ident)
+        },
+        Argument::Optional(_, _, ident) => quote! {
+            ::comemo::internal::OptionTracked(#ident)
+        },
+        Argument::Prehashed(_, _, ident, false) => quote! {
+            ::comemo::internal::Prehash::new(#ident)
+        },
+        Argument::Prehashed(_, _, ident, true) => quote! {
+            ::comemo::internal::Prehash::content(#ident)
+        },
+        Argument::Ignored(_, _, ident) => quote! {
+            ::comemo::internal::Ignored(#ident)
+        },
     });
-    let arg_tuple = quote! { (#(#args,)*) };
 
-    let arg_tys = function.args.iter().map(|arg| match arg {
-        Argument::Receiver(_) => quote! { () },
-        Argument::Ident(ty, _, _) => quote! { #ty },
+    // The cache is shared between all instantiations of a generic function, so
+    // the type arguments must be part of the key.
+    let type_params: Vec<_> = function.item.sig.generics.type_params().collect();
+    let type_ids = type_params.iter().map(|param| {
+        let ident = &param.ident;
+        quote! { ::core::any::TypeId::of::<#ident>() }
+    });
+    nest(args.chain(type_ids).collect(), |tuple| {
+        quote! { ::comemo::internal::Args(#tuple) }
+    })
+}
+
+/// Produce the memoized version of a function.
+fn rewrite(function: &Function) -> TokenStream {
+    // Construct assertions that the arguments fulfill the necessary bounds.
+    let bounds = function.args.iter().filter_map(|arg| {
+        let val = match arg {
+            Argument::Receiver(token) => quote! { #token },
+            Argument::Ident(_, _, ident) => quote! { #ident },
+            Argument::Sink(..)
+            | Argument::Optional(..)
+            | Argument::Prehashed(..)
+            | Argument::Ignored(..) => return None,
+        };
+        Some(quote_spanned! { function.item.span() =>
+            ::comemo::internal::assert_hashable_or_trackable(&#val);
+        })
+    });
+
+    // Construct a tuple from all arguments and the type parameters.
+    let arg_tuple = arg_tuple(function);
+    let type_params: Vec<_> = function.item.sig.generics.type_params().collect();
+
+    // The cache is a static, which cannot use the function's lifetime
+    // parameters. Since constraints are `'static` anyway, we can replace them.
+    let lifetimes: Vec<_> = function
+        .item
+        .sig
+        .generics
+        .lifetimes()
+        .map(|param| param.lifetime.ident.clone())
+        .collect();
+    let arg_tys = function.args.iter().map(|arg| {
+        let ty = match arg {
+            Argument::Receiver(_) => quote! { () },
+            Argument::Ident(ty, _, _) => quote! { #ty },
+            Argument::Sink(ty, _) => quote! { ::comemo::internal::Sink<#ty> },
+            Argument::Optional(ty, _, _) => {
+                quote! { ::comemo::internal::OptionTracked<#ty> }
+            }
+            Argument::Prehashed(ty, _, _, _) => {
+                quote! { ::comemo::internal::Prehash<#ty> }
+            }
+            Argument::Ignored(ty, _, _) => quote! { ::comemo::internal::Ignored<#ty> },
+        };
+        staticify(ty, &lifetimes)
+    });
+    let type_id_tys = type_params.iter().map(|_| quote! { ::core::any::TypeId });
+    let arg_ty_tuple = nest(arg_tys.chain(type_id_tys).collect(), |tuple| {
+        quote! { ::comemo::internal::Args<#tuple> }
     });
-    let arg_ty_tuple = quote! { (#(#arg_tys,)*) };
 
     // Construct a tuple for all parameters.
     let params = function.args.iter().map(|arg| match arg {
         Argument::Receiver(_) => quote! { _ },
-        Argument::Ident(_, mutability, ident) => quote! { #mutability #ident },
+        Argument::Ident(_, mutability, ident)
+        | Argument::Optional(_, mutability, ident)
+        | Argument::Prehashed(_, mutability, ident, _)
+        | Argument::Ignored(_, mutability, ident) => {
+            quote! { #mutability #ident }
+        }
+        Argument::Sink(_, ident) => quote! { mut #ident },
     });
-    let param_tuple = quote! { (#(#params,)*) };
+    let type_id_params = type_params.iter().map(|_| quote! { _ });
+    let param_tuple = nest(params.chain(type_id_params).collect(), |tuple| tuple);
+
+    // Turn the recording sinks back into plain callbacks.
+    let sinks: Vec<_> = function
+        .args
+        .iter()
+        .filter_map(|arg| match arg {
+            Argument::Sink(ty, ident) => Some(quote! {
+                let #ident: &mut dyn ::core::ops::FnMut(#ty) = &mut |event| #ident.emit(event);
+            }),
+            _ => None,
+        })
+        .collect();
 
     // Construct the inner closure.
     let output = &function.output;
     let body = &function.item.block;
-    let closure = quote! { |#param_tuple| -> #output #body };
+    let closure = if function.item.sig.asyncness.is_some() {
+        quote! { |#param_tuple| async move { #(#sinks)* #body } }
+    } else if sinks.is_empty() {
+        quote! { |#param_tuple| -> #output #body }
+    } else {
+        quote! { |#param_tuple| -> #output { #(#sinks)* #body } }
+    };
 
     // Adjust the function's body.
     let mut wrapped = function.item.clone();
@@ -145,30 +775,347 @@ fn process(function: &Function) -> Result<TokenStream> {
     }
 
     let enabled = function.enabled.clone().unwrap_or(parse_quote! { true });
+    let policy = match &function.policy {
+        Some(policy) => quote! { Some(&#policy) },
+        None => quote! { None },
+    };
+    let max_age = match &function.max_age {
+        Some(max_age) => quote! { Some(#max_age) },
+        None => quote! { None },
+    };
+    let evict = quote! { evict(eviction, #policy, #max_age) };
 
-    wrapped.block = parse_quote! { {
-        static __CACHE: ::comemo::internal::Cache<
+    let memoized = if function.cached_eq {
+        quote! { memoized_changed }
+    } else if function.ok.is_some() {
+        quote! { memoized_result }
+    } else if !function.outputs.is_empty() {
+        quote! { memoized_field }
+    } else if function.item.sig.asyncness.is_some() {
+        quote! { memoized_async }
+    } else {
+        quote! { memoized }
+    };
+
+    // Fallible functions only cache their `Ok` value.
+    let cached = function.ok.as_ref().unwrap_or(output);
+
+    // Construct an assertion that the output can be shared between threads.
+    let thread_safe = (!function.single_threaded).then(|| {
+        quote_spanned! { cached.span() =>
+            ::comemo::internal::assert_send_sync::<#cached>();
+        }
+    });
+    let hasher = function.hasher.as_ref().map(|hasher| quote! { #hasher, });
+    let cache_ty = quote! {
+        ::comemo::internal::Cache<
             <::comemo::internal::Args<#arg_ty_tuple> as ::comemo::internal::Input>::Constraint,
-            #output,
-        > = ::comemo::internal::Cache::new(|| {
-            ::comemo::internal::register_evictor(|max_age| __CACHE.evict(max_age));
-            ::core::default::Default::default()
+            #cached,
+            #hasher
+        >
+    };
+
+    // The name under which the cache is reported. With the testing feature,
+    // the cache is also registered for dependency export.
+    let name = wrapped.sig.ident.to_string();
+    let export = |cache: TokenStream| {
+        cfg!(feature = "testing").then(|| {
+            quote! { ::comemo::internal::register_exporter(|| #cache); }
+        })
+    };
+
+    // A cache with a capacity replaces its least recently used entries.
+    let mut data = match &function.capacity {
+        Some(capacity) => quote! { ::comemo::internal::CacheData::bounded(#capacity) },
+        None => quote! { ::comemo::internal::CacheData::default() },
+    };
+
+    // A cache can be informed about each evicted output.
+    if let Some(on_evict) = &function.on_evict {
+        data = quote! { #data.on_evict(#on_evict) };
+    }
+    let data = quote! { ::comemo::internal::RwLock::new(#data) };
+
+    // A cache can panic on recursive calls with the same key.
+    let cycles = function.detect_cycles.then(|| quote! { .with_cycle_detection() });
+
+    // A cache whose output isn't thread-safe is guarded against access from
+    // other threads. Evictions from other threads skip it.
+    let (cache_ty, cache_init, cache, evictor) = if function.single_threaded {
+        let export = export(quote! {
+            __CACHE.try_get().map(|cache| cache.export(#name)).unwrap_or_default()
         });
+        let evictor = quote! { __CACHE.try_get().map_or(0, |cache| cache.#evict) };
+        (
+            quote! { ::comemo::internal::SingleThreaded<#cache_ty> },
+            quote! {{
+                let cache = ::comemo::internal::Cache::new(|| {
+                    ::comemo::internal::register_evictor(|eviction| #evictor);
+                    ::comemo::internal::register_reporter(|| {
+                        __CACHE.try_get().map(|cache| cache.stats(#name))
+                    });
+                    #export
+                    #data
+                }) #cycles;
+                // Safety: The wrapper is only used for this static.
+                unsafe { ::comemo::internal::SingleThreaded::new(cache) }
+            }},
+            quote! { __CACHE.get() },
+            evictor,
+        )
+    } else {
+        let export = export(quote! { __CACHE.export(#name) });
+        let evictor = quote! { __CACHE.#evict };
+        (
+            cache_ty,
+            quote! {
+                ::comemo::internal::Cache::new(|| {
+                    ::comemo::internal::register_evictor(|eviction| #evictor);
+                    ::comemo::internal::register_reporter(|| Some(__CACHE.stats(#name)));
+                    #export
+                    #data
+                }) #cycles
+            },
+            quote! { &__CACHE },
+            evictor,
+        )
+    };
+
+    // With multiple outputs, the memoized function is hidden and additionally
+    // takes the field of the tuple that should be returned.
+    let field = (!function.outputs.is_empty()).then(|| {
+        wrapped.sig.ident = quote::format_ident!("__{}_outputs", function.item.sig.ident);
+        wrapped
+            .sig
+            .generics
+            .params
+            .push(parse_quote! { __T: ::core::clone::Clone });
+        wrapped
+            .sig
+            .inputs
+            .push(parse_quote! { __field: fn(&#output) -> &__T });
+        wrapped.sig.output = parse_quote! { -> __T };
+        wrapped.attrs.push(parse_quote! { #[doc(hidden)] });
+        quote! { __field, }
+    });
+
+    // With a `cached_eq` or refresh twin, the memoized function is hidden,
+    // too, and additionally takes whether the call should be refreshed and
+    // whether the output should be compared with the entries it replaces.
+    if (function.cached_eq || function.refresh) && function.outputs.is_empty() {
+        let name = &function.item.sig.ident;
+        wrapped.sig.ident = quote::format_ident!("__{name}_memoized");
+        wrapped.attrs.push(parse_quote! { #[doc(hidden)] });
+    }
+    let refresh = if function.refresh {
+        wrapped.sig.inputs.push(parse_quote! { __refresh: bool });
+        quote! { __refresh }
+    } else {
+        quote! { false }
+    };
+    let compare = function.cached_eq.then(|| {
+        wrapped.sig.inputs.push(parse_quote! { __compare: bool });
+        wrapped.sig.output = parse_quote! { -> (#output, bool) };
+        quote! { __compare, }
+    });
+
+    // With a handle, the cache is moved into a hidden accessor function, so
+    // that the handle can reach it, too.
+    // The same applies to priming and peeking.
+    let mut shared = vec![];
+    let shared_cache = function.handle || function.prime || function.peek;
+    let declaration = if shared_cache {
+        let name = &function.item.sig.ident;
+        let vis = &function.item.vis;
+        let accessor = quote::format_ident!("__{name}_cache");
+        let path = if function.args.iter().any(|arg| matches!(arg, Argument::Receiver(_)))
+        {
+            quote! { Self::#accessor }
+        } else {
+            quote! { #accessor }
+        };
+        shared.push(quote! {
+            #[doc(hidden)]
+            #vis fn #accessor() -> &'static #cache_ty {
+                static __CACHE: #cache_ty = #cache_init;
+                &__CACHE
+            }
+        });
+
+        if function.handle {
+            let doc = format!(" A handle to the cache of [`{name}`].");
+            let handle_name = quote::format_ident!("{name}_cache");
+            shared.push(quote! {
+                #[doc = #doc]
+                #vis fn #handle_name() -> ::comemo::CacheHandle {
+                    ::comemo::CacheHandle::new(|eviction| {
+                        #[allow(non_snake_case)]
+                        let __CACHE = #path();
+                        #evictor
+                    })
+                }
+            });
+        }
+
+        if function.prime {
+            let doc = format!(
+                " Insert the output of a call to [`{name}`] without executing it."
+            );
+            let mut prime = forwarder(function, quote::format_ident!("{name}_prime"));
+            prime.attrs.retain(|attr| !attr.path().is_ident("doc"));
+            prime.attrs.push(parse_quote! { #[doc = #doc] });
+            prime.sig.asyncness = None;
+            prime.sig.inputs.push(parse_quote! { output: #cached });
+            prime.sig.output = syn::ReturnType::Default;
+            prime.block = parse_quote! { {
+                #[allow(non_snake_case)]
+                let __CACHE = #path();
+                let input = ::comemo::internal::Args(#arg_tuple);
+                ::comemo::internal::prime(input, #cache, output);
+            } };
+            shared.push(quote! { #prime });
+        }
+
+        if function.peek {
+            let doc = format!(
+                " Return the cached output of a call to [`{name}`] without executing it."
+            );
+            let mut peek = forwarder(function, quote::format_ident!("{name}_peek"));
+            peek.attrs.retain(|attr| !attr.path().is_ident("doc"));
+            peek.attrs.push(parse_quote! { #[doc = #doc] });
+            peek.sig.asyncness = None;
+            peek.sig.output = parse_quote! { -> ::core::option::Option<#cached> };
+            peek.block = parse_quote! { {
+                #[allow(non_snake_case)]
+                let __CACHE = #path();
+                let enabled = #enabled;
+                let input = ::comemo::internal::Args(#arg_tuple);
+                ::comemo::internal::peek(input, #cache, enabled)
+            } };
+            shared.push(quote! { #peek });
+        }
+
+        quote! {
+            #[allow(non_snake_case)]
+            let __CACHE = #path();
+        }
+    } else {
+        quote! { static __CACHE: #cache_ty = #cache_init; }
+    };
+
+    // Asynchronous functions produce a future that must be awaited.
+    let await_ = function.item.sig.asyncness.map(|_| quote! { .await });
+
+    // The condition is evaluated before the arguments are moved into the
+    // tuple, so that it can access all of them, including `self`.
+    wrapped.block = parse_quote! { {
+        #declaration
 
         #(#bounds;)*
+        #thread_safe
 
-        ::comemo::internal::memoized(
+        let __comemo_enabled: bool = #enabled;
+        ::comemo::internal::#memoized(
             ::comemo::internal::Args(#arg_tuple),
             &::core::default::Default::default(),
-            &__CACHE,
-            #enabled,
+            #cache,
+            __comemo_enabled,
+            #refresh,
+            #compare
+            #field
             #closure,
-        )
+        ) #await_
     } };
 
-    Ok(quote! { #wrapped })
+    quote! {
+        #wrapped
+        #(#shared)*
+    }
+}
+
+/// Replace the given lifetimes and anonymous lifetimes with `'static`.
+///
+/// Elided lifetimes of references and of `Tracked` and `TrackedMut` types are
+/// made explicit, too, as the type might be used outside of a `static` item.
+fn staticify(tokens: TokenStream, lifetimes: &[syn::Ident]) -> TokenStream {
+    let is_lifetime = |tree: Option<&proc_macro2::TokenTree>| match tree {
+        Some(proc_macro2::TokenTree::Punct(punct)) => punct.as_char() == '\'',
+        _ => false,
+    };
+
+    let mut output = TokenStream::new();
+    let mut iter = tokens.into_iter().peekable();
+    while let Some(tree) = iter.next() {
+        match tree {
+            proc_macro2::TokenTree::Punct(punct) if punct.as_char() == '&' => {
+                let span = punct.span();
+                output.extend(quote_spanned! { span => & });
+                if !is_lifetime(iter.peek()) {
+                    output.extend(quote_spanned! { span => 'static });
+                }
+            }
+            proc_macro2::TokenTree::Ident(ident)
+                if ident == "Tracked" || ident == "TrackedMut" =>
+            {
+                output.extend([proc_macro2::TokenTree::Ident(ident.clone())]);
+                let Some(proc_macro2::TokenTree::Punct(open)) = iter.peek() else {
+                    continue;
+                };
+                if open.as_char() != '<' {
+                    continue;
+                }
+                output.extend(iter.next());
+                if !is_lifetime(iter.peek()) {
+                    let span = ident.span();
+                    output.extend(quote_spanned! { span => 'static, });
+                }
+            }
+            proc_macro2::TokenTree::Punct(punct) if punct.as_char() == '\'' => {
+                if let Some(proc_macro2::TokenTree::Ident(ident)) = iter.peek() {
+                    if ident == "_" || lifetimes.contains(ident) {
+                        let span = ident.span();
+                        iter.next();
+                        output.extend(quote_spanned! { span => 'static });
+                        continue;
+                    }
+                }
+                output.extend([proc_macro2::TokenTree::Punct(punct)]);
+            }
+            proc_macro2::TokenTree::Group(group) => {
+                let mut new = proc_macro2::Group::new(
+                    group.delimiter(),
+                    staticify(group.stream(), lifetimes),
+                );
+                new.set_span(group.span());
+                output.extend([proc_macro2::TokenTree::Group(new)]);
+            }
+            tree => output.extend([tree]),
+        }
+    }
+    output
 }
 
 pub mod kw {
     syn::custom_keyword!(enabled);
+    syn::custom_keyword!(cached_eq);
+    syn::custom_keyword!(result);
+    syn::custom_keyword!(collect);
+    syn::custom_keyword!(cache_errors);
+    syn::custom_keyword!(refresh);
+    syn::custom_keyword!(key);
+    syn::custom_keyword!(assert_single_threaded);
+    syn::custom_keyword!(emit_unmemoized);
+    syn::custom_keyword!(policy);
+    syn::custom_keyword!(max_age);
+    syn::custom_keyword!(capacity);
+    syn::custom_keyword!(on_evict);
+    syn::custom_keyword!(handle);
+    syn::custom_keyword!(prime);
+    syn::custom_keyword!(peek);
+    syn::custom_keyword!(detect_cycles);
+    syn::custom_keyword!(hasher);
+    syn::custom_keyword!(ignore);
+    syn::custom_keyword!(prehash);
+    syn::custom_keyword!(content_hash);
+    syn::custom_keyword!(outputs);
 }
@@ -2,23 +2,47 @@ use super::*;
 
 /// Make a type trackable.
 pub fn expand(item: &syn::Item) -> Result<TokenStream> {
+    // Structs are tracked through generated getters for their public fields.
+    if let syn::Item::Struct(item) = item {
+        let syn::Fields::Named(fields) = &item.fields else {
+            bail!(item, "`track` can only be applied to structs with named fields");
+        };
+
+        let public = fields
+            .named
+            .iter()
+            .filter(|field| !matches!(field.vis, syn::Visibility::Inherited));
+        let block = getters(&item.ident, &item.generics, public);
+        let tracked = expand(&block)?;
+        return Ok(quote! {
+            #item
+            #tracked
+        });
+    }
+
     // Preprocess and validate the methods.
     let mut methods = vec![];
 
-    let (ty, generics, trait_) = match item {
-        syn::Item::Impl(item) => {
-            for param in item.generics.params.iter() {
-                match param {
-                    syn::GenericParam::Lifetime(_) => {}
-                    syn::GenericParam::Type(_) => {
-                        bail!(param, "tracked impl blocks cannot use type generics")
-                    }
-                    syn::GenericParam::Const(_) => {
-                        bail!(param, "tracked impl blocks cannot use const generics")
-                    }
-                }
+    // The item without comemo's method attributes.
+    let mut cleaned = item.clone();
+    match &mut cleaned {
+        syn::Item::Impl(item) => item.items.iter_mut().for_each(|item| {
+            if let syn::ImplItem::Fn(method) = item {
+                method.attrs.retain(|attr| !attr.path().is_ident("track"));
+                strip_arg_attrs(&mut method.sig);
             }
+        }),
+        syn::Item::Trait(item) => item.items.iter_mut().for_each(|item| {
+            if let syn::TraitItem::Fn(method) = item {
+                method.attrs.retain(|attr| !attr.path().is_ident("track"));
+                strip_arg_attrs(&mut method.sig);
+            }
+        }),
+        _ => {}
+    }
 
+    let (ty, generics, trait_) = match item {
+        syn::Item::Impl(item) => {
             for item in &item.items {
                 methods.push(prepare_impl_method(item)?);
             }
@@ -39,15 +63,18 @@ pub fn expand(item: &syn::Item) -> Result<TokenStream> {
             let ty = parse_quote! { dyn #name + '__comemo_dynamic };
             (ty, &item.generics, Some(item.ident.clone()))
         }
-        _ => bail!(item, "`track` can only be applied to impl blocks and traits"),
+        _ => {
+            bail!(item, "`track` can only be applied to impl blocks, traits and structs")
+        }
     };
 
     // Produce the necessary items for the type to become trackable.
-    let variants = create_variants(&methods);
-    let scope = create(&ty, generics, trait_, &methods)?;
+    let call_generics = create_call_generics(generics, &methods);
+    let variants = create_variants(&methods, &call_generics);
+    let scope = create(&ty, generics, &call_generics, trait_, &methods)?;
 
     Ok(quote! {
-        #item
+        #cleaned
         const _: () = {
             #variants
             #scope
@@ -55,14 +82,128 @@ pub fn expand(item: &syn::Item) -> Result<TokenStream> {
     })
 }
 
+/// Derive `Track` for a struct by generating a tracked getter per field.
+pub fn derive(item: &syn::DeriveInput) -> Result<TokenStream> {
+    let syn::Data::Struct(data) = &item.data else {
+        bail!(item, "`Track` can only be derived for structs");
+    };
+
+    let syn::Fields::Named(fields) = &data.fields else {
+        bail!(item, "`Track` can only be derived for structs with named fields");
+    };
+
+    let block = getters(&item.ident, &item.generics, &fields.named);
+    expand(&block)
+}
+
+/// Produce an impl block with a getter for each of the given fields that has
+/// the field's name and visibility.
+fn getters<'a>(
+    name: &syn::Ident,
+    generics: &syn::Generics,
+    fields: impl IntoIterator<Item = &'a syn::Field>,
+) -> syn::Item {
+    let getters = fields.into_iter().map(|field| {
+        let vis = &field.vis;
+        let name = &field.ident;
+        let ty = &field.ty;
+        quote! {
+            #vis fn #name(&self) -> &#ty {
+                &self.#name
+            }
+        }
+    });
+
+    let (impl_gen, type_gen, where_clause) = generics.split_for_impl();
+    parse_quote! {
+        impl #impl_gen #name #type_gen #where_clause {
+            #(#getters)*
+        }
+    }
+}
+
 /// Details about a method that should be tracked.
 struct Method {
+    attrs: Vec<syn::Attribute>,
     vis: syn::Visibility,
     sig: syn::Signature,
     mutable: bool,
     args: Vec<syn::Ident>,
     types: Vec<syn::Type>,
     kinds: Vec<Kind>,
+    defaults: Vec<Option<syn::Expr>>,
+    collect: Option<syn::Type>,
+    content_hash: bool,
+    untracked: bool,
+    rolling_hash: Option<syn::Expr>,
+}
+
+/// Additional metadata for a tracked method.
+#[derive(Default)]
+struct MethodMeta {
+    collect_hash: bool,
+    content_hash: bool,
+    untracked: bool,
+    rolling_hash: Option<syn::Expr>,
+}
+
+impl syn::parse::Parse for MethodMeta {
+    fn parse(input: syn::parse::ParseStream) -> Result<Self> {
+        let mut meta = Self::default();
+        while !input.is_empty() {
+            if utils::parse_flag::<kw::collect_hash>(input)? {
+                meta.collect_hash = true;
+            } else if utils::parse_flag::<kw::content_hash>(input)? {
+                meta.content_hash = true;
+            } else if utils::parse_flag::<kw::untracked>(input)? {
+                meta.untracked = true;
+            } else if let Some(expr) =
+                utils::parse_key_value::<kw::rolling_hash, _>(input)?
+            {
+                meta.rolling_hash = Some(expr);
+            } else {
+                return Err(input.error("comemo: unknown track argument"));
+            }
+        }
+        Ok(meta)
+    }
+}
+
+/// Parse the `#[track(...)]` attributes of a method.
+fn parse_method_meta(attrs: &[syn::Attribute]) -> Result<MethodMeta> {
+    let mut meta = MethodMeta::default();
+    for attr in attrs.iter().filter(|attr| attr.path().is_ident("track")) {
+        let parsed = attr.parse_args::<MethodMeta>()?;
+        meta.collect_hash |= parsed.collect_hash;
+        meta.content_hash |= parsed.content_hash;
+        meta.untracked |= parsed.untracked;
+        meta.rolling_hash = parsed.rolling_hash.or(meta.rolling_hash);
+    }
+    Ok(meta)
+}
+
+/// Parse the `#[track(default = <expr>)]` attribute of a method argument.
+fn parse_arg_default(attrs: &[syn::Attribute]) -> Result<Option<syn::Expr>> {
+    let mut default = None;
+    for attr in attrs.iter().filter(|attr| attr.path().is_ident("track")) {
+        let expr = attr.parse_args_with(|input: syn::parse::ParseStream| {
+            match utils::parse_key_value::<kw::default, _>(input)? {
+                Some(expr) if input.is_empty() => Ok(expr),
+                _ => Err(input.error("comemo: unknown track argument")),
+            }
+        })?;
+        default = Some(expr);
+    }
+    Ok(default)
+}
+
+/// Remove comemo's attributes from the arguments of a method.
+fn strip_arg_attrs(sig: &mut syn::Signature) {
+    for input in sig.inputs.iter_mut() {
+        if let syn::FnArg::Typed(typed) = input {
+            typed.attrs.retain(|attr| !attr.path().is_ident("track"));
+        }
+    }
 }
 
 /// Whether an argument to a tracked method is bare or by reference.
@@ -77,7 +218,8 @@ fn prepare_impl_method(item: &syn::ImplItem) -> Result<Method> {
         bail!(item, "only methods can be tracked");
     };
 
-    prepare_method(method.vis.clone(), &method.sig)
+    let meta = parse_method_meta(&method.attrs)?;
+    prepare_method(&method.attrs, method.vis.clone(), &method.sig, meta)
 }
 
 /// Preprocess and validate a method in a trait.
@@ -86,11 +228,27 @@ fn prepare_trait_method(item: &syn::TraitItem) -> Result<Method> {
         bail!(item, "only methods can be tracked");
     };
 
-    prepare_method(syn::Visibility::Inherited, &method.sig)
+    let meta = parse_method_meta(&method.attrs)?;
+    if meta.collect_hash {
+        bail!(method.sig, "`collect_hash` is not supported in tracked traits");
+    }
+
+    let prepared =
+        prepare_method(&method.attrs, syn::Visibility::Inherited, &method.sig, meta)?;
+    if prepared.defaults.iter().any(Option::is_some) {
+        bail!(method.sig, "`default` arguments are not supported in tracked traits");
+    }
+
+    Ok(prepared)
 }
 
 /// Preprocess and validate a method signature.
-fn prepare_method(vis: syn::Visibility, sig: &syn::Signature) -> Result<Method> {
+fn prepare_method(
+    attrs: &[syn::Attribute],
+    vis: syn::Visibility,
+    sig: &syn::Signature,
+    meta: MethodMeta,
+) -> Result<Method> {
     if let Some(unsafety) = sig.unsafety {
         bail!(unsafety, "unsafe methods cannot be tracked");
     }
@@ -117,13 +275,28 @@ fn prepare_method(vis: syn::Visibility, sig: &syn::Signature) -> Result<Method>
         bail!(sig, "tracked method must take self");
     };
 
-    if receiver.reference.is_none() {
+    let Some(mutable) = receiver_mutability(receiver) else {
+        if is_pinned(&receiver.ty) {
+            bail!(
+                receiver,
+                "tracked method cannot take pinned self, \
+                 track a wrapper around `Pin<&T>` instead"
+            );
+        }
+        if receiver.colon_token.is_some() && !is_self(&receiver.ty) {
+            bail!(
+                receiver,
+                "tracked method must take `&self` or `&mut self` because \
+                 tracked values are only accessible by reference"
+            );
+        }
         bail!(receiver, "tracked method must take self by reference");
-    }
+    };
 
     let mut args = vec![];
     let mut types = vec![];
     let mut kinds = vec![];
+    let mut defaults = vec![];
 
     for input in inputs {
         let typed = match input {
@@ -156,9 +329,16 @@ fn prepare_method(vis: syn::Visibility, sig: &syn::Signature) -> Result<Method>
             ty => (ty.clone(), Kind::Normal),
         };
 
+        // Only trailing arguments can be left out.
+        let default = parse_arg_default(&typed.attrs)?;
+        if default.is_none() && defaults.iter().any(Option::is_some) {
+            bail!(typed, "arguments after a `default` argument must have a default, too");
+        }
+
         args.push(ident.clone());
         types.push(ty);
-        kinds.push(kind)
+        kinds.push(kind);
+        defaults.push(default);
     }
 
     if let syn::ReturnType::Type(_, ty) = &sig.output {
@@ -169,19 +349,193 @@ fn prepare_method(vis: syn::Visibility, sig: &syn::Signature) -> Result<Method>
         }
     }
 
+    if meta.content_hash && meta.collect_hash {
+        bail!(sig, "`content_hash` cannot be combined with `collect_hash`");
+    }
+
+    if meta.untracked {
+        if mutable {
+            bail!(receiver, "`untracked` methods must take `&self`");
+        }
+
+        if meta.collect_hash || meta.content_hash {
+            bail!(
+                sig,
+                "`untracked` cannot be combined with `collect_hash` or `content_hash`"
+            );
+        }
+    }
+
+    if let Some(expr) = &meta.rolling_hash {
+        if mutable {
+            bail!(receiver, "`rolling_hash` methods must take `&self`");
+        }
+
+        if meta.collect_hash || meta.content_hash || meta.untracked {
+            bail!(
+                expr,
+                "`rolling_hash` cannot be combined with `collect_hash`, \
+                 `content_hash` or `untracked`"
+            );
+        }
+    }
+
+    let mut collect = None;
+    if meta.collect_hash {
+        if mutable {
+            bail!(receiver, "`collect_hash` methods must take `&self`");
+        }
+
+        let syn::ReturnType::Type(_, ty) = &sig.output else {
+            bail!(sig, "`collect_hash` methods must return `impl Iterator<Item = T>`");
+        };
+
+        let Some(item) = utils::iterator_item(ty, &["Iterator"]) else {
+            bail!(ty, "`collect_hash` methods must return `impl Iterator<Item = T>`");
+        };
+
+        collect = Some(item);
+    }
+
+    // Carry inert attributes like docs, `#[must_use]` and `#[deprecated]` over
+    // to the surface. The wrapper brings its own `#[inline]` and
+    // `#[track_caller]`.
+    let attrs = attrs
+        .iter()
+        .filter(|attr| {
+            let path = attr.path();
+            !path.is_ident("track")
+                && !path.is_ident("inline")
+                && !path.is_ident("track_caller")
+        })
+        .cloned()
+        .collect();
+
+    let mut sig = sig.clone();
+    strip_arg_attrs(&mut sig);
+
     Ok(Method {
+        attrs,
         vis,
-        sig: sig.clone(),
-        mutable: receiver.mutability.is_some(),
+        sig,
+        mutable,
         args,
         types,
         kinds,
+        defaults,
+        collect,
+        content_hash: meta.content_hash,
+        untracked: meta.untracked,
+        rolling_hash: meta.rolling_hash,
     })
 }
 
+/// Whether a receiver is `&self` or `&mut self`, returning its mutability.
+///
+/// The receiver may also be spelled out as `self: &Self` or `self: &mut Self`.
+fn receiver_mutability(receiver: &syn::Receiver) -> Option<bool> {
+    let syn::Type::Reference(reference) = receiver.ty.as_ref() else { return None };
+    is_self(&reference.elem).then_some(reference.mutability.is_some())
+}
+
+/// Whether a type is `Self`.
+fn is_self(ty: &syn::Type) -> bool {
+    matches!(ty, syn::Type::Path(path) if path.qself.is_none() && path.path.is_ident("Self"))
+}
+
+/// Whether a receiver type is of the form `Pin<_>`.
+fn is_pinned(ty: &syn::Type) -> bool {
+    let syn::Type::Path(path) = ty else { return false };
+    path.path
+        .segments
+        .last()
+        .is_some_and(|segment| segment.ident == "Pin")
+}
+
+/// Produce the generics of the call and variant types.
+///
+/// They carry the type and const parameters of the impl block, but not its
+/// lifetimes as constraints are `'static`. The where clause ensures that the arguments of
+/// all tracked methods can be stored in a constraint.
+fn create_call_generics(generics: &syn::Generics, methods: &[Method]) -> syn::Generics {
+    let mut call_generics = syn::Generics::default();
+    for param in &generics.params {
+        match param.clone() {
+            syn::GenericParam::Lifetime(_) => {}
+            syn::GenericParam::Type(mut param) => {
+                param.eq_token = None;
+                param.default = None;
+                param.bounds = param
+                    .bounds
+                    .into_iter()
+                    .filter(|bound| !matches!(bound, syn::TypeParamBound::Lifetime(_)))
+                    .collect();
+                call_generics.params.push(syn::GenericParam::Type(param));
+            }
+            syn::GenericParam::Const(mut param) => {
+                param.eq_token = None;
+                param.default = None;
+                call_generics.params.push(syn::GenericParam::Const(param));
+            }
+        }
+    }
+
+    let predicates = &mut call_generics.make_where_clause().predicates;
+    for param in generics.type_params() {
+        let ident = &param.ident;
+        predicates.push(parse_quote! { #ident: 'static });
+    }
+
+    for ty in methods.iter().flat_map(|m| &m.types) {
+        predicates.push(parse_quote! { #ty: ::std::borrow::ToOwned });
+        predicates.push(parse_quote! {
+            <#ty as ::std::borrow::ToOwned>::Owned: ::core::clone::Clone
+                + ::core::cmp::PartialEq
+                + ::core::hash::Hash
+        });
+    }
+
+    call_generics
+}
+
 /// Produces the variants for the constraint.
-fn create_variants(methods: &[Method]) -> TokenStream {
+fn create_variants(methods: &[Method], call_generics: &syn::Generics) -> TokenStream {
+    let (impl_gen, type_gen, where_clause) = call_generics.split_for_impl();
     let variants = methods.iter().map(create_variant);
+
+    // Type and const parameters that no method mentions would be unused, so
+    // they are captured by an uninhabited variant.
+    let params: Vec<syn::Type> = call_generics
+        .params
+        .iter()
+        .filter_map(|param| match param {
+            syn::GenericParam::Type(param) => {
+                let ident = &param.ident;
+                Some(parse_quote! { #ident })
+            }
+            syn::GenericParam::Const(param) => {
+                let ident = &param.ident;
+                Some(parse_quote! { [(); #ident] })
+            }
+            syn::GenericParam::Lifetime(_) => None,
+        })
+        .collect();
+    let generic = !params.is_empty();
+    let phantom = generic.then(|| {
+        quote! {
+            __ComemoPhantom(
+                ::core::convert::Infallible,
+                ::core::marker::PhantomData<fn() -> (#(#params,)*)>,
+            ),
+        }
+    });
+    let phantom_ref = generic.then(|| {
+        quote! { __ComemoVariant::__ComemoPhantom(never, _) => match *never {}, }
+    });
+    let phantom_own = generic.then(|| {
+        quote! { __ComemoVariant::__ComemoPhantom(never, _) => match never {}, }
+    });
+
     let is_mutable_variants = methods.iter().map(|m| {
         let name = &m.sig.ident;
         let mutable = m.mutable;
@@ -192,26 +546,134 @@ fn create_variants(methods: &[Method]) -> TokenStream {
         .then(|| {
             quote! {
                 match &self.0 {
-                    #(#is_mutable_variants),*
+                    #(#is_mutable_variants,)*
+                    #phantom_ref
                 }
             }
         })
         .unwrap_or_else(|| quote! { false });
 
+    let name_variants = methods.iter().map(|m| {
+        let name = &m.sig.ident;
+        let string = name.to_string();
+        quote! { __ComemoVariant::#name(..) => #string }
+    });
+
+    let name = if !methods.is_empty() {
+        quote! {
+            match &self.0 {
+                #(#name_variants,)*
+                #phantom_ref
+            }
+        }
+    } else {
+        quote! { "" }
+    };
+
+    // The standard derives would require all type parameters to implement
+    // the derived traits, so the implementations are spelled out.
+    let bindings = |method: &Method, prefix: &str| -> Vec<syn::Ident> {
+        (0..method.types.len())
+            .map(|i| quote::format_ident!("__comemo_{prefix}{i}"))
+            .collect()
+    };
+
+    let clones = methods.iter().map(|m| {
+        let name = &m.sig.ident;
+        let a = bindings(m, "a");
+        quote! {
+            __ComemoVariant::#name(#(ref #a),*) => {
+                __ComemoVariant::#name(#(::core::clone::Clone::clone(#a)),*)
+            }
+        }
+    });
+
+    let eqs = methods.iter().map(|m| {
+        let name = &m.sig.ident;
+        let a = bindings(m, "a");
+        let b = bindings(m, "b");
+        quote! {
+            (__ComemoVariant::#name(#(#a),*), __ComemoVariant::#name(#(#b),*)) => {
+                true #(&& #a == #b)*
+            }
+        }
+    });
+
+    let hashes = methods.iter().map(|m| {
+        let name = &m.sig.ident;
+        let a = bindings(m, "a");
+        quote! {
+            __ComemoVariant::#name(#(ref #a),*) => {
+                #(::core::hash::Hash::hash(#a, state);)*
+            }
+        }
+    });
+
     quote! {
-        #[derive(Clone, PartialEq, Hash)]
-        pub struct __ComemoCall(__ComemoVariant);
+        pub struct __ComemoCall #impl_gen(__ComemoVariant #type_gen) #where_clause;
+
+        impl #impl_gen ::core::clone::Clone for __ComemoCall #type_gen #where_clause {
+            fn clone(&self) -> Self {
+                Self(::core::clone::Clone::clone(&self.0))
+            }
+        }
+
+        impl #impl_gen ::core::cmp::PartialEq for __ComemoCall #type_gen #where_clause {
+            fn eq(&self, other: &Self) -> bool {
+                self.0 == other.0
+            }
+        }
 
-        impl ::comemo::internal::Call for __ComemoCall {
+        impl #impl_gen ::core::hash::Hash for __ComemoCall #type_gen #where_clause {
+            fn hash<H: ::core::hash::Hasher>(&self, state: &mut H) {
+                ::core::hash::Hash::hash(&self.0, state);
+            }
+        }
+
+        impl #impl_gen ::comemo::internal::Call for __ComemoCall #type_gen #where_clause {
             fn is_mutable(&self) -> bool {
                 #is_mutable
             }
+
+            fn name(&self) -> &'static str {
+                #name
+            }
         }
 
-        #[derive(Clone, PartialEq, Hash)]
         #[allow(non_camel_case_types)]
-        enum __ComemoVariant {
+        enum __ComemoVariant #impl_gen #where_clause {
             #(#variants,)*
+            #phantom
+        }
+
+        impl #impl_gen ::core::clone::Clone for __ComemoVariant #type_gen #where_clause {
+            fn clone(&self) -> Self {
+                match *self {
+                    #(#clones)*
+                    #phantom_own
+                }
+            }
+        }
+
+        impl #impl_gen ::core::cmp::PartialEq for __ComemoVariant #type_gen #where_clause {
+            #[allow(unreachable_patterns)]
+            fn eq(&self, other: &Self) -> bool {
+                match (self, other) {
+                    #(#eqs)*
+                    _ => false,
+                }
+            }
+        }
+
+        impl #impl_gen ::core::hash::Hash for __ComemoVariant #type_gen #where_clause {
+            #[allow(unused_variables)]
+            fn hash<H: ::core::hash::Hasher>(&self, state: &mut H) {
+                ::core::hash::Hash::hash(&::core::mem::discriminant(self), state);
+                match *self {
+                    #(#hashes)*
+                    #phantom_own
+                }
+            }
         }
     }
 }
@@ -220,20 +682,33 @@ fn create_variants(methods: &[Method]) -> TokenStream {
 fn create(
     ty: &syn::Type,
     generics: &syn::Generics,
+    call_generics: &syn::Generics,
     trait_: Option<syn::Ident>,
     methods: &[Method],
 ) -> Result<TokenStream> {
     let t: syn::GenericParam = parse_quote! { '__comemo_tracked };
     let r: syn::GenericParam = parse_quote! { '__comemo_retrack };
     let d: syn::GenericParam = parse_quote! { '__comemo_dynamic };
+    // Mutable calls are validated on a copy of the value, which is only made
+    // once the first mutable call is encountered.
     let maybe_cloned = if methods.iter().any(|it| it.mutable) {
-        quote! { ::core::clone::Clone::clone(self) }
+        quote! { ::std::borrow::Cow::Borrowed(self) }
     } else {
         quote! { self }
     };
 
-    // Prepare generics.
+    // Prepare generics. The type can only be tracked if its calls can be
+    // stored in a constraint.
+    let mut generics = generics.clone();
+    if let Some(call_where) = &call_generics.where_clause {
+        let predicates = call_where.predicates.iter().cloned();
+        generics.make_where_clause().predicates.extend(predicates);
+    }
     let (impl_gen, type_gen, where_clause) = generics.split_for_impl();
+    let (_, call_gen, _) = call_generics.split_for_impl();
+    let phantom = (!call_generics.params.is_empty()).then(|| {
+        quote! { __ComemoVariant::__ComemoPhantom(never, _) => match *never {}, }
+    });
     let mut impl_params: syn::Generics = parse_quote! { #impl_gen };
     let mut type_params: syn::Generics = parse_quote! { #type_gen };
     if trait_.is_some() {
@@ -252,7 +727,7 @@ fn create(
     let validate = if !methods.is_empty() {
         quote! {
             let mut this = #maybe_cloned;
-            constraint.validate(|call| match &call.0 { #(#validations,)* })
+            constraint.validate(|call| match &call.0 { #(#validations,)* #phantom })
         }
     } else {
         quote! { true }
@@ -261,7 +736,7 @@ fn create(
         quote! {
             let mut this = #maybe_cloned;
             constraint.validate_with_id(
-                |call| match &call.0 { #(#validations,)* },
+                |call| match &call.0 { #(#validations,)* #phantom },
                 id,
             )
         }
@@ -274,7 +749,7 @@ fn create(
     let replays = methods.iter().map(create_replay);
     let replay = (!immutable).then(|| {
         quote! {
-            constraint.replay(|call| match &call.0 { #(#replays,)* });
+            constraint.replay(|call| match &call.0 { #(#replays,)* #phantom });
         }
     });
 
@@ -294,8 +769,9 @@ fn create(
     Ok(quote! {
         impl #impl_params ::comemo::Track for #ty #where_clause {}
 
+        #[allow(deprecated)]
         impl #impl_params ::comemo::Validate for #ty #where_clause {
-            type Constraint = ::comemo::internal::#constraint<__ComemoCall>;
+            type Constraint = ::comemo::internal::#constraint<__ComemoCall #call_gen>;
 
             #[inline]
             fn validate(&self, constraint: &Self::Constraint) -> bool {
@@ -349,7 +825,7 @@ fn create(
         #where_clause;
 
         #[allow(dead_code)]
-        impl #impl_params_t #prefix __ComemoSurface #type_params_t {
+        impl #impl_params_t #prefix __ComemoSurface #type_params_t #where_clause {
             #(#wrapper_methods)*
         }
 
@@ -358,7 +834,7 @@ fn create(
         #where_clause;
 
         #[allow(dead_code)]
-        impl #impl_params_t #prefix __ComemoSurfaceMut #type_params_t {
+        impl #impl_params_t #prefix __ComemoSurfaceMut #type_params_t #where_clause {
             #(#wrapper_methods_mut)*
         }
     })
@@ -379,9 +855,27 @@ fn create_validation(method: &Method) -> TokenStream {
         Kind::Normal => quote! { #arg.to_owned() },
         Kind::Reference => quote! { #arg },
     });
+    if let Some(expr) = &method.rolling_hash {
+        let expr = replace_self(quote! { #expr }, &parse_quote! { this });
+        return quote! {
+            __ComemoVariant::#name(..) => ::comemo::internal::hash(&(#expr))
+        };
+    }
+
+    let this = if method.mutable {
+        quote! { ::std::borrow::Cow::to_mut(&mut this) }
+    } else {
+        quote! { (*this) }
+    };
+    let hash = if method.collect.is_some() {
+        quote! { ::comemo::internal::hash_iter(#this.#name(#(#prepared),*)) }
+    } else if method.content_hash {
+        quote! { ::comemo::ContentHash::content_hash(&#this.#name(#(#prepared),*)) }
+    } else {
+        quote! { ::comemo::internal::hash(&#this.#name(#(#prepared),*)) }
+    };
     quote! {
-        __ComemoVariant::#name(#(#args),*)
-            => ::comemo::internal::hash(&this.#name(#(#prepared),*))
+        __ComemoVariant::#name(#(#args),*) => #hash
     }
 }
 
@@ -403,9 +897,10 @@ fn create_replay(method: &Method) -> TokenStream {
 
 /// Produce a wrapped surface method.
 fn create_wrapper(method: &Method, tracked_mut: bool) -> TokenStream {
+    let attrs = &method.attrs;
     let name = &method.sig.ident;
     let vis = &method.vis;
-    let sig = &method.sig;
+    let mut sig = method.sig.clone();
     let args = &method.args;
     let to_parts = if !tracked_mut {
         quote! { to_parts_ref(self.0) }
@@ -414,20 +909,163 @@ fn create_wrapper(method: &Method, tracked_mut: bool) -> TokenStream {
     } else {
         quote! { to_parts_mut_mut(&mut self.0) }
     };
+
+    // Iterators are collected into a vector.
+    let call = quote! { __comemo_value.#name(#(#args,)*) };
+    let (call, hash) = match (&method.collect, &method.rolling_hash) {
+        (Some(item), _) => {
+            sig.output = parse_quote! { -> ::std::vec::Vec<#item> };
+            (
+                quote! { ::core::iter::Iterator::collect::<::std::vec::Vec<_>>(#call) },
+                quote! { ::comemo::internal::hash_iter(&output) },
+            )
+        }
+        (None, Some(expr)) => {
+            let expr = replace_self(quote! { #expr }, &parse_quote! { __comemo_value });
+            (call, quote! { ::comemo::internal::hash(&(#expr)) })
+        }
+        (None, None) if method.content_hash => {
+            (call, quote! { ::comemo::ContentHash::content_hash(&output) })
+        }
+        (None, None) => (call, quote! { ::comemo::internal::hash(&output) }),
+    };
+
+    // Trailing arguments with defaults can be left out through a second
+    // method, which forwards to this one.
+    let defaulted = create_defaulted(method, &sig);
+
+    // Calls to untracked methods are not recorded.
+    if method.untracked {
+        return quote! {
+            #(#attrs)*
+            #[track_caller]
+            #[inline]
+            #vis #sig {
+                let (__comemo_value, _) = ::comemo::internal::#to_parts;
+                #[allow(deprecated)]
+                let output = #call;
+                output
+            }
+
+            #defaulted
+        };
+    }
+
+    // Check that borrowed arguments survive the trip through the variant.
+    let mut fields = vec![];
+    let mut round_trips = vec![];
+    for (i, (arg, kind)) in method.args.iter().zip(&method.kinds).enumerate() {
+        if matches!(kind, Kind::Reference) {
+            let owned = quote::format_ident!("__comemo_owned_{i}");
+            round_trips.push(quote! {
+                (&::comemo::internal::RoundTrip(#arg, #owned)).check();
+            });
+            fields.push(quote! { #owned });
+        } else {
+            fields.push(quote! { _ });
+        }
+    }
+    let round_trips = (!round_trips.is_empty()).then(|| {
+        quote! {
+            #[cfg(debug_assertions)]
+            {
+                #[allow(unused_imports)]
+                use ::comemo::internal::{CheckRoundTrip as _, SkipRoundTrip as _};
+                #[allow(irrefutable_let_patterns)]
+                if let __ComemoVariant::#name(#(#fields),*) = &__comemo_variant {
+                    #(#round_trips)*
+                }
+            }
+        }
+    });
+
     quote! {
+        #(#attrs)*
         #[track_caller]
         #[inline]
         #vis #sig {
             let __comemo_variant = __ComemoVariant::#name(#(#args.to_owned()),*);
+            #round_trips
             let (__comemo_value, __comemo_constraint) = ::comemo::internal::#to_parts;
-            let output = __comemo_value.#name(#(#args,)*);
+            #[allow(deprecated)]
+            let output = #call;
             if let Some(constraint) = __comemo_constraint {
                 constraint.push(
                     __ComemoCall(__comemo_variant),
-                    ::comemo::internal::hash(&output),
+                    #hash,
                 );
             }
             output
         }
+
+        #defaulted
+    }
+}
+
+/// Produce a method named `<name>_default` that leaves out the arguments with
+/// defaults and forwards to the wrapper with the given signature.
+fn create_defaulted(method: &Method, sig: &syn::Signature) -> Option<TokenStream> {
+    let count = method.defaults.iter().filter(|default| default.is_some()).count();
+    if count == 0 {
+        return None;
     }
+
+    let attrs = &method.attrs;
+    let vis = &method.vis;
+    let name = &method.sig.ident;
+    let mut sig = sig.clone();
+    sig.ident = quote::format_ident!("{}_default", name);
+    sig.inputs = sig.inputs.iter().take(sig.inputs.len() - count).cloned().collect();
+
+    let args =
+        method
+            .args
+            .iter()
+            .zip(&method.defaults)
+            .map(|(arg, default)| match default {
+                Some(expr) => quote! { #expr },
+                None => quote! { #arg },
+            });
+
+    Some(quote! {
+        #(#attrs)*
+        #[track_caller]
+        #[inline]
+        #vis #sig {
+            #[allow(deprecated)]
+            let output = self.#name(#(#args),*);
+            output
+        }
+    })
+}
+
+/// Replace the `self` keyword with the given identifier.
+fn replace_self(tokens: TokenStream, with: &syn::Ident) -> TokenStream {
+    tokens
+        .into_iter()
+        .map(|tree| match tree {
+            proc_macro2::TokenTree::Ident(ident) if ident == "self" => {
+                let mut with = with.clone();
+                with.set_span(ident.span());
+                proc_macro2::TokenTree::Ident(with)
+            }
+            proc_macro2::TokenTree::Group(group) => {
+                let mut new = proc_macro2::Group::new(
+                    group.delimiter(),
+                    replace_self(group.stream(), with),
+                );
+                new.set_span(group.span());
+                proc_macro2::TokenTree::Group(new)
+            }
+            tree => tree,
+        })
+        .collect()
+}
+
+pub mod kw {
+    syn::custom_keyword!(collect_hash);
+    syn::custom_keyword!(content_hash);
+    syn::custom_keyword!(untracked);
+    syn::custom_keyword!(rolling_hash);
+    syn::custom_keyword!(default);
 }
@@ -20,9 +20,60 @@ pub fn parse_key_value<K: Token + Default + Parse, V: Parse>(
     Ok(Some(value))
 }
 
+/// Parse a metadata flag without a value.
+pub fn parse_flag<K: Token + Default + Parse>(input: ParseStream) -> Result<bool> {
+    if !input.peek(|_| K::default()) {
+        return Ok(false);
+    }
+
+    let _: K = input.parse()?;
+    eat_comma(input);
+    Ok(true)
+}
+
+/// Parse a metadata key with a parenthesized, comma-separated list of values.
+pub fn parse_list<K: Token + Default + Parse, V: Parse>(
+    input: ParseStream,
+) -> Result<Option<Vec<V>>> {
+    if !input.peek(|_| K::default()) {
+        return Ok(None);
+    }
+
+    let _: K = input.parse()?;
+    let content;
+    syn::parenthesized!(content in input);
+    let values = content.parse_terminated(V::parse, syn::Token![,])?;
+    eat_comma(input);
+    Ok(Some(values.into_iter().collect()))
+}
+
 /// Parse a comma if there is one.
 pub fn eat_comma(input: ParseStream) {
     if input.peek(syn::Token![,]) {
         let _: syn::Token![,] = input.parse().unwrap();
     }
 }
+
+/// Extract the item type from an `impl Trait<Item = T>` type, where `Trait` is
+/// one of the given trait names.
+pub fn iterator_item(ty: &syn::Type, traits: &[&str]) -> Option<syn::Type> {
+    let syn::Type::ImplTrait(ty) = ty else { return None };
+    ty.bounds.iter().find_map(|bound| {
+        let syn::TypeParamBound::Trait(bound) = bound else { return None };
+        let segment = bound.path.segments.last()?;
+        if !traits.iter().any(|name| segment.ident == name) {
+            return None;
+        }
+
+        let syn::PathArguments::AngleBracketed(args) = &segment.arguments else {
+            return None;
+        };
+
+        args.args.iter().find_map(|arg| match arg {
+            syn::GenericArgument::AssocType(assoc) if assoc.ident == "Item" => {
+                Some(assoc.ty.clone())
+            }
+            _ => None,
+        })
+    })
+}
@@ -1,18 +1,31 @@
-use std::collections::HashMap;
-use std::sync::atomic::{AtomicUsize, Ordering};
+use std::sync::atomic::{AtomicBool, AtomicUsize, Ordering};
 
 use parking_lot::{MappedRwLockReadGuard, Mutex, RwLock, RwLockReadGuard};
 
+use crate::constraint::PassthroughHashMap;
+
 /// The global list of currently alive accelerators.
 static ACCELERATORS: RwLock<(usize, Vec<Accelerator>)> = RwLock::new((0, Vec::new()));
 
 /// The current ID of the accelerator.
 static ID: AtomicUsize = AtomicUsize::new(0);
 
+/// The maximum number of accelerators between evictions.
+static LIMIT: AtomicUsize = AtomicUsize::new(usize::MAX);
+
+/// Whether accelerators are used at all.
+static ENABLED: AtomicBool = AtomicBool::new(true);
+
+/// The maximum total number of entries across all accelerators.
+static CAPACITY: AtomicUsize = AtomicUsize::new(usize::MAX);
+
+/// The total number of entries across all accelerators.
+static ENTRIES: AtomicUsize = AtomicUsize::new(0);
+
 /// The type of each individual accelerator.
 ///
 /// Maps from call hashes to return hashes.
-type Accelerator = Mutex<HashMap<u128, u128>>;
+type Accelerator = Mutex<PassthroughHashMap<u128, u128>>;
 
 /// Generate a new accelerator.
 pub fn id() -> usize {
@@ -29,16 +42,82 @@ pub fn evict() {
     *offset = ID.load(Ordering::SeqCst);
 
     // Clear all accelerators while keeping the memory allocated.
-    vec.iter_mut().for_each(|accelerator| accelerator.lock().clear())
+    vec.iter_mut().for_each(|accelerator| accelerator.lock().clear());
+    ENTRIES.store(0, Ordering::SeqCst);
+}
+
+/// Clear the accelerators and release their memory without evicting any
+/// memoized results.
+///
+/// Accelerators speed up the validation of constraints for values that were
+/// tracked since the last eviction. They are cleared during each call to
+/// [`evict`](crate::evict), but keep their memory allocated for reuse. After a
+/// burst of `track()` activity, this function can be used to reclaim that
+/// memory while keeping all memoized results.
+pub fn clear_accelerators() {
+    let mut accelerators = ACCELERATORS.write();
+    let (offset, vec) = &mut *accelerators;
+    *offset = ID.load(Ordering::SeqCst);
+    *vec = Vec::new();
+    ENTRIES.store(0, Ordering::SeqCst);
+}
+
+/// Limit the number of accelerators that are created between evictions.
+///
+/// Each call to `track()` creates a new accelerator that lives until the next
+/// eviction. Because tracked values carry no information about when they are
+/// dropped, accelerators of values that are long gone cannot be reused, so a
+/// workload that tracks many short-lived values grows the list of accelerators
+/// with each of them. Values tracked after the limit was reached don't get an
+/// accelerator and recompute the return values of their tracked methods during
+/// validation instead. By default, there is no limit.
+pub fn set_accelerator_limit(limit: usize) {
+    LIMIT.store(limit, Ordering::SeqCst);
+}
+
+/// Limit the total number of entries across all accelerators.
+///
+/// Each accelerator remembers the return values of tracked methods that were
+/// validated for one tracked value. In a workload that never calls
+/// [`evict`](crate::evict), they would grow without bound. Once the limit is
+/// exceeded, the accelerators of the values that were tracked the longest time
+/// ago are dropped until the limit is met again, independently of cache
+/// eviction. By default, there is no limit.
+pub fn set_accelerator_capacity(entries: usize) {
+    CAPACITY.store(entries, Ordering::SeqCst);
+}
+
+/// Enable or disable the acceleration of constraint validation.
+///
+/// When disabled, no accelerators are created and validation always
+/// recomputes the return values of tracked methods. This trades validation
+/// speed for memory, which can pay off in short-lived processes that track
+/// many distinct values. Accelerators that already exist keep their memory
+/// until the next [`evict`](crate::evict) or
+/// [`clear_accelerators`]. By default, acceleration is enabled.
+pub fn set_acceleration(enabled: bool) {
+    ENABLED.store(enabled, Ordering::SeqCst);
 }
 
 /// Get an accelerator by ID.
 pub fn get(id: usize) -> Option<MappedRwLockReadGuard<'static, Accelerator>> {
+    if !ENABLED.load(Ordering::Relaxed) {
+        return None;
+    }
+
+    if ENTRIES.load(Ordering::Relaxed) > CAPACITY.load(Ordering::Relaxed) {
+        shrink();
+    }
+
     // We always lock the accelerators, as we need to make sure that the
     // accelerator is not removed while we are reading it.
     let mut accelerators = ACCELERATORS.read();
 
     let mut i = id.checked_sub(accelerators.0)?;
+    if i >= LIMIT.load(Ordering::Relaxed) {
+        return None;
+    }
+
     if i >= accelerators.1.len() {
         drop(accelerators);
         resize(i + 1);
@@ -53,11 +132,37 @@ pub fn get(id: usize) -> Option<MappedRwLockReadGuard<'static, Accelerator>> {
     Some(RwLockReadGuard::map(accelerators, move |(_, vec)| &vec[i]))
 }
 
+/// Note that an entry was inserted into an accelerator.
+pub fn inserted() {
+    ENTRIES.fetch_add(1, Ordering::Relaxed);
+}
+
+/// Drop the oldest accelerators until the capacity is met.
+#[cold]
+fn shrink() {
+    let mut accelerators = ACCELERATORS.write();
+    let (offset, vec) = &mut *accelerators;
+    let capacity = CAPACITY.load(Ordering::SeqCst);
+    let mut entries = ENTRIES.load(Ordering::SeqCst);
+    let mut dropped = 0;
+    for accelerator in vec.iter_mut() {
+        if entries <= capacity {
+            break;
+        }
+        entries = entries.saturating_sub(accelerator.get_mut().len());
+        dropped += 1;
+    }
+
+    vec.drain(..dropped);
+    *offset += dropped;
+    ENTRIES.store(entries, Ordering::SeqCst);
+}
+
 /// Adjusts the amount of accelerators.
 #[cold]
 fn resize(len: usize) {
     let mut pair = ACCELERATORS.write();
     if len > pair.1.len() {
-        pair.1.resize_with(len, || Mutex::new(HashMap::new()));
+        pair.1.resize_with(len, || Mutex::new(PassthroughHashMap::default()));
     }
 }
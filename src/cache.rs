@@ -1,126 +1,1051 @@
-use std::collections::HashMap;
-use std::sync::atomic::{AtomicUsize, Ordering};
+use std::cell::{Cell, RefCell};
+use std::collections::{BTreeMap, HashMap};
+use std::future::Future;
+use std::marker::PhantomData;
+use std::sync::atomic::{AtomicBool, AtomicU64, AtomicUsize, Ordering};
+use std::sync::Arc;
+use std::thread::ThreadId;
+use std::time::{Duration, Instant};
 
-use once_cell::sync::Lazy;
-use parking_lot::RwLock;
-use siphasher::sip128::{Hasher128, SipHasher13};
+use once_cell::sync::{Lazy, OnceCell};
+use parking_lot::{Condvar, Mutex, RwLock};
+use siphasher::sip128::SipHasher13;
 
 use crate::accelerate;
-use crate::constraint::Join;
+#[cfg(feature = "testing")]
+use crate::constraint::CallLabel;
+use crate::constraint::{self, Join, KeyHasher};
+use crate::eviction::{self, EntryMeta, EvictionPolicy, LruPolicy, EPOCH};
+use crate::impurity::{self, ImpurityPolicy};
 use crate::input::Input;
 
 /// The global list of eviction functions.
-static EVICTORS: RwLock<Vec<fn(usize)>> = RwLock::new(Vec::new());
+static EVICTORS: RwLock<Vec<Evictor>> = RwLock::new(Vec::new());
+
+/// Evicts a single cache and returns how many entries were removed.
+type Evictor = fn(Eviction) -> usize;
+
+/// How to evict a single cache.
+#[derive(Copy, Clone)]
+pub enum Eviction<'a> {
+    /// Remove the entries that the policy doesn't want to keep. If `forced`
+    /// is given, it overrides the cache's own policy. Without a `max_age`, the
+    /// cache's own or else the default maximum age is used.
+    Evict { max_age: Option<usize>, forced: Option<&'a dyn EvictionPolicy> },
+    /// Remove all entries and release their memory.
+    Clear,
+    /// Pass the metadata of each entry to the function without removing any.
+    Inspect(&'a dyn Fn(&EntryMeta)),
+}
+
+/// The number of shards an unbounded cache is split into.
+const SHARDS: usize = 16;
+
+/// Storage of a cache is released when less than this fraction of it is live.
+const COMPACT_RATIO: usize = 4;
+
+/// The maximum age for functions without their own one.
+static DEFAULT_MAX_AGE: AtomicUsize = AtomicUsize::new(usize::MAX);
+
+/// The initial capacity of each function's cache.
+static CACHE_CAPACITY: AtomicUsize = AtomicUsize::new(0);
+
+/// Whether a memoized function was already called.
+static STARTED: AtomicBool = AtomicBool::new(false);
+
+/// The global list of statistics reporting functions.
+static REPORTERS: RwLock<Vec<Reporter>> = RwLock::new(Vec::new());
+
+/// Reports the statistics of a single cache.
+type Reporter = fn() -> Option<CacheStats>;
+
+thread_local! {
+    /// Whether memoization is disabled on this thread.
+    static UNCACHED: Cell<bool> = const { Cell::new(false) };
+
+    /// How many in-flight computations this thread is currently executing.
+    static FLYING: Cell<usize> = const { Cell::new(0) };
+
+    /// The caches and keys of the calls with cycle detection that this thread
+    /// is currently executing.
+    static COMPUTING: RefCell<Vec<(usize, u128)>> = const { RefCell::new(Vec::new()) };
+}
+
+/// The global list of export functions.
+#[cfg(feature = "testing")]
+static EXPORTERS: RwLock<Vec<Exporter>> = RwLock::new(Vec::new());
+
+/// Exports the dependencies of a single cache.
+#[cfg(feature = "testing")]
+type Exporter = fn() -> Vec<EntryExport>;
 
 #[cfg(feature = "testing")]
 thread_local! {
     /// Whether the last call was a hit.
     static LAST_WAS_HIT: std::cell::Cell<bool> = const { std::cell::Cell::new(false) };
+
+    /// How many calls were hits since the last reset.
+    static HIT_COUNT: std::cell::Cell<usize> = const { std::cell::Cell::new(0) };
+
+    /// How many calls were misses since the last reset.
+    static MISS_COUNT: std::cell::Cell<usize> = const { std::cell::Cell::new(0) };
+}
+
+/// Ensure a type is suitable as shared output.
+#[inline]
+pub fn assert_send_sync<Out: SharedOutput + ?Sized>() {}
+
+/// An output that can be stored in a cache shared between threads.
+#[diagnostic::on_unimplemented(
+    message = "comemo: memoized output must be `Send + Sync`, but `{Self}` is not",
+    label = "stored in a cache that is shared between threads",
+    note = "use `#[memoize(assert_single_threaded)]` if the function is only \
+            called from a single thread"
+)]
+pub trait SharedOutput {}
+
+impl<T: Send + Sync + ?Sized> SharedOutput for T {}
+
+/// The name of the memoized function whose body is the closure `F`.
+fn function_name<F>() -> &'static str {
+    let name = std::any::type_name::<F>();
+    name.strip_suffix("::{{closure}}").unwrap_or(name)
+}
+
+/// The labels of the tracked parts of an input.
+#[cfg(feature = "tracing")]
+fn labels<In: Input>(input: &In) -> Vec<&'static str> {
+    let mut labels = vec![];
+    input.labels(&mut labels);
+    labels
 }
 
 /// Execute a function or use a cached result for it.
-pub fn memoized<'c, In, Out, F>(
-    mut input: In,
+pub fn memoized<'c, In, Out, H, F>(
+    input: In,
     constraint: &'c In::Constraint,
-    cache: &Cache<In::Constraint, Out>,
+    cache: &Cache<In::Constraint, Out, H>,
     enabled: bool,
+    refresh: bool,
     func: F,
 ) -> Out
 where
     In: Input + 'c,
     Out: Clone + 'static,
+    H: KeyHasher,
+    F: FnOnce(In::Tracked<'c>) -> Out,
+{
+    memoized_with_change(
+        input,
+        constraint,
+        cache,
+        enabled,
+        refresh,
+        None,
+        |out| Some(out),
+        Out::clone,
+        |out| out,
+        func,
+    )
+    .0
+}
+
+/// Execute a function or use a cached result for it and additionally return
+/// whether the output changed.
+///
+/// If `compare` is true, the output counts as changed if the function was
+/// executed and its output differs from the output of the entries that it
+/// replaces, which are the ones that were valid for the same input (or if
+/// there were no such entries). Otherwise, it always counts as changed if the
+/// function was executed.
+#[allow(clippy::too_many_arguments)]
+pub fn memoized_changed<'c, In, Out, H, F>(
+    input: In,
+    constraint: &'c In::Constraint,
+    cache: &Cache<In::Constraint, Out, H>,
+    enabled: bool,
+    refresh: bool,
+    compare: bool,
+    func: F,
+) -> (Out, bool)
+where
+    In: Input + 'c,
+    Out: PartialEq + Clone + 'static,
+    H: KeyHasher,
     F: FnOnce(In::Tracked<'c>) -> Out,
+{
+    memoized_with_change(
+        input,
+        constraint,
+        cache,
+        enabled,
+        refresh,
+        compare.then_some(Out::eq),
+        |out| Some(out),
+        Out::clone,
+        |out| out,
+        func,
+    )
+}
+
+/// Execute a fallible function or use a cached result for it.
+///
+/// Only successful outputs are cached. Errors are recomputed on each call.
+pub fn memoized_result<'c, In, Out, E, H, F>(
+    input: In,
+    constraint: &'c In::Constraint,
+    cache: &Cache<In::Constraint, Out, H>,
+    enabled: bool,
+    refresh: bool,
+    func: F,
+) -> Result<Out, E>
+where
+    In: Input + 'c,
+    Out: Clone + 'static,
+    H: KeyHasher,
+    F: FnOnce(In::Tracked<'c>) -> Result<Out, E>,
+{
+    memoized_with_change(
+        input,
+        constraint,
+        cache,
+        enabled,
+        refresh,
+        None,
+        ok,
+        |out| Ok(out.clone()),
+        |result| result,
+        func,
+    )
+    .0
+}
+
+/// Execute a function or use a cached result for it and return only one field
+/// of its output.
+///
+/// The full output is cached, but only the selected field is cloned.
+pub fn memoized_field<'c, In, Out, T, H, F>(
+    input: In,
+    constraint: &'c In::Constraint,
+    cache: &Cache<In::Constraint, Out, H>,
+    enabled: bool,
+    refresh: bool,
+    field: fn(&Out) -> &T,
+    func: F,
+) -> T
+where
+    In: Input + 'c,
+    Out: Clone + 'static,
+    H: KeyHasher,
+    T: Clone,
+    F: FnOnce(In::Tracked<'c>) -> Out,
+{
+    memoized_with_change(
+        input,
+        constraint,
+        cache,
+        enabled,
+        refresh,
+        None,
+        |out| Some(out),
+        |out| field(out).clone(),
+        |out| field(&out).clone(),
+        func,
+    )
+    .0
+}
+
+/// The output of a successful result.
+fn ok<Out, E>(result: &Result<Out, E>) -> Option<&Out> {
+    result.as_ref().ok()
+}
+
+/// Execute a function or use a cached result for it and, if there is an `eq`
+/// function, compare a freshly computed output with the entries it replaces.
+///
+/// The function's result `R` is cached if `cacheable` extracts an output from
+/// it. The value that is returned is produced from the cached output with `hit`
+/// and from a freshly computed result with `miss`.
+///
+/// If `refresh` is true, the function is executed even if there is a cached
+/// output and all entries that would have produced a hit are replaced.
+#[allow(clippy::too_many_arguments)]
+fn memoized_with_change<'c, In, Out, R, T, H, F>(
+    mut input: In,
+    constraint: &'c In::Constraint,
+    cache: &Cache<In::Constraint, Out, H>,
+    enabled: bool,
+    refresh: bool,
+    eq: Option<fn(&Out, &Out) -> bool>,
+    cacheable: fn(&R) -> Option<&Out>,
+    hit: impl FnOnce(&Out) -> T,
+    miss: impl FnOnce(R) -> T,
+    func: F,
+) -> (T, bool)
+where
+    In: Input + 'c,
+    Out: Clone + 'static,
+    H: KeyHasher,
+    F: FnOnce(In::Tracked<'c>) -> R,
 {
     // Early bypass if memoization is disabled.
     // Hopefully the compiler will optimize this away, if the condition is constant.
-    if !enabled {
-        return memoized_disabled(input, constraint, func);
+    if !enabled || cache.bypassed.load(Ordering::Relaxed) || UNCACHED.with(Cell::get) {
+        return (miss(memoized_disabled(input, constraint, func)), true);
     }
 
     // Compute the hash of the input's key part.
-    let key = {
-        let mut state = SipHasher13::new();
-        input.key(&mut state);
-        state.finish128().as_u128()
+    let key = key::<In, H>(&input);
+
+    // Remove all entries that would produce a hit if they should be refreshed.
+    let mut replaced = vec![];
+    if refresh {
+        replaced = cache.shard(key).write().invalidate::<In>(key, &input);
+    }
+
+    // Check if there is a cached output. If another thread is already
+    // computing an output for the same key, wait for it and check again. As
+    // its constraints may not be valid for our input, we might still miss.
+    let mut flight = None;
+    let _flight = loop {
+        let borrow = cache.shard(key).read();
+        if let Some((constrained, value)) = borrow.lookup::<In>(key, &input) {
+            // Release the borrow before replaying so that sinks and mutated
+            // values can call memoized functions without dead locking.
+            let (constrained, output) = (constrained.clone(), hit(value));
+            drop(borrow);
+
+            #[cfg(feature = "tracing")]
+            tracing::trace!(
+                function = function_name::<F>(),
+                hit = true,
+                labels = ?labels(&input),
+                "memoized"
+            );
+
+            // Replay the mutations.
+            input.replay(&constrained);
+
+            // Add the cached constraints to the outer ones.
+            input.retrack(constraint).1.join(&constrained);
+
+            cache.hits.fetch_add(1, Ordering::Relaxed);
+
+            #[cfg(feature = "metrics")]
+            cache.count::<F>(true);
+
+            #[cfg(feature = "testing")]
+            record_call(true);
+
+            return (output, false);
+        }
+
+        // Release the borrow so that nested memoized calls can access the
+        // cache without dead locking.
+        drop(borrow);
+
+        // Once we have registered our own computation, we checked the cache
+        // one last time and can compute the output.
+        if flight.is_some() {
+            break flight;
+        }
+
+        match cache.take_off(key) {
+            Takeoff::Leader(guard) => flight = Some(guard),
+            Takeoff::Waiter(pending) => pending.land(),
+            Takeoff::Solo => break None,
+        }
     };
 
-    // Check if there is a cached output.
-    let borrow = cache.0.read();
-    if let Some((constrained, value)) = borrow.lookup::<In>(key, &input) {
-        // Replay the mutations.
-        input.replay(constrained);
+    // Detect whether the function (transitively) calls itself with the same
+    // key, which would recurse forever.
+    let _cycle = cache.cycles.then(|| CycleGuard::enter::<F>(cache.id(), key));
+
+    cache.misses.fetch_add(1, Ordering::Relaxed);
+
+    #[cfg(feature = "metrics")]
+    cache.count::<F>(false);
+
+    // An output that is compared replaces the entries that are valid for the
+    // same input. Besides refreshed ones, these can only be entries that
+    // another thread inserted since the lookup.
+    if eq.is_some() {
+        replaced.extend(cache.shard(key).write().invalidate::<In>(key, &input));
+    }
+
+    // The execution shows up as a span in profiles.
+    #[cfg(feature = "tracing")]
+    let _span = tracing::trace_span!(
+        "memoized",
+        function = function_name::<F>(),
+        hit = false,
+        labels = ?labels(&input),
+    )
+    .entered();
 
-        // Add the cached constraints to the outer ones.
-        input.retrack(constraint).1.join(constrained);
+    // Execute the function with the new constraints hooked in.
+    let (input, outer) = input.retrack(constraint);
+    let (result, impure) = impurity::detect(std::any::type_name::<F>, || {
+        let result = constraint::recording(|| func(input));
 
-        #[cfg(feature = "testing")]
-        LAST_WAS_HIT.with(|cell| cell.set(true));
+        // Add the new constraints to the outer ones.
+        outer.join(constraint);
 
-        return value.clone();
+        result
+    });
+
+    #[cfg(feature = "testing")]
+    record_call(false);
+
+    // Don't trust the result if the function turned out to be impure.
+    if impure {
+        cache.bypass::<F>();
+        return (miss(result), true);
     }
 
-    // Release the borrow so that nested memoized calls can access the
-    // cache without dead locking.
-    drop(borrow);
+    // Only cache results that should be cached.
+    let Some(output) = cacheable(&result) else {
+        return (miss(result), true);
+    };
 
+    // Insert the output into the cache.
+    let changed = match eq {
+        Some(eq) => !replaced.last().is_some_and(|prev| eq(prev, output)),
+        None => true,
+    };
+    cache
+        .shard(key)
+        .write()
+        .insert::<In>(key, constraint.take(), output.clone());
+
+    (miss(result), changed)
+}
+
+fn memoized_disabled<'c, In, R, F>(
+    input: In,
+    constraint: &'c In::Constraint,
+    func: F,
+) -> R
+where
+    In: Input + 'c,
+    F: FnOnce(In::Tracked<'c>) -> R,
+{
     // Execute the function with the new constraints hooked in.
     let (input, outer) = input.retrack(constraint);
-    let output = func(input);
+    let output = constraint::recording(|| func(input));
 
     // Add the new constraints to the outer ones.
     outer.join(constraint);
 
-    // Insert the result into the cache.
-    let mut borrow = cache.0.write();
-    borrow.insert::<In>(key, constraint.take(), output.clone());
-
+    // Ensure that the last call was a miss during testing.
     #[cfg(feature = "testing")]
-    LAST_WAS_HIT.with(|cell| cell.set(false));
+    record_call(false);
 
     output
 }
 
-fn memoized_disabled<'c, In, Out, F>(
+/// Insert an output into the cache without executing the function.
+///
+/// The entry has no constraints, so it is only valid for inputs without
+/// tracked parts. Entries that were valid for the input are replaced.
+pub fn prime<In, Out, H>(input: In, cache: &Cache<In::Constraint, Out, H>, output: Out)
+where
+    In: Input,
+    Out: 'static,
+    H: KeyHasher,
+{
+    let key = key::<In, H>(&input);
+    let mut data = cache.shard(key).write();
+    data.invalidate::<In>(key, &input);
+    data.insert::<In>(key, Default::default(), output);
+}
+
+/// Return the cached output for an input without executing the function.
+///
+/// Neither inserts an entry nor marks a matching one as used. Mutations
+/// recorded for tracked arguments are not replayed.
+pub fn peek<In, Out, H>(
     input: In,
+    cache: &Cache<In::Constraint, Out, H>,
+    enabled: bool,
+) -> Option<Out>
+where
+    In: Input,
+    Out: Clone + 'static,
+    H: KeyHasher,
+{
+    if !enabled || cache.bypassed.load(Ordering::Relaxed) || UNCACHED.with(Cell::get) {
+        return None;
+    }
+
+    let key = key::<In, H>(&input);
+    cache.shard(key).read().peek::<In>(key, &input).cloned()
+}
+
+/// How a thread that missed the cache proceeds.
+enum Takeoff<'a> {
+    /// It computes the output and other threads wait for it.
+    Leader(FlightGuard<'a>),
+    /// It waits for another thread to compute the output.
+    Waiter(Arc<Flight>),
+    /// It computes the output on its own.
+    Solo,
+}
+
+/// A computation that is in flight.
+#[derive(Default)]
+struct Flight {
+    /// Whether the computation has finished.
+    landed: Mutex<bool>,
+    /// Notifies waiters when the computation has finished.
+    cond: Condvar,
+}
+
+impl Flight {
+    /// Block until the computation has finished.
+    fn land(&self) {
+        let mut landed = self.landed.lock();
+        while !*landed {
+            self.cond.wait(&mut landed);
+        }
+    }
+}
+
+/// Unregisters an in-flight computation and wakes up its waiters when
+/// dropped, even if the computation panicked.
+struct FlightGuard<'a> {
+    pending: &'a Mutex<HashMap<u128, Arc<Flight>>>,
+    key: u128,
+    flight: Arc<Flight>,
+}
+
+impl Drop for FlightGuard<'_> {
+    fn drop(&mut self) {
+        self.pending.lock().remove(&self.key);
+        FLYING.with(|cell| cell.set(cell.get() - 1));
+        *self.flight.landed.lock() = true;
+        self.flight.cond.notify_all();
+    }
+}
+
+/// Marks a call with cycle detection as being executed by the current thread
+/// until dropped.
+struct CycleGuard;
+
+impl CycleGuard {
+    /// Enter a call to the function `F`, panicking if the same call is already
+    /// being executed.
+    #[track_caller]
+    fn enter<F>(id: usize, key: u128) -> Self {
+        COMPUTING.with(|stack| {
+            let mut stack = stack.borrow_mut();
+            if stack.contains(&(id, key)) {
+                drop(stack);
+                let name = function_name::<F>();
+                panic!(
+                    "comemo: detected a cycle, `{name}` was called with the \
+                     same arguments while it was already executing"
+                );
+            }
+            stack.push((id, key));
+        });
+        Self
+    }
+}
+
+impl Drop for CycleGuard {
+    fn drop(&mut self) {
+        COMPUTING.with(|stack| stack.borrow_mut().pop());
+    }
+}
+
+/// Execute an asynchronous function or use a cached result for it.
+///
+/// Only the resolved output is cached. On a miss, the future is awaited
+/// without holding any locks, so concurrent calls with the same key may all
+/// execute the function. Impurity detection doesn't apply because the future
+/// may move between threads.
+pub async fn memoized_async<'c, In, Out, H, F, Fut>(
+    mut input: In,
     constraint: &'c In::Constraint,
+    cache: &Cache<In::Constraint, Out, H>,
+    enabled: bool,
+    refresh: bool,
     func: F,
 ) -> Out
 where
     In: Input + 'c,
     Out: Clone + 'static,
-    F: FnOnce(In::Tracked<'c>) -> Out,
+    H: KeyHasher,
+    F: FnOnce(In::Tracked<'c>) -> Fut,
+    Fut: Future<Output = Out>,
 {
+    // Early bypass if memoization is disabled.
+    if !enabled || cache.bypassed.load(Ordering::Relaxed) || UNCACHED.with(Cell::get) {
+        let (input, outer) = input.retrack(constraint);
+        let output = func(input).await;
+        outer.join(constraint);
+        return output;
+    }
+
+    // Compute the hash of the input's key part.
+    let key = key::<In, H>(&input);
+
+    // Remove all entries that would produce a hit if they should be refreshed.
+    if refresh {
+        cache.shard(key).write().invalidate::<In>(key, &input);
+    }
+
+    // Check if there is a cached output. The lock must be released before
+    // awaiting the function.
+    {
+        let borrow = cache.shard(key).read();
+        if let Some((constrained, value)) = borrow.lookup::<In>(key, &input) {
+            let (constrained, output) = (constrained.clone(), value.clone());
+            drop(borrow);
+
+            #[cfg(feature = "tracing")]
+            tracing::trace!(
+                function = function_name::<F>(),
+                hit = true,
+                labels = ?labels(&input),
+                "memoized"
+            );
+
+            input.replay(&constrained);
+            input.retrack(constraint).1.join(&constrained);
+            cache.hits.fetch_add(1, Ordering::Relaxed);
+
+            #[cfg(feature = "metrics")]
+            cache.count::<F>(true);
+
+            #[cfg(feature = "testing")]
+            record_call(true);
+
+            return output;
+        }
+    }
+
+    cache.misses.fetch_add(1, Ordering::Relaxed);
+
+    #[cfg(feature = "metrics")]
+    cache.count::<F>(false);
+
+    #[cfg(feature = "tracing")]
+    let span = tracing::trace_span!(
+        "memoized",
+        function = function_name::<F>(),
+        hit = false,
+        labels = ?labels(&input),
+    );
+
     // Execute the function with the new constraints hooked in.
     let (input, outer) = input.retrack(constraint);
-    let output = func(input);
-
-    // Add the new constraints to the outer ones.
+    let future = func(input);
+    #[cfg(feature = "tracing")]
+    let future = tracing::Instrument::instrument(future, span);
+    let output = future.await;
     outer.join(constraint);
 
-    // Ensure that the last call was a miss during testing.
     #[cfg(feature = "testing")]
-    LAST_WAS_HIT.with(|cell| cell.set(false));
+    record_call(false);
 
+    // Insert the output into the cache.
+    cache
+        .shard(key)
+        .write()
+        .insert::<In>(key, constraint.take(), output.clone());
     output
 }
 
+/// Compute the hash of an input's key part.
+pub fn key<In: Input, H: KeyHasher>(input: &In) -> u128 {
+    let mut state = H::default();
+    input.key(&mut state);
+    state.finish128()
+}
+
+/// Run a closure with memoization disabled.
+///
+/// All memoized calls made by the closure on the current thread are executed
+/// without consulting or filling their caches, as if their `enabled` condition
+/// were `false`. Other threads and calls outside of the closure keep caching.
+/// This is useful to recompute an operation from scratch, for example to check
+/// that its memoized result is correct.
+pub fn without_cache<T>(f: impl FnOnce() -> T) -> T {
+    let _guard = UncachedGuard::enter();
+    f()
+}
+
+/// Disables memoization on the current thread until dropped, even if the
+/// closure panics.
+struct UncachedGuard {
+    prev: bool,
+}
+
+impl UncachedGuard {
+    /// Disable memoization on the current thread.
+    fn enter() -> Self {
+        Self { prev: UNCACHED.with(|cell| cell.replace(true)) }
+    }
+}
+
+impl Drop for UncachedGuard {
+    fn drop(&mut self) {
+        UNCACHED.with(|cell| cell.set(self.prev));
+    }
+}
+
+/// Populate caches by executing a batch of memoized calls on all available
+/// threads.
+///
+/// This is useful to move the cost of cold caches to the startup of a
+/// latency-sensitive service. The calls may overlap: If multiple threads make
+/// the same memoized call at the same time, one of them executes the function
+/// and the others wait for its result.
+///
+/// ```
+/// #[comemo::memoize]
+/// fn square(x: u64) -> u64 {
+///     x * x
+/// }
+///
+/// comemo::warm((0..100).map(|x| move || {
+///     square(x);
+/// }));
+/// ```
+pub fn warm<F>(calls: impl IntoIterator<Item = F>)
+where
+    F: FnOnce() + Send,
+{
+    let calls: Vec<F> = calls.into_iter().collect();
+    let threads = std::thread::available_parallelism().map_or(1, |n| n.get());
+    let queue = Mutex::new(calls.into_iter());
+    std::thread::scope(|scope| {
+        for _ in 0..threads.min(queue.lock().len()) {
+            scope.spawn(|| loop {
+                // Release the lock before the call so that others can proceed.
+                let Some(call) = queue.lock().next() else { break };
+                call();
+            });
+        }
+    });
+}
+
 /// Evict the global cache.
 ///
 /// This removes all memoized results from the cache whose age is larger than or
 /// equal to `max_age`. The age of a result grows by one during each eviction
 /// and is reset to zero when the result produces a cache hit. Set `max_age` to
 /// zero to completely clear the cache.
-pub fn evict(max_age: usize) {
-    for subevict in EVICTORS.read().iter() {
-        subevict(max_age);
+///
+/// Which results are removed can be customized with an
+/// [`EvictionPolicy`](crate::EvictionPolicy). The description above applies to
+/// the default [`AgePolicy`](crate::AgePolicy).
+///
+/// Returns how many results were removed across all memoized functions.
+pub fn evict(max_age: usize) -> usize {
+    evict_all(Eviction::Evict { max_age: Some(max_age), forced: None })
+}
+
+/// Evict the global cache with each function's own maximum age.
+///
+/// Functions with a `#[memoize(max_age = <expr>)]` attribute use that age, all
+/// others use the one set with [`set_default_max_age`]. This way, different
+/// subsystems can retain their results for a different number of evictions.
+/// Otherwise, this works just like [`evict`].
+///
+/// Returns how many results were removed across all memoized functions.
+pub fn evict_configured() -> usize {
+    evict_all(Eviction::Evict { max_age: None, forced: None })
+}
+
+/// Set the maximum age that [`evict_configured`] uses for functions without
+/// their own one.
+///
+/// Defaults to `usize::MAX`, so that the results of such functions aren't
+/// evicted by age.
+pub fn set_default_max_age(max_age: usize) {
+    DEFAULT_MAX_AGE.store(max_age, Ordering::Relaxed);
+}
+
+/// Evict all memoized results that weren't used within the given duration.
+///
+/// This applies an [`LruPolicy`](crate::LruPolicy) to all memoized functions,
+/// regardless of their configured eviction policies. It counts as an eviction
+/// for the purpose of age-based policies.
+///
+/// Returns how many results were removed across all memoized functions.
+pub fn evict_older_than(max_idle: Duration) -> usize {
+    evict_all(Eviction::Evict {
+        max_age: Some(0),
+        forced: Some(&LruPolicy(max_idle)),
+    })
+}
+
+/// Evict all but the `budget` most recently used memoized results across all
+/// memoized functions.
+///
+/// Unlike the other evictions, which treat each function on its own, this
+/// keeps a global budget of results, which is useful in memory-capped
+/// environments. Results that were last used at the same instant as the
+/// least recent retained one are retained, too. If the budget isn't
+/// exceeded, nothing is evicted. Otherwise, this counts as an eviction for
+/// the purpose of age-based policies.
+///
+/// Returns how many results were removed across all memoized functions.
+pub fn evict_to(budget: usize) -> usize {
+    let accesses = RefCell::new(vec![]);
+    let inspect = |meta: &EntryMeta| accesses.borrow_mut().push(meta.last_access);
+    for evictor in EVICTORS.read().iter() {
+        evictor(Eviction::Inspect(&inspect));
+    }
+
+    let mut accesses = accesses.into_inner();
+    if accesses.len() <= budget {
+        return 0;
+    } else if budget == 0 {
+        return evict_all(Eviction::Clear);
     }
 
+    // Find the least recent access among the `budget` most recent ones.
+    let (_, &mut cutoff, _) =
+        accesses.select_nth_unstable_by(budget - 1, |a, b| b.cmp(a));
+    evict_all(Eviction::Evict {
+        max_age: Some(0),
+        forced: Some(&AccessedSince(cutoff)),
+    })
+}
+
+/// Keeps entries that were used at or after the given instant.
+struct AccessedSince(Instant);
+
+impl EvictionPolicy for AccessedSince {
+    fn should_keep(&self, meta: &EntryMeta, _: usize) -> bool {
+        meta.last_access >= self.0
+    }
+}
+
+/// Remove all memoized results and release the memory of all caches and
+/// accelerators.
+///
+/// Unlike [`evict`], this doesn't depend on any eviction policy and also
+/// releases the memory that caches keep allocated for reuse. It can be called
+/// concurrently with memoized calls.
+///
+/// Returns how many results were removed across all memoized functions.
+pub fn clear() -> usize {
+    let removed = EVICTORS.read().iter().map(|subevict| subevict(Eviction::Clear)).sum();
+    accelerate::clear_accelerators();
+    removed
+}
+
+/// Evict all caches.
+fn evict_all(eviction: Eviction) -> usize {
+    let removed = EVICTORS.read().iter().map(|subevict| subevict(eviction)).sum();
     accelerate::evict();
+    removed
+}
+
+/// A handle to the cache of a single memoized function.
+///
+/// It is returned by the `<name>_cache()` function that
+/// `#[memoize(handle)]` generates.
+#[derive(Copy, Clone)]
+pub struct CacheHandle(Evictor);
+
+impl CacheHandle {
+    /// Create a handle from the cache's evictor.
+    #[doc(hidden)]
+    pub const fn new(evictor: Evictor) -> Self {
+        Self(evictor)
+    }
+
+    /// Evict the function's cache like [`evict`] does, but without touching
+    /// the caches of other functions.
+    ///
+    /// Returns how many results were removed.
+    pub fn evict(&self, max_age: usize) -> usize {
+        (self.0)(Eviction::Evict { max_age: Some(max_age), forced: None })
+    }
+
+    /// Remove all of the function's results and release their memory.
+    ///
+    /// Returns how many results were removed.
+    pub fn clear(&self) -> usize {
+        (self.0)(Eviction::Clear)
+    }
+}
+
+/// Process-wide configuration, applied with [`init`].
+///
+/// The default configuration is used if `init` isn't called. Settings that are
+/// `None` are left unchanged by `init`, so that they keep their defaults or the
+/// values set with the individual setters.
+#[derive(Copy, Clone)]
+pub struct Config {
+    /// How many memoized functions to reserve space for.
+    pub caches_capacity: usize,
+    /// How many distinct hashed arguments to reserve space for in the cache of
+    /// each memoized function.
+    pub default_cache_capacity: usize,
+    /// The maximum number of accelerators between evictions. See
+    /// [`set_accelerator_limit`](crate::set_accelerator_limit).
+    pub accelerator_limit: Option<usize>,
+    /// The maximum total number of entries across all accelerators. See
+    /// [`set_accelerator_capacity`](crate::set_accelerator_capacity).
+    pub accelerator_capacity: Option<usize>,
+    /// Whether constraint validation is accelerated. See
+    /// [`set_acceleration`](crate::set_acceleration).
+    pub acceleration: bool,
+    /// How to react to impure tracked functions. See
+    /// [`set_impurity_policy`](crate::set_impurity_policy).
+    pub impurity_policy: ImpurityPolicy,
+    /// Whether impure tracked functions are detected in release builds, too.
+    /// See [`set_strict`](crate::set_strict).
+    pub strict: bool,
+    /// The eviction policy for functions without a function-specific one. See
+    /// [`set_eviction_policy`](crate::set_eviction_policy).
+    pub eviction_policy: Option<&'static dyn EvictionPolicy>,
+    /// The maximum age for functions without their own one. See
+    /// [`set_default_max_age`].
+    pub default_max_age: Option<usize>,
+}
+
+impl Default for Config {
+    fn default() -> Self {
+        Self {
+            caches_capacity: 0,
+            default_cache_capacity: 0,
+            accelerator_limit: None,
+            accelerator_capacity: None,
+            acceleration: true,
+            impurity_policy: ImpurityPolicy::default(),
+            strict: false,
+            eviction_policy: None,
+            default_max_age: None,
+        }
+    }
+}
+
+/// Configure comemo for the whole process.
+///
+/// This pre-sizes comemo's global data structures, which avoids rehashing at
+/// startup when the number of memoized functions and cached results is large
+/// and predictable, and sets all policies at once.
+///
+/// ```
+/// comemo::init(comemo::Config {
+///     default_cache_capacity: 1024,
+///     accelerator_limit: Some(1 << 16),
+///     eviction_policy: Some(&comemo::LfuPolicy { min_hits: 3 }),
+///     ..Default::default()
+/// });
+/// ```
+///
+/// # Panics
+/// Panics if a memoized function was already called because caches are set up
+/// on first use.
+pub fn init(config: Config) {
+    // Holding the lock throughout keeps memoized functions from setting up
+    // their caches while the configuration is only partially applied.
+    let mut evictors = EVICTORS.write();
+    if STARTED.load(Ordering::SeqCst) {
+        panic!("comemo: `init` must be called before the first memoized call");
+    }
+
+    CACHE_CAPACITY.store(config.default_cache_capacity, Ordering::Relaxed);
+    evictors.reserve(config.caches_capacity);
+    REPORTERS.write().reserve(config.caches_capacity);
+    if let Some(limit) = config.accelerator_limit {
+        accelerate::set_accelerator_limit(limit);
+    }
+    if let Some(entries) = config.accelerator_capacity {
+        accelerate::set_accelerator_capacity(entries);
+    }
+    accelerate::set_acceleration(config.acceleration);
+    impurity::set_impurity_policy(config.impurity_policy);
+    impurity::set_strict(config.strict);
+    if let Some(policy) = config.eviction_policy {
+        eviction::set_eviction_policy(policy);
+    }
+    if let Some(max_age) = config.default_max_age {
+        set_default_max_age(max_age);
+    }
 }
 
 /// Register an eviction function in the global list.
-pub fn register_evictor(evict: fn(usize)) {
-    EVICTORS.write().push(evict);
+pub fn register_evictor(evict: Evictor) {
+    let mut evictors = EVICTORS.write();
+    STARTED.store(true, Ordering::SeqCst);
+    evictors.push(evict);
+}
+
+/// Register a statistics reporting function in the global list.
+pub fn register_reporter(report: Reporter) {
+    REPORTERS.write().push(report);
+}
+
+/// Usage statistics of a memoized function's cache.
+#[derive(Debug, Clone, Eq, PartialEq)]
+pub struct CacheStats {
+    /// The name of the memoized function.
+    pub function: &'static str,
+    /// How many calls were answered from the cache.
+    pub hits: u64,
+    /// How many calls executed the function.
+    pub misses: u64,
+    /// How many results are currently cached.
+    pub entries: usize,
+    /// How many of the currently cached results produced at least one hit.
+    pub reused: usize,
+}
+
+/// Collect usage statistics for all memoized functions that were called at
+/// least once.
+///
+/// Calls to functions whose memoization was disabled, for example through the
+/// `enabled` attribute, are neither counted as hits nor as misses. Caches of
+/// functions with `assert_single_threaded` are only reported on their own
+/// thread.
+pub fn cache_stats() -> Vec<CacheStats> {
+    REPORTERS.read().iter().filter_map(|report| report()).collect()
+}
+
+/// The cache usage of a memoized function whose results are never reused.
+#[derive(Debug, Clone, Eq, PartialEq)]
+pub struct Overhead {
+    /// The name of the memoized function.
+    pub function: &'static str,
+    /// How many results are cached for the function.
+    pub entries: usize,
+}
+
+/// Find memoized functions whose cached results never produced a hit.
+///
+/// Such functions are likely called with unique arguments each time, so
+/// memoizing them only costs time and memory. A function is reported if it
+/// has at least `min_entries` cached results and none of them was ever hit.
+///
+/// Only results that are currently cached are considered, so the observed
+/// window is determined by eviction: With the default policy and regular calls
+/// to [`evict`] with a `max_age` of `n`, it spans the last `n` eviction rounds.
+pub fn overhead_report(min_entries: usize) -> Vec<Overhead> {
+    REPORTERS
+        .read()
+        .iter()
+        .filter_map(|report| report())
+        .filter(|stats| stats.reused == 0 && stats.entries >= min_entries.max(1))
+        .map(|stats| Overhead { function: stats.function, entries: stats.entries })
+        .collect()
+}
+
+/// Record whether a call on this thread was a hit.
+#[cfg(feature = "testing")]
+fn record_call(hit: bool) {
+    LAST_WAS_HIT.with(|cell| cell.set(hit));
+    let count = if hit { &HIT_COUNT } else { &MISS_COUNT };
+    count.with(|cell| cell.set(cell.get() + 1));
 }
 
 /// Whether the last call was a hit.
@@ -129,42 +1054,447 @@ pub fn last_was_hit() -> bool {
     LAST_WAS_HIT.with(|cell| cell.get())
 }
 
+/// How many memoized calls on this thread were cache hits since the last
+/// [`reset_counts`].
+///
+/// Together with [`miss_count`], this allows asserting how a whole block of
+/// calls was answered, e.g. that exactly three of them executed.
+#[cfg(feature = "testing")]
+pub fn hit_count() -> usize {
+    HIT_COUNT.with(|cell| cell.get())
+}
+
+/// How many memoized calls on this thread executed their function since the
+/// last [`reset_counts`].
+///
+/// Calls with memoization disabled count as misses.
+#[cfg(feature = "testing")]
+pub fn miss_count() -> usize {
+    MISS_COUNT.with(|cell| cell.get())
+}
+
+/// Reset the hit and miss counts of this thread to zero.
+#[cfg(feature = "testing")]
+pub fn reset_counts() {
+    HIT_COUNT.with(|cell| cell.set(0));
+    MISS_COUNT.with(|cell| cell.set(0));
+}
+
+/// The dependencies of a cached result, as produced by [`export`].
+#[cfg(feature = "testing")]
+#[derive(Debug, Clone, Eq, PartialEq)]
+pub struct EntryExport {
+    /// The name of the memoized function.
+    pub function: &'static str,
+    /// The hash of the hashed arguments.
+    pub key: u128,
+    /// The tracked calls the result depends on and their return hashes.
+    pub calls: Vec<(CallLabel, u128)>,
+}
+
+/// Enumerate the dependencies of all cached results.
+///
+/// This can be used to visualize the dependency structure of memoized calls,
+/// e.g. to find out why invalidations cascade the way they do.
+#[cfg(feature = "testing")]
+pub fn export() -> Vec<EntryExport> {
+    EXPORTERS.read().iter().flat_map(|export| export()).collect()
+}
+
+/// Register an export function in the global list.
+#[cfg(feature = "testing")]
+pub fn register_exporter(export: Exporter) {
+    EXPORTERS.write().push(export);
+}
+
 /// A cache for a single memoized function.
-pub struct Cache<C, Out>(Lazy<RwLock<CacheData<C, Out>>>);
+///
+/// Its keys are computed with the hasher `H`.
+pub struct Cache<C, Out, H = SipHasher13> {
+    /// Creates the cached data on first access.
+    init: fn() -> RwLock<CacheData<C, Out>>,
+    /// The cached data, split into shards by key so that insertions for
+    /// different keys don't contend for the same lock.
+    shards: OnceCell<Box<[Shard<C, Out>]>>,
+    /// Whether the cache is permanently bypassed because the function turned
+    /// out to be impure.
+    bypassed: AtomicBool,
+    /// How many calls were answered from the cache.
+    hits: AtomicU64,
+    /// How many calls executed the function.
+    misses: AtomicU64,
+    /// The keys of the hit and miss counters reported to the `metrics` crate.
+    #[cfg(feature = "metrics")]
+    counters: OnceCell<[metrics::Key; 2]>,
+    /// Computations that are currently in flight, by key.
+    pending: Lazy<Mutex<HashMap<u128, Arc<Flight>>>>,
+    /// Whether recursive calls with the same key are detected.
+    cycles: bool,
+    /// The hasher for keys.
+    hasher: PhantomData<fn() -> H>,
+}
 
-impl<C: 'static, Out: 'static> Cache<C, Out> {
+impl<C: 'static, Out: 'static, H> Cache<C, Out, H> {
     /// Create an empty cache.
     ///
     /// It must take an initialization function because the `evict` fn
     /// pointer cannot be passed as an argument otherwise the function
-    /// passed to `Lazy::new` is a closure and not a function pointer.
+    /// that creates the data is a closure and not a function pointer.
     pub const fn new(init: fn() -> RwLock<CacheData<C, Out>>) -> Self {
-        Self(Lazy::new(init))
+        Self {
+            init,
+            shards: OnceCell::new(),
+            bypassed: AtomicBool::new(false),
+            hits: AtomicU64::new(0),
+            misses: AtomicU64::new(0),
+            #[cfg(feature = "metrics")]
+            counters: OnceCell::new(),
+            pending: Lazy::new(Default::default),
+            cycles: false,
+            hasher: PhantomData,
+        }
     }
 
-    /// Evict all entries whose age is larger than or equal to `max_age`.
-    pub fn evict(&self, max_age: usize) {
-        self.0.write().evict(max_age)
+    /// Panic when the function calls itself with the same key while it is
+    /// already executing.
+    pub const fn with_cycle_detection(mut self) -> Self {
+        self.cycles = true;
+        self
     }
+
+    /// Count a hit or miss of the function `F` for the `metrics` crate.
+    ///
+    /// The keys are built once, so that counting doesn't allocate.
+    #[cfg(feature = "metrics")]
+    fn count<F>(&self, hit: bool) {
+        static METADATA: metrics::Metadata = metrics::Metadata::new(
+            module_path!(),
+            metrics::Level::INFO,
+            Some(module_path!()),
+        );
+
+        let counters = self.counters.get_or_init(|| {
+            let labels = || vec![metrics::Label::new("function", function_name::<F>())];
+            [
+                metrics::Key::from_parts("comemo.hits", labels()),
+                metrics::Key::from_parts("comemo.misses", labels()),
+            ]
+        });
+
+        let key = &counters[usize::from(!hit)];
+        metrics::with_recorder(|recorder| {
+            recorder.register_counter(key, &METADATA).increment(1)
+        });
+    }
+
+    /// All shards of the cached data.
+    fn shards(&self) -> &[Shard<C, Out>] {
+        self.shards.get_or_init(|| (self.init)().into_inner().split())
+    }
+
+    /// The shard responsible for the given key.
+    fn shard(&self, key: u128) -> &Shard<C, Out> {
+        let shards = self.shards();
+        &shards[key as usize % shards.len()]
+    }
+
+    /// A unique identifier for this cache.
+    fn id(&self) -> usize {
+        self as *const Self as usize
+    }
+
+    /// Evict entries and return how many were removed.
+    ///
+    /// Unless overridden by the eviction, entries are evicted according to the
+    /// given function-specific or else the global eviction policy. The same
+    /// goes for the maximum age.
+    pub fn evict(
+        &self,
+        eviction: Eviction,
+        policy: Option<&dyn EvictionPolicy>,
+        max_age: Option<usize>,
+    ) -> usize {
+        let (requested, forced) = match eviction {
+            Eviction::Evict { max_age, forced } => (max_age, forced),
+            Eviction::Clear => return self.clear(),
+            Eviction::Inspect(f) => {
+                for shard in self.shards() {
+                    shard.read().entries.values().flatten().for_each(|e| f(&e.meta()));
+                }
+                return 0;
+            }
+        };
+
+        let max_age = requested
+            .or(max_age)
+            .unwrap_or_else(|| DEFAULT_MAX_AGE.load(Ordering::Relaxed));
+
+        match (forced, policy) {
+            (Some(policy), _) | (None, Some(policy)) => self.evict_with(max_age, policy),
+            (None, None) => {
+                eviction::with_policy(|policy| self.evict_with(max_age, policy))
+            }
+        }
+    }
+
+    /// Evict entries according to the given eviction policy and return how
+    /// many were removed.
+    fn evict_with(&self, max_age: usize, policy: &dyn EvictionPolicy) -> usize {
+        self.shards()
+            .iter()
+            .map(|shard| shard.write().evict(max_age, policy))
+            .sum()
+    }
+
+    /// Remove all entries, release their memory and return how many there
+    /// were.
+    fn clear(&self) -> usize {
+        self.shards().iter().map(|shard| shard.write().clear()).sum()
+    }
+
+    /// Export the dependencies of all entries.
+    #[cfg(feature = "testing")]
+    pub fn export(&self, function: &'static str) -> Vec<EntryExport>
+    where
+        C: Join,
+    {
+        let mut exports = vec![];
+        for shard in self.shards() {
+            for (&key, entries) in &shard.read().entries {
+                for entry in entries {
+                    let mut calls = vec![];
+                    entry.constraint.export(&mut calls);
+                    exports.push(EntryExport { function, key, calls });
+                }
+            }
+        }
+        exports
+    }
+
+    /// Collect the usage statistics of the cache.
+    pub fn stats(&self, function: &'static str) -> CacheStats {
+        let mut entries = 0;
+        let mut reused = 0;
+        for shard in self.shards() {
+            for entry in shard.read().entries.values().flatten() {
+                if entry.hits.load(Ordering::Relaxed) > 0 {
+                    reused += 1;
+                }
+                entries += 1;
+            }
+        }
+        CacheStats {
+            function,
+            hits: self.hits.load(Ordering::Relaxed),
+            misses: self.misses.load(Ordering::Relaxed),
+            entries,
+            reused,
+        }
+    }
+
+    /// Register a computation for the given key.
+    ///
+    /// A thread that is itself computing an output never waits for another
+    /// one. Otherwise, two threads could wait for each other.
+    fn take_off(&self, key: u128) -> Takeoff<'_> {
+        let mut pending = self.pending.lock();
+        if let Some(flight) = pending.get(&key) {
+            return if FLYING.with(Cell::get) == 0 {
+                Takeoff::Waiter(flight.clone())
+            } else {
+                Takeoff::Solo
+            };
+        }
+
+        let flight = Arc::new(Flight::default());
+        pending.insert(key, flight.clone());
+        FLYING.with(|cell| cell.set(cell.get() + 1));
+        Takeoff::Leader(FlightGuard { pending: &self.pending, key, flight })
+    }
+
+    /// Permanently bypass the cache for the function `F`.
+    #[cold]
+    fn bypass<F>(&self) {
+        if !self.bypassed.swap(true, Ordering::SeqCst) {
+            let function = function_name::<F>();
+            impurity::report_bypass(function);
+
+            #[cfg(feature = "tracing")]
+            tracing::warn!(
+                function,
+                "comemo: detected an impure tracked function, memoization is \
+                 disabled for it from now on"
+            );
+        }
+        for shard in self.shards() {
+            shard.write().discard();
+        }
+    }
+}
+
+/// Wraps a value that is not thread-safe and binds it to the first thread that
+/// accesses it.
+///
+/// Used for the caches of memoized functions with
+/// `#[memoize(assert_single_threaded)]`.
+pub struct SingleThreaded<T> {
+    /// The thread that first accessed the value through [`get`](Self::get).
+    owner: OnceCell<ThreadId>,
+    /// The wrapped value.
+    value: T,
 }
 
+// Safety: The value is only ever accessed from its owning thread.
+unsafe impl<T> Sync for SingleThreaded<T> {}
+
+impl<T> SingleThreaded<T> {
+    /// Wrap a value.
+    ///
+    /// # Safety
+    /// The wrapper is `Sync` regardless of `T` and only checks the thread
+    /// when the value is accessed, not when it is dropped. It must only be
+    /// used in a `static`, which is never dropped, like the ones generated by
+    /// `#[memoize(assert_single_threaded)]`.
+    pub const unsafe fn new(value: T) -> Self {
+        Self { owner: OnceCell::new(), value }
+    }
+
+    /// Access the value, making the current thread its owner if it has none
+    /// yet and panicking if called from a thread other than the owner.
+    #[track_caller]
+    pub fn get(&self) -> &T {
+        let current = std::thread::current().id();
+        if *self.owner.get_or_init(|| current) != current {
+            panic!(
+                "comemo: memoized function with `assert_single_threaded` \
+                 was called from multiple threads"
+            );
+        }
+        &self.value
+    }
+
+    /// Access the value if called from its owning thread. Doesn't claim
+    /// ownership, so that inspecting or evicting the cache from another thread
+    /// before the first call doesn't lock out the thread that makes it.
+    pub fn try_get(&self) -> Option<&T> {
+        let current = std::thread::current().id();
+        (self.owner.get() == Some(&current)).then_some(&self.value)
+    }
+}
+
+/// A part of a cache's data, selected by key.
+type Shard<C, Out> = RwLock<CacheData<C, Out>>;
+
 /// The internal data for a cache.
 pub struct CacheData<C, Out> {
     /// Maps from hashes to memoized results.
     entries: HashMap<u128, Vec<CacheEntry<C, Out>>>,
+    /// The total number of entries.
+    len: usize,
+    /// The maximum number of entries. Zero means unbounded.
+    limit: usize,
+    /// For bounded data, the key of each entry by when it was last used, as
+    /// far as known. Hits only update the entry itself, so an entry's
+    /// position is corrected once it reaches the front. Entries that were
+    /// removed in other ways are skipped there, too.
+    recency: BTreeMap<(u64, u64), u128>,
+    /// The ID of the next inserted entry.
+    next_id: u64,
+    /// How many entries were reserved initially.
+    reserved: usize,
+    /// Called with the output of each entry before it is evicted.
+    on_evict: Option<fn(&Out)>,
+}
+
+impl<C, Out> CacheData<C, Out> {
+    /// Create empty cache data that holds at most `limit` entries. Once the
+    /// limit is reached, the least recently used entry is replaced on insert.
+    /// A limit of zero means unbounded.
+    pub fn bounded(limit: usize) -> Self {
+        Self { limit, ..Self::default() }
+    }
+
+    /// Call `callback` with the output of each entry before it is evicted or
+    /// replaced because the limit was reached.
+    pub fn on_evict(mut self, callback: fn(&Out)) -> Self {
+        self.on_evict = Some(callback);
+        self
+    }
+
+    /// Split the data into shards that are selected by key.
+    ///
+    /// Bounded data isn't split because the least recently used entry must
+    /// be found among all entries.
+    fn split(self) -> Box<[Shard<C, Out>]> {
+        if self.limit > 0 {
+            return Box::new([RwLock::new(self)]);
+        }
+
+        let reserved = self.reserved.div_ceil(SHARDS);
+        (0..SHARDS)
+            .map(|_| {
+                RwLock::new(Self {
+                    entries: HashMap::with_capacity(reserved),
+                    reserved,
+                    on_evict: self.on_evict,
+                    ..Self::default()
+                })
+            })
+            .collect()
+    }
 }
 
 impl<C, Out: 'static> CacheData<C, Out> {
-    /// Evict all entries whose age is larger than or equal to `max_age`.
-    fn evict(&mut self, max_age: usize) {
+    /// Evict all entries that the policy doesn't want to keep.
+    fn evict(&mut self, max_age: usize, policy: &dyn EvictionPolicy) -> usize {
+        let mut removed = 0;
+        let on_evict = self.on_evict;
         self.entries.retain(|_, entries| {
+            let len = entries.len();
             entries.retain_mut(|entry| {
                 let age = entry.age.get_mut();
-                *age += 1;
-                *age <= max_age
+                *age = age.saturating_add(1);
+                let keep = policy.should_keep(&entry.meta(), max_age);
+                if let (false, Some(callback)) = (keep, on_evict) {
+                    callback(&entry.output);
+                }
+                keep
             });
+            removed += len - entries.len();
             !entries.is_empty()
         });
+        self.len -= removed;
+        self.prune_recency();
+        self.compact();
+        removed
+    }
+
+    /// Drop the recency records of removed entries once they make up most of
+    /// the records.
+    fn prune_recency(&mut self) {
+        if self.recency.len() <= 2 * self.len {
+            return;
+        }
+
+        self.recency = self
+            .entries
+            .iter()
+            .flat_map(|(&key, entries)| {
+                entries.iter().map(move |entry| {
+                    ((entry.last_access.load(Ordering::Relaxed), entry.id), key)
+                })
+            })
+            .collect();
+    }
+
+    /// Release unused storage once only a small fraction of it is live, for
+    /// instance after a large workload was evicted. The initially reserved
+    /// capacity is kept.
+    fn compact(&mut self) {
+        let len = self.entries.len();
+        if len < self.entries.capacity() / COMPACT_RATIO {
+            self.entries.shrink_to(self.reserved.max(2 * len));
+        }
     }
 
     /// Look for a matching entry in the cache.
@@ -179,41 +1509,165 @@ impl<C, Out: 'static> CacheData<C, Out> {
             .find_map(|entry| entry.lookup::<In>(input))
     }
 
+    /// Look for a matching entry without marking it as used.
+    fn peek<In>(&self, key: u128, input: &In) -> Option<&Out>
+    where
+        In: Input<Constraint = C>,
+    {
+        self.entries
+            .get(&key)?
+            .iter()
+            .rev()
+            .find(|entry| input.validate(&entry.constraint))
+            .map(|entry| &entry.output)
+    }
+
+    /// Remove all entries that are valid for the given input and return their
+    /// outputs in insertion order.
+    fn invalidate<In>(&mut self, key: u128, input: &In) -> Vec<Out>
+    where
+        In: Input<Constraint = C>,
+    {
+        let Some(entries) = self.entries.get_mut(&key) else { return vec![] };
+        let (removed, kept) = std::mem::take(entries)
+            .into_iter()
+            .partition::<Vec<_>, _>(|entry| input.validate(&entry.constraint));
+        *entries = kept;
+        if entries.is_empty() {
+            self.entries.remove(&key);
+        }
+
+        self.len -= removed.len();
+        self.prune_recency();
+        removed.into_iter().map(|entry| entry.output).collect()
+    }
+
+    /// Remove the entry that was least recently inserted or hit.
+    fn remove_least_recently_used(&mut self) {
+        while let Some(((stamp, id), key)) = self.recency.pop_first() {
+            // Skip records of entries that are already gone.
+            let Some(entries) = self.entries.get_mut(&key) else { continue };
+            let Some(index) = entries.iter().position(|entry| entry.id == id) else {
+                continue;
+            };
+
+            // Move entries that were hit since they were recorded back.
+            let last_access = entries[index].last_access.load(Ordering::Relaxed);
+            if last_access != stamp {
+                self.recency.insert((last_access, id), key);
+                continue;
+            }
+
+            let entry = entries.remove(index);
+            if entries.is_empty() {
+                self.entries.remove(&key);
+            }
+
+            self.len -= 1;
+            if let Some(callback) = self.on_evict {
+                callback(&entry.output);
+            }
+            return;
+        }
+    }
+
+    /// Remove all entries, release their memory and return how many there
+    /// were.
+    fn clear(&mut self) -> usize {
+        let entries = std::mem::take(&mut self.entries);
+        let removed = self.len;
+        self.discard();
+        if let Some(callback) = self.on_evict {
+            entries.values().flatten().for_each(|entry| callback(&entry.output));
+        }
+        removed
+    }
+
+    /// Remove all entries without reporting them.
+    fn discard(&mut self) {
+        self.entries = HashMap::new();
+        self.recency = BTreeMap::new();
+        self.len = 0;
+    }
+
     /// Insert an entry into the cache.
     fn insert<In>(&mut self, key: u128, constraint: In::Constraint, output: Out)
     where
         In: Input<Constraint = C>,
     {
-        self.entries
-            .entry(key)
-            .or_default()
-            .push(CacheEntry::new::<In>(constraint, output));
+        let entry = CacheEntry::new::<In>(self.next_id, constraint, output);
+        self.next_id += 1;
+
+        if self.limit > 0 {
+            while self.len >= self.limit && !self.recency.is_empty() {
+                self.remove_least_recently_used();
+            }
+            let stamp = entry.last_access.load(Ordering::Relaxed);
+            self.recency.insert((stamp, entry.id), key);
+        }
+
+        self.entries.entry(key).or_default().push(entry);
+        self.len += 1;
     }
 }
 
 impl<C, Out> Default for CacheData<C, Out> {
     fn default() -> Self {
-        Self { entries: HashMap::new() }
+        let capacity = CACHE_CAPACITY.load(Ordering::Relaxed);
+        Self {
+            entries: HashMap::with_capacity(capacity),
+            len: 0,
+            limit: 0,
+            recency: BTreeMap::new(),
+            next_id: 0,
+            reserved: capacity,
+            on_evict: None,
+        }
     }
 }
 
 /// A memoized result.
 struct CacheEntry<C, Out> {
+    /// Identifies the entry among all entries of its cache data.
+    id: u64,
     /// The memoized function's constraint.
     constraint: C,
     /// The memoized function's output.
     output: Out,
     /// How many evictions have passed since the entry has been last used.
+    /// Saturates at `usize::MAX` instead of wrapping around.
     age: AtomicUsize,
+    /// When the entry was last used, in nanoseconds since the epoch.
+    last_access: AtomicU64,
+    /// How often the entry produced a cache hit.
+    hits: AtomicUsize,
 }
 
 impl<C, Out: 'static> CacheEntry<C, Out> {
     /// Create a new entry.
-    fn new<In>(constraint: In::Constraint, output: Out) -> Self
+    fn new<In>(id: u64, constraint: In::Constraint, output: Out) -> Self
     where
         In: Input<Constraint = C>,
     {
-        Self { constraint, output, age: AtomicUsize::new(0) }
+        Self {
+            id,
+            constraint,
+            output,
+            age: AtomicUsize::new(0),
+            last_access: AtomicU64::new(now()),
+            hits: AtomicUsize::new(0),
+        }
+    }
+
+    /// The entry's metadata for eviction decisions.
+    fn meta(&self) -> EntryMeta {
+        let nanos = self.last_access.load(Ordering::Relaxed);
+        EntryMeta {
+            age: self.age.load(Ordering::Relaxed),
+            last_access: *EPOCH + Duration::from_nanos(nanos),
+            hits: self.hits.load(Ordering::Relaxed),
+            size: std::mem::size_of::<Out>(),
+        }
     }
 
     /// Return the entry's output if it is valid for the given input.
@@ -223,7 +1677,14 @@ impl<C, Out: 'static> CacheEntry<C, Out> {
     {
         input.validate(&self.constraint).then(|| {
             self.age.store(0, Ordering::SeqCst);
+            self.last_access.store(now(), Ordering::Relaxed);
+            self.hits.fetch_add(1, Ordering::Relaxed);
             (&self.constraint, &self.output)
         })
     }
 }
+
+/// The current time in nanoseconds since the epoch.
+fn now() -> u64 {
+    EPOCH.elapsed().as_nanos() as u64
+}
@@ -1,17 +1,37 @@
 use std::borrow::Cow;
 use std::collections::hash_map::Entry;
 use std::collections::HashMap;
-use std::hash::Hash;
+use std::hash::{BuildHasherDefault, Hash, Hasher};
 
 use parking_lot::RwLock;
 use siphasher::sip128::{Hasher128, SipHasher13};
 
 use crate::accelerate;
+use crate::impurity::{self, DiagnosticKind};
+
+#[cfg(feature = "testing")]
+thread_local! {
+    /// How many distinct calls the in-flight memoized execution recorded.
+    static RECORDED: std::cell::Cell<usize> = const { std::cell::Cell::new(0) };
+}
 
 /// A call to a tracked function.
 pub trait Call: Hash + PartialEq + Clone {
     /// Whether the call is mutable.
     fn is_mutable(&self) -> bool;
+
+    /// The name of the called method.
+    fn name(&self) -> &'static str;
+}
+
+/// Identifies a tracked call in an exported constraint.
+#[cfg(feature = "testing")]
+#[derive(Debug, Copy, Clone, Eq, PartialEq, Hash)]
+pub struct CallLabel {
+    /// The name of the called method.
+    pub name: &'static str,
+    /// The hash of the call, including its arguments.
+    pub hash: u128,
 }
 
 /// A constraint entry for a single call.
@@ -22,6 +42,15 @@ struct ConstraintEntry<T: Call> {
     ret_hash: u128,
 }
 
+#[cfg(feature = "testing")]
+impl<T: Call> ConstraintEntry<T> {
+    /// The entry's call label and return hash.
+    fn export(&self) -> (CallLabel, u128) {
+        let label = CallLabel { name: self.call.name(), hash: self.call_hash };
+        (label, self.ret_hash)
+    }
+}
+
 /// Defines a constraint for an immutably tracked type.
 pub struct ImmutableConstraint<T: Call>(RwLock<EntryMap<T>>);
 
@@ -58,8 +87,10 @@ impl<T: Call> ImmutableConstraint<T> {
         if let Some(accelerator) = accelerate::get(id) {
             let mut map = accelerator.lock();
             guard.0.values().all(|entry| {
-                *map.entry(entry.call_hash).or_insert_with(|| f(&entry.call))
-                    == entry.ret_hash
+                *map.entry(entry.call_hash).or_insert_with(|| {
+                    accelerate::inserted();
+                    f(&entry.call)
+                }) == entry.ret_hash
             })
         } else {
             guard.0.values().all(|entry| f(&entry.call) == entry.ret_hash)
@@ -157,19 +188,22 @@ impl<T: Call> Default for MutableConstraint<T> {
 
 /// A map of calls.
 #[derive(Clone)]
-struct EntryMap<T: Call>(HashMap<u128, ConstraintEntry<T>>);
+struct EntryMap<T: Call>(PassthroughHashMap<u128, ConstraintEntry<T>>);
 
 impl<T: Call> EntryMap<T> {
     /// Enter a constraint for a call to a function.
     #[inline]
     fn push_inner(&mut self, entry: Cow<ConstraintEntry<T>>) {
         match self.0.entry(entry.call_hash) {
-            Entry::Occupied(_occupied) => {
-                #[cfg(debug_assertions)]
-                check(_occupied.get(), &entry);
+            Entry::Occupied(occupied) => {
+                if impurity::checked() {
+                    check(occupied.get(), &entry);
+                }
             }
             Entry::Vacant(vacant) => {
                 vacant.insert(entry.into_owned());
+                #[cfg(feature = "testing")]
+                RECORDED.with(|cell| cell.set(cell.get() + 1));
             }
         }
     }
@@ -177,7 +211,7 @@ impl<T: Call> EntryMap<T> {
 
 impl<T: Call> Default for EntryMap<T> {
     fn default() -> Self {
-        Self(HashMap::new())
+        Self(PassthroughHashMap::default())
     }
 }
 
@@ -200,8 +234,9 @@ impl<T: Call> EntryVec<T> {
                 }
 
                 if entry.call_hash == prev.call_hash && entry.ret_hash == prev.ret_hash {
-                    #[cfg(debug_assertions)]
-                    check(&entry, prev);
+                    if impurity::checked() {
+                        check(&entry, prev);
+                    }
                     return;
                 }
             }
@@ -209,6 +244,8 @@ impl<T: Call> EntryVec<T> {
 
         // Insert the call into the call list.
         self.0.push(entry.into_owned());
+        #[cfg(feature = "testing")]
+        RECORDED.with(|cell| cell.set(cell.get() + 1));
     }
 }
 
@@ -225,6 +262,10 @@ pub trait Join<T = Self> {
 
     /// Take out the constraint.
     fn take(&self) -> Self;
+
+    /// Append the recorded calls and their return hashes to `calls`.
+    #[cfg(feature = "testing")]
+    fn export(&self, _calls: &mut Vec<(CallLabel, u128)>) {}
 }
 
 impl<T: Join> Join<T> for Option<&T> {
@@ -254,6 +295,11 @@ impl<T: Call> Join for ImmutableConstraint<T> {
     fn take(&self) -> Self {
         Self(RwLock::new(std::mem::take(&mut *self.0.write())))
     }
+
+    #[cfg(feature = "testing")]
+    fn export(&self, calls: &mut Vec<(CallLabel, u128)>) {
+        calls.extend(self.0.read().0.values().map(ConstraintEntry::export));
+    }
 }
 
 impl<T: Call> Join for MutableConstraint<T> {
@@ -269,6 +315,103 @@ impl<T: Call> Join for MutableConstraint<T> {
     fn take(&self) -> Self {
         Self(RwLock::new(std::mem::take(&mut *self.0.write())))
     }
+
+    #[cfg(feature = "testing")]
+    fn export(&self, calls: &mut Vec<(CallLabel, u128)>) {
+        calls.extend(self.0.read().0.iter().map(ConstraintEntry::export));
+    }
+}
+
+/// The number of distinct tracked calls recorded so far by the innermost
+/// in-flight memoized call on this thread.
+///
+/// This includes the calls recorded by nested memoized calls that already
+/// returned. Asserting a bound on it in tests helps to catch accidental
+/// explosions of tracked accesses.
+#[cfg(feature = "testing")]
+pub fn current_recording_len() -> usize {
+    RECORDED.with(|cell| cell.get())
+}
+
+/// Execute `f` as a memoized call with a fresh recording count.
+#[inline]
+pub(crate) fn recording<T>(f: impl FnOnce() -> T) -> T {
+    #[cfg(feature = "testing")]
+    let prev = RECORDED.with(|cell| cell.replace(0));
+    let output = f();
+    #[cfg(feature = "testing")]
+    RECORDED.with(|cell| cell.set(prev));
+    output
+}
+
+/// A value that already has a high-quality 128-bit hash, for example from a
+/// content-addressed store.
+///
+/// By default, comemo hashes arguments and the return values of tracked
+/// methods with SipHash through their [`Hash`] implementation. With
+/// `#[memoize(content_hash(<args>))]` and `#[track(content_hash)]`, the
+/// content hash is used instead. In that case, the type doesn't need to
+/// implement [`Hash`] at all. Content hashes must fulfill the same contract as
+/// `Hash`: Values that are distinguishable must have different hashes.
+pub trait ContentHash {
+    /// The value's content hash.
+    fn content_hash(&self) -> u128;
+}
+
+impl<T: ContentHash + ?Sized> ContentHash for &T {
+    fn content_hash(&self) -> u128 {
+        T::content_hash(self)
+    }
+}
+
+/// A hasher that produces 128-bit hashes, used to compute the keys of
+/// memoized calls.
+///
+/// By default, keys are computed with SipHash-1-3. A memoized function can
+/// use a different hasher with `#[memoize(hasher = <type>)]`, for instance a
+/// faster one for many cheap calls. The hasher must produce high-quality
+/// hashes because two calls whose keys collide may share cache entries.
+pub trait KeyHasher: Hasher + Default {
+    /// Produce the 128-bit hash of the written data.
+    fn finish128(self) -> u128;
+}
+
+impl KeyHasher for SipHasher13 {
+    fn finish128(self) -> u128 {
+        Hasher128::finish128(&self).as_u128()
+    }
+}
+
+/// A map whose keys already are 128-bit hashes.
+pub(crate) type PassthroughHashMap<K, V> =
+    HashMap<K, V, BuildHasherDefault<PassthroughHasher>>;
+
+/// A hasher that uses an already computed 128-bit hash as is instead of
+/// hashing it again.
+#[derive(Default)]
+pub(crate) struct PassthroughHasher(u64);
+
+impl Hasher for PassthroughHasher {
+    #[inline]
+    fn finish(&self) -> u64 {
+        self.0
+    }
+
+    /// Fold arbitrary bytes into the state, in case a key other than a `u128`
+    /// is ever hashed. The keys in use never take this path.
+    fn write(&mut self, bytes: &[u8]) {
+        for chunk in bytes.chunks(8) {
+            let mut buf = [0; 8];
+            buf[..chunk.len()].copy_from_slice(chunk);
+            self.0 = (self.0.rotate_left(5) ^ u64::from_le_bytes(buf))
+                .wrapping_mul(0x51_7c_c1_b7_27_22_0a_95);
+        }
+    }
+
+    #[inline]
+    fn write_u128(&mut self, hash: u128) {
+        self.0 = hash as u64;
+    }
 }
 
 /// Produce a 128-bit hash of a value.
@@ -276,19 +419,39 @@ impl<T: Call> Join for MutableConstraint<T> {
 pub fn hash<T: Hash>(value: &T) -> u128 {
     let mut state = SipHasher13::new();
     value.hash(&mut state);
-    state.finish128().as_u128()
+    state.finish128()
+}
+
+/// Produce a 128-bit hash of the items yielded by an iterator.
+///
+/// Iterating over `T` and over `&T` produces the same hash.
+#[inline]
+pub fn hash_iter<I>(iter: I) -> u128
+where
+    I: IntoIterator,
+    I::Item: Hash,
+{
+    let mut state = SipHasher13::new();
+    let mut len = 0usize;
+    for item in iter {
+        item.hash(&mut state);
+        len += 1;
+    }
+    len.hash(&mut state);
+    state.finish128()
 }
 
 /// Check for a constraint violation.
 #[inline]
 #[track_caller]
-#[allow(dead_code)]
 fn check<T: Call>(lhs: &ConstraintEntry<T>, rhs: &ConstraintEntry<T>) {
     if lhs.ret_hash != rhs.ret_hash {
-        panic!(
+        impurity::report(
+            DiagnosticKind::ConflictingReturn,
             "comemo: found conflicting constraints. \
-             is this tracked function pure?"
-        )
+             is this tracked function pure?",
+        );
+        return;
     }
 
     // Additional checks for debugging.
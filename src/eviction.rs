@@ -0,0 +1,95 @@
+use std::time::{Duration, Instant};
+
+use once_cell::sync::Lazy;
+use parking_lot::RwLock;
+
+/// The global eviction policy. `None` means the default [`AgePolicy`].
+static POLICY: RwLock<Option<Box<dyn EvictionPolicy>>> = RwLock::new(None);
+
+/// The instant relative to which access times are stored.
+pub(crate) static EPOCH: Lazy<Instant> = Lazy::new(Instant::now);
+
+/// Decides which cache entries survive an eviction.
+///
+/// A policy can be configured globally with [`set_eviction_policy`] or per
+/// function with `#[memoize(policy = <expr>)]`.
+pub trait EvictionPolicy: Send + Sync {
+    /// Whether to keep an entry during a call to [`evict`](crate::evict) with
+    /// the given `max_age`.
+    fn should_keep(&self, meta: &EntryMeta, max_age: usize) -> bool;
+}
+
+/// Metadata about a cache entry, passed to an [`EvictionPolicy`].
+#[derive(Debug, Copy, Clone)]
+pub struct EntryMeta {
+    /// How many evictions have passed since the entry was last used,
+    /// including the current one. Saturates at `usize::MAX`, so an entry that
+    /// wasn't used for a very long time never appears young again.
+    pub age: usize,
+    /// When the entry was last inserted or hit.
+    pub last_access: Instant,
+    /// How often the entry produced a cache hit.
+    pub hits: usize,
+    /// The shallow size of the cached output in bytes.
+    pub size: usize,
+}
+
+/// Keeps entries that were used within the last `max_age` evictions.
+///
+/// This is the default policy.
+#[derive(Debug, Default, Copy, Clone)]
+pub struct AgePolicy;
+
+impl EvictionPolicy for AgePolicy {
+    fn should_keep(&self, meta: &EntryMeta, max_age: usize) -> bool {
+        meta.age <= max_age
+    }
+}
+
+/// Keeps entries that were used within the given duration, regardless of
+/// `max_age`.
+#[derive(Debug, Copy, Clone)]
+pub struct LruPolicy(pub Duration);
+
+impl EvictionPolicy for LruPolicy {
+    fn should_keep(&self, meta: &EntryMeta, _: usize) -> bool {
+        meta.last_access.elapsed() <= self.0
+    }
+}
+
+/// Keeps entries that were hit at least `min_hits` times and otherwise falls
+/// back to the age-based policy.
+///
+/// Note that with this policy, an eviction with `max_age` zero does not clear
+/// frequently used entries.
+#[derive(Debug, Copy, Clone)]
+pub struct LfuPolicy {
+    /// How many hits protect an entry from eviction.
+    pub min_hits: usize,
+}
+
+impl EvictionPolicy for LfuPolicy {
+    fn should_keep(&self, meta: &EntryMeta, max_age: usize) -> bool {
+        meta.hits >= self.min_hits || meta.age <= max_age
+    }
+}
+
+impl<P: EvictionPolicy + ?Sized> EvictionPolicy for &P {
+    fn should_keep(&self, meta: &EntryMeta, max_age: usize) -> bool {
+        (**self).should_keep(meta, max_age)
+    }
+}
+
+/// Configure the eviction policy for all memoized functions without a
+/// function-specific policy.
+pub fn set_eviction_policy<P: EvictionPolicy + 'static>(policy: P) {
+    *POLICY.write() = Some(Box::new(policy));
+}
+
+/// Run `f` with the global eviction policy.
+pub(crate) fn with_policy<T>(f: impl FnOnce(&dyn EvictionPolicy) -> T) -> T {
+    match &*POLICY.read() {
+        Some(policy) => f(policy.as_ref()),
+        None => f(&AgePolicy),
+    }
+}
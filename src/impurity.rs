@@ -0,0 +1,135 @@
+use std::cell::Cell;
+use std::sync::atomic::{AtomicBool, AtomicU8, Ordering};
+
+use parking_lot::RwLock;
+
+/// The global impurity policy.
+static POLICY: AtomicU8 = AtomicU8::new(ImpurityPolicy::Panic as u8);
+
+/// Whether impurities are also detected in release builds.
+static STRICT: AtomicBool = AtomicBool::new(false);
+
+/// The global diagnostic hook.
+static HOOK: RwLock<Option<fn(Diagnostic)>> = RwLock::new(None);
+
+thread_local! {
+    /// Whether an impurity was detected during the current memoized call.
+    static DETECTED: Cell<bool> = const { Cell::new(false) };
+
+    /// The name of the innermost memoized function that is being executed.
+    static FUNCTION: Cell<Option<fn() -> &'static str>> = const { Cell::new(None) };
+}
+
+/// How to react when a tracked function turns out to be impure.
+///
+/// Comemo detects impure tracked functions in debug builds by checking that
+/// repeated calls with the same arguments produce the same return value. With
+/// [`set_strict`], it does so in release builds, too.
+#[derive(Debug, Default, Copy, Clone, Eq, PartialEq, Hash)]
+pub enum ImpurityPolicy {
+    /// Panic with a descriptive message. This is the default.
+    #[default]
+    Panic = 0,
+    /// Report a diagnostic and permanently bypass the cache of the memoized
+    /// function during whose execution the impurity was detected. The function
+    /// is executed without memoization from then on.
+    LogAndBypass = 1,
+}
+
+/// Configure how comemo reacts to impure tracked functions.
+pub fn set_impurity_policy(policy: ImpurityPolicy) {
+    POLICY.store(policy as u8, Ordering::SeqCst);
+}
+
+/// The currently configured impurity policy.
+pub fn impurity_policy() -> ImpurityPolicy {
+    match POLICY.load(Ordering::SeqCst) {
+        0 => ImpurityPolicy::Panic,
+        _ => ImpurityPolicy::LogAndBypass,
+    }
+}
+
+/// Detect impure tracked functions in release builds, too.
+///
+/// By default, the check only runs in debug builds because it costs some
+/// performance on every tracked call. Enabling it in release builds is useful
+/// to audit a production workload or to fail a CI performance gate instead of
+/// silently losing cache hits. Detections are handled according to the
+/// [impurity policy](set_impurity_policy).
+pub fn set_strict(strict: bool) {
+    STRICT.store(strict, Ordering::SeqCst);
+}
+
+/// Whether tracked functions are checked for impurities.
+#[inline]
+pub fn checked() -> bool {
+    cfg!(debug_assertions) || STRICT.load(Ordering::Relaxed)
+}
+
+/// A problem that comemo detected during the execution of a memoized function.
+#[derive(Debug, Copy, Clone, Eq, PartialEq, Hash)]
+pub struct Diagnostic {
+    /// The name of the memoized function.
+    pub function: &'static str,
+    /// What went wrong.
+    pub kind: DiagnosticKind,
+}
+
+/// The kind of a [`Diagnostic`].
+#[derive(Debug, Copy, Clone, Eq, PartialEq, Hash)]
+#[non_exhaustive]
+pub enum DiagnosticKind {
+    /// A tracked method returned different values for the same arguments.
+    ConflictingReturn,
+    /// Because of an impurity, the memoized function's cache is bypassed from
+    /// now on. Reported once per function.
+    Bypassed,
+}
+
+/// Install a hook that is called whenever an impurity is detected or a cache
+/// is bypassed because of one, or remove it with `None`.
+///
+/// The hook is called before the detection is handled according to the
+/// [impurity policy](set_impurity_policy). Together with
+/// [`ImpurityPolicy::LogAndBypass`] and [`set_strict`], this allows auditing
+/// the determinism of a large codebase in production without crashing.
+pub fn set_diagnostic_hook(hook: Option<fn(Diagnostic)>) {
+    *HOOK.write() = hook;
+}
+
+/// Report a detected impurity according to the configured policy.
+#[track_caller]
+pub fn report(kind: DiagnosticKind, message: &str) {
+    if let Some(hook) = *HOOK.read() {
+        if let Some(name) = FUNCTION.with(Cell::get) {
+            let name = name();
+            let function = name.strip_suffix("::{{closure}}").unwrap_or(name);
+            hook(Diagnostic { function, kind });
+        }
+    }
+
+    match impurity_policy() {
+        ImpurityPolicy::Panic => panic!("{message}"),
+        ImpurityPolicy::LogAndBypass => DETECTED.with(|cell| cell.set(true)),
+    }
+}
+
+/// Inform the diagnostic hook that the cache of the given memoized function is
+/// bypassed from now on.
+pub fn report_bypass(function: &'static str) {
+    if let Some(hook) = *HOOK.read() {
+        hook(Diagnostic { function, kind: DiagnosticKind::Bypassed });
+    }
+}
+
+/// Execute `f` on behalf of the memoized function with the given name and
+/// return whether an impurity was reported during it.
+#[inline]
+pub fn detect<T>(function: fn() -> &'static str, f: impl FnOnce() -> T) -> (T, bool) {
+    let prev = DETECTED.with(|cell| cell.replace(false));
+    let prev_function = FUNCTION.with(|cell| cell.replace(Some(function)));
+    let output = f();
+    FUNCTION.with(|cell| cell.set(prev_function));
+    let detected = DETECTED.with(|cell| cell.replace(prev));
+    (output, detected)
+}
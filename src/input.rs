@@ -1,6 +1,7 @@
 use std::hash::{Hash, Hasher};
 
-use crate::constraint::Join;
+use crate::constraint::{ContentHash, Join};
+use crate::sink::{Sink, SinkConstraint};
 use crate::track::{Track, Tracked, TrackedMut, Validate};
 
 /// Ensure a type is suitable as input.
@@ -9,8 +10,18 @@ pub fn assert_hashable_or_trackable<In: Input>(_: &In) {}
 
 /// An input to a cached function.
 ///
-/// This is implemented for hashable types, `Tracked<_>` types and `Args<(...)>`
-/// types containing tuples up to length twelve.
+/// This is implemented for hashable types, `Tracked<_>` types, `Sink<_>` types,
+/// `Zip<_, _>` types and `Args<(...)>` types containing tuples up to length
+/// twelve. Functions with more arguments nest the remaining ones in an inner
+/// `Args<(...)>` as the tuple's last element.
+#[diagnostic::on_unimplemented(
+    message = "`{Self}` is not a valid argument of a memoized function",
+    label = "arguments must be hashable or tracked",
+    note = "arguments must implement `Hash` or be of the form `Tracked<T>`, \
+            `TrackedMut<T>` or `Option<Tracked<T>>`",
+    note = "a `Tracked<T>` nested in another type is not tracked, pass it as \
+            a separate argument or combine it with others through `Zip`"
+)]
 pub trait Input {
     /// The constraints for this input.
     type Constraint: Default + Clone + Join + 'static;
@@ -40,6 +51,10 @@ pub trait Input {
     ) -> (Self::Tracked<'r>, Self::Outer)
     where
         Self: 'r;
+
+    /// Collect the labels of the tracked parts of the input.
+    #[cfg(feature = "tracing")]
+    fn labels(&self, _: &mut Vec<&'static str>) {}
 }
 
 impl<T: Hash> Input for T {
@@ -102,9 +117,15 @@ where
             value: self.value,
             constraint: Some(constraint),
             id: self.id,
+            label: self.label,
         };
         (tracked, self.constraint)
     }
+
+    #[cfg(feature = "tracing")]
+    fn labels(&self, labels: &mut Vec<&'static str>) {
+        labels.extend(self.label);
+    }
 }
 
 impl<'a, T> Input for TrackedMut<'a, T>
@@ -142,6 +163,269 @@ where
     }
 }
 
+impl<'a, T> Input for Sink<'a, T>
+where
+    T: Clone + 'static,
+{
+    // Record the emitted values.
+    type Constraint = SinkConstraint<T>;
+    type Tracked<'r> = Sink<'r, T> where Self: 'r;
+    // Always empty in practice: Nested calls emit into the recording callback
+    // of the outer call, which already records their values.
+    type Outer = Option<&'a Self::Constraint>;
+
+    #[inline]
+    fn key<H: Hasher>(&self, _: &mut H) {}
+
+    #[inline]
+    fn validate(&self, _: &Self::Constraint) -> bool {
+        true
+    }
+
+    #[inline]
+    fn replay(&mut self, constraint: &Self::Constraint) {
+        constraint.replay(|value| (self.emit)(value));
+    }
+
+    #[inline]
+    fn retrack<'r>(
+        self,
+        constraint: &'r Self::Constraint,
+    ) -> (Self::Tracked<'r>, Self::Outer)
+    where
+        Self: 'r,
+    {
+        (Sink { emit: self.emit, constraint: Some(constraint) }, self.constraint)
+    }
+}
+
+/// Wrapper for a hashed input whose hash is computed upfront.
+pub struct Prehash<T> {
+    /// The hash of the value.
+    hash: u128,
+    /// The value itself.
+    value: T,
+}
+
+impl<T> Prehash<T> {
+    /// Hash a value.
+    #[inline]
+    pub fn new(value: T) -> Self
+    where
+        T: Hash,
+    {
+        Self { hash: crate::constraint::hash(&value), value }
+    }
+
+    /// Use a value's content hash.
+    #[inline]
+    pub fn content(value: T) -> Self
+    where
+        T: ContentHash,
+    {
+        Self { hash: value.content_hash(), value }
+    }
+}
+
+impl<T> Input for Prehash<T> {
+    // No constraint for hashed inputs.
+    type Constraint = ();
+    type Tracked<'r> = T where Self: 'r;
+    type Outer = ();
+
+    #[inline]
+    fn key<H: Hasher>(&self, state: &mut H) {
+        state.write_u128(self.hash);
+    }
+
+    #[inline]
+    fn validate(&self, _: &()) -> bool {
+        true
+    }
+
+    #[inline]
+    fn replay(&mut self, _: &Self::Constraint) {}
+
+    #[inline]
+    fn retrack<'r>(self, _: &'r ()) -> (Self::Tracked<'r>, Self::Outer)
+    where
+        Self: 'r,
+    {
+        (self.value, ())
+    }
+}
+
+/// Wrapper for an input that doesn't contribute to the key.
+pub struct Ignored<T>(pub T);
+
+impl<T> Input for Ignored<T> {
+    // No constraint for ignored inputs.
+    type Constraint = ();
+    type Tracked<'r> = T where Self: 'r;
+    type Outer = ();
+
+    #[inline]
+    fn key<H: Hasher>(&self, _: &mut H) {}
+
+    #[inline]
+    fn validate(&self, _: &()) -> bool {
+        true
+    }
+
+    #[inline]
+    fn replay(&mut self, _: &Self::Constraint) {}
+
+    #[inline]
+    fn retrack<'r>(self, _: &'r ()) -> (Self::Tracked<'r>, Self::Outer)
+    where
+        Self: 'r,
+    {
+        (self.0, ())
+    }
+}
+
+/// Wrapper for an optional tracked input.
+///
+/// `Option<Tracked<T>>` cannot implement [`Input`] directly because it would
+/// overlap with the implementation for hashable types.
+pub struct OptionTracked<T>(pub Option<T>);
+
+impl<'a, T> Input for OptionTracked<Tracked<'a, T>>
+where
+    T: Track + ?Sized,
+{
+    // Forward constraint from `Trackable` implementation.
+    type Constraint = <T as Validate>::Constraint;
+    type Tracked<'r> = Option<Tracked<'r, T>> where Self: 'r;
+    type Outer = Option<&'a Self::Constraint>;
+
+    #[inline]
+    fn key<H: Hasher>(&self, state: &mut H) {
+        // Keep `None` and `Some` apart.
+        self.0.is_some().hash(state);
+    }
+
+    #[inline]
+    fn validate(&self, constraint: &Self::Constraint) -> bool {
+        self.0.as_ref().is_none_or(|tracked| tracked.validate(constraint))
+    }
+
+    #[inline]
+    fn replay(&mut self, _: &Self::Constraint) {}
+
+    #[inline]
+    fn retrack<'r>(
+        self,
+        constraint: &'r Self::Constraint,
+    ) -> (Self::Tracked<'r>, Self::Outer)
+    where
+        Self: 'r,
+    {
+        match self.0 {
+            Some(tracked) => {
+                let (tracked, outer) = tracked.retrack(constraint);
+                (Some(tracked), outer)
+            }
+            None => (None, None),
+        }
+    }
+
+    #[cfg(feature = "tracing")]
+    fn labels(&self, labels: &mut Vec<&'static str>) {
+        if let Some(tracked) = &self.0 {
+            tracked.labels(labels);
+        }
+    }
+}
+
+/// Combines two inputs into one argument.
+///
+/// Memoized functions can take multiple tracked arguments, but sometimes it's
+/// more convenient to carry several tracked dependencies around behind a
+/// single value, for instance in a helper struct. A `Zip` can be passed to a
+/// memoized function like any other input. Both of its parts are tracked or
+/// hashed as if they were passed separately. Zips can be nested to combine
+/// more than two inputs.
+///
+/// ```
+/// # use comemo::{Track, Tracked, Zip};
+/// # struct Files;
+/// # struct Config;
+/// #[comemo::track]
+/// impl Files {
+///     fn read(&self, path: &str) -> String {
+///         # /*
+///         ...
+///         # */ String::new()
+///     }
+/// }
+///
+/// #[comemo::track]
+/// impl Config {
+///     fn indent(&self) -> usize {
+///         # /*
+///         ...
+///         # */ 2
+///     }
+/// }
+///
+/// type Deps<'a> = Zip<Tracked<'a, Files>, Tracked<'a, Config>>;
+///
+/// #[comemo::memoize]
+/// fn format(path: &str, deps: Deps) -> String {
+///     let Zip(files, config) = deps;
+///     let indent = " ".repeat(config.indent());
+///     files.read(path).lines().map(|line| format!("{indent}{line}\n")).collect()
+/// }
+///
+/// format("main.rs", Zip(Files.track(), Config.track()));
+/// ```
+#[derive(Debug, Copy, Clone)]
+pub struct Zip<A, B>(pub A, pub B);
+
+impl<A: Input, B: Input> Input for Zip<A, B> {
+    // Combine the constraints of both parts.
+    type Constraint = (A::Constraint, B::Constraint);
+    type Tracked<'r> = Zip<A::Tracked<'r>, B::Tracked<'r>> where Self: 'r;
+    type Outer = (A::Outer, B::Outer);
+
+    #[inline]
+    fn key<H: Hasher>(&self, state: &mut H) {
+        self.0.key(state);
+        self.1.key(state);
+    }
+
+    #[inline]
+    fn validate(&self, constraint: &Self::Constraint) -> bool {
+        self.0.validate(&constraint.0) && self.1.validate(&constraint.1)
+    }
+
+    #[inline]
+    fn replay(&mut self, constraint: &Self::Constraint) {
+        self.0.replay(&constraint.0);
+        self.1.replay(&constraint.1);
+    }
+
+    #[inline]
+    fn retrack<'r>(
+        self,
+        constraint: &'r Self::Constraint,
+    ) -> (Self::Tracked<'r>, Self::Outer)
+    where
+        Self: 'r,
+    {
+        let (a, a_outer) = self.0.retrack(&constraint.0);
+        let (b, b_outer) = self.1.retrack(&constraint.1);
+        (Zip(a, b), (a_outer, b_outer))
+    }
+
+    #[cfg(feature = "tracing")]
+    fn labels(&self, labels: &mut Vec<&'static str>) {
+        self.0.labels(labels);
+        self.1.labels(labels);
+    }
+}
+
 /// Wrapper for multiple inputs.
 pub struct Args<T>(pub T);
 
@@ -179,6 +463,11 @@ macro_rules! args_input {
                 $(let $param = (self.0).$idx.retrack(&constraint.$idx);)*
                 (($($param.0,)*), ($($param.1,)*))
             }
+
+            #[cfg(feature = "tracing")]
+            fn labels(&self, labels: &mut Vec<&'static str>) {
+                $((self.0).$idx.labels(labels);)*
+            }
         }
 
         #[allow(unused_variables, clippy::unused_unit)]
@@ -192,6 +481,11 @@ macro_rules! args_input {
             fn take(&self) -> Self {
                 ($(self.$idx.take(),)*)
             }
+
+            #[cfg(feature = "testing")]
+            fn export(&self, calls: &mut Vec<(crate::constraint::CallLabel, u128)>) {
+                $(self.$idx.export(calls);)*
+            }
         }
     };
 }
@@ -85,25 +85,61 @@ For the full example see [`examples/calc.rs`][calc].
 mod accelerate;
 mod cache;
 mod constraint;
+mod eviction;
+mod impurity;
 mod input;
 mod prehashed;
+mod sink;
 mod track;
 
-pub use crate::cache::evict;
-pub use crate::prehashed::Prehashed;
-pub use crate::track::{Track, Tracked, TrackedMut, Validate};
-pub use comemo_macros::{memoize, track};
+pub use crate::accelerate::{
+    clear_accelerators, set_acceleration, set_accelerator_capacity, set_accelerator_limit,
+};
+pub use crate::cache::{
+    cache_stats, clear, evict, evict_configured, evict_older_than, evict_to, init,
+    overhead_report, set_default_max_age, warm, without_cache, CacheHandle, CacheStats,
+    Config, Overhead,
+};
+#[cfg(feature = "testing")]
+pub use crate::cache::{export, hit_count, miss_count, reset_counts, EntryExport};
+#[cfg(feature = "testing")]
+pub use crate::constraint::{current_recording_len, CallLabel};
+pub use crate::constraint::{ContentHash, KeyHasher};
+pub use crate::eviction::{
+    set_eviction_policy, AgePolicy, EntryMeta, EvictionPolicy, LfuPolicy, LruPolicy,
+};
+pub use crate::impurity::{
+    impurity_policy, set_diagnostic_hook, set_impurity_policy, set_strict, Diagnostic,
+    DiagnosticKind, ImpurityPolicy,
+};
+pub use crate::input::Zip;
+pub use crate::prehashed::{LazyPrehashed, Prehashed, PrehashedGuard};
+pub use crate::track::{Constraint, Track, Tracked, TrackedMut, Validate};
+pub use comemo_macros::{memoize, track, Track};
 
 /// These are implementation details. Do not rely on them!
 #[doc(hidden)]
 pub mod internal {
     pub use parking_lot::RwLock;
-
-    pub use crate::cache::{memoized, register_evictor, Cache, CacheData};
-    pub use crate::constraint::{hash, Call, ImmutableConstraint, MutableConstraint};
-    pub use crate::input::{assert_hashable_or_trackable, Args, Input};
-    pub use crate::track::{to_parts_mut_mut, to_parts_mut_ref, to_parts_ref, Surfaces};
+    pub use siphasher::sip128::SipHasher13;
+
+    pub use crate::cache::{
+        assert_send_sync, key, memoized, memoized_async, memoized_changed,
+        memoized_field, memoized_result, peek, prime, register_evictor,
+        register_reporter, Cache, CacheData, Eviction, SingleThreaded,
+    };
+    pub use crate::constraint::{
+        hash, hash_iter, Call, ImmutableConstraint, MutableConstraint,
+    };
+    pub use crate::input::{
+        assert_hashable_or_trackable, Args, Ignored, Input, OptionTracked, Prehash,
+    };
+    pub use crate::sink::{Sink, SinkConstraint};
+    pub use crate::track::{
+        to_parts_mut_mut, to_parts_mut_ref, to_parts_ref, CheckRoundTrip, RoundTrip,
+        SkipRoundTrip, Surfaces,
+    };
 
     #[cfg(feature = "testing")]
-    pub use crate::cache::last_was_hit;
+    pub use crate::cache::{last_was_hit, register_exporter};
 }
@@ -2,8 +2,9 @@ use std::any::Any;
 use std::cmp::{Ord, PartialOrd};
 use std::fmt::{self, Debug, Formatter};
 use std::hash::{Hash, Hasher};
-use std::ops::Deref;
+use std::ops::{Deref, DerefMut};
 
+use once_cell::sync::OnceCell;
 use siphasher::sip128::{Hasher128, SipHasher13};
 
 /// A wrapper type with precomputed hash.
@@ -18,6 +19,11 @@ use siphasher::sip128::{Hasher128, SipHasher13};
 /// However, that seldomly matters as you are typically either dealing with
 /// values of type `T` or with values of type `Prehashed<T>`, not a mix of both.
 ///
+/// # Lazy hashing
+/// [`Prehashed::new`] computes the hash right away. If many values are created,
+/// but only few of them are ever hashed or compared, [`LazyPrehashed`] defers
+/// hashing until the hash is first needed.
+///
 /// # Equality
 /// Because comemo uses high-quality 128 bit hashes in all places, the risk of a
 /// hash collision is reduced to an absolute minimum. Therefore, this type
@@ -25,6 +31,11 @@ use siphasher::sip128::{Hasher128, SipHasher13};
 /// hash instead of by value. For this to be correct, your hash implementation
 /// **must feed all information relevant to the `PartialEq` impl to the
 /// hasher.**
+///
+/// # Serialization
+/// With the `serde` feature, `Prehashed<T>` serializes just like `T`. The hash
+/// is not part of the serialized data. Instead, it is recomputed upon
+/// deserialization.
 #[derive(Copy, Clone)]
 pub struct Prehashed<T: ?Sized> {
     /// The precomputed hash.
@@ -46,6 +57,18 @@ impl<T: Hash + 'static> Prehashed<T> {
         self.item
     }
 
+    /// Whether `other` hashes to the same value as the wrapped item.
+    ///
+    /// This hashes `other` in exactly the same way as [`new`](Self::new)
+    /// hashed the wrapped item, so it is equivalent to comparing with
+    /// `Prehashed::new(other)`, but without taking ownership of `other`. Just
+    /// like the `PartialEq` implementation, this relies on the `Hash`
+    /// implementation of `T` feeding all relevant information to the hasher.
+    #[inline]
+    pub fn hash_eq(&self, other: &T) -> bool {
+        self.hash == hash(other)
+    }
+
     /// Update the wrapped value and recompute the hash.
     #[inline]
     pub fn update<F, U>(&mut self, f: F) -> U
@@ -56,6 +79,45 @@ impl<T: Hash + 'static> Prehashed<T> {
         self.hash = hash(&self.item);
         output
     }
+
+    /// Mutably access the wrapped value.
+    ///
+    /// The hash is recomputed when the returned guard is dropped. This is a
+    /// more flexible alternative to [`update`](Self::update) for mutations
+    /// that span multiple statements.
+    #[inline]
+    pub fn get_mut(&mut self) -> PrehashedGuard<'_, T> {
+        PrehashedGuard(self)
+    }
+}
+
+/// Mutable access to the value of a [`Prehashed`].
+///
+/// Recomputes the hash when dropped, even during a panic. Returned by
+/// [`Prehashed::get_mut`].
+pub struct PrehashedGuard<'a, T: Hash + 'static>(&'a mut Prehashed<T>);
+
+impl<T: Hash + 'static> Deref for PrehashedGuard<'_, T> {
+    type Target = T;
+
+    #[inline]
+    fn deref(&self) -> &Self::Target {
+        &self.0.item
+    }
+}
+
+impl<T: Hash + 'static> DerefMut for PrehashedGuard<'_, T> {
+    #[inline]
+    fn deref_mut(&mut self) -> &mut Self::Target {
+        &mut self.0.item
+    }
+}
+
+impl<T: Hash + 'static> Drop for PrehashedGuard<'_, T> {
+    #[inline]
+    fn drop(&mut self) {
+        self.0.hash = hash(&self.0.item);
+    }
 }
 
 /// Hash the item.
@@ -127,3 +189,97 @@ impl<T: PartialOrd + ?Sized> PartialOrd for Prehashed<T> {
         self.item.partial_cmp(&other.item)
     }
 }
+
+#[cfg(feature = "serde")]
+impl<T: serde::Serialize + ?Sized> serde::Serialize for Prehashed<T> {
+    #[inline]
+    fn serialize<S: serde::Serializer>(&self, serializer: S) -> Result<S::Ok, S::Error> {
+        self.item.serialize(serializer)
+    }
+}
+
+#[cfg(feature = "serde")]
+impl<'de, T> serde::Deserialize<'de> for Prehashed<T>
+where
+    T: serde::Deserialize<'de> + Hash + 'static,
+{
+    #[inline]
+    fn deserialize<D: serde::Deserializer<'de>>(
+        deserializer: D,
+    ) -> Result<Self, D::Error> {
+        T::deserialize(deserializer).map(Self::new)
+    }
+}
+
+/// A wrapper type whose hash is computed once it is first needed.
+///
+/// This is an alternative to [`Prehashed`] for values that are created in
+/// large numbers, but seldomly hashed or compared. A lazily hashed value hashes
+/// to the same value as the equivalent `Prehashed`. The same requirements
+/// regarding [equality](Prehashed#equality) apply.
+#[derive(Clone)]
+pub struct LazyPrehashed<T: Hash + 'static> {
+    /// The hash, if it was already computed.
+    hash: OnceCell<u128>,
+    /// The wrapped item.
+    item: T,
+}
+
+impl<T: Hash + 'static> LazyPrehashed<T> {
+    /// Wrap an item without hashing it yet.
+    #[inline]
+    pub fn new(item: T) -> Self {
+        Self { hash: OnceCell::new(), item }
+    }
+
+    /// Return the wrapped value.
+    #[inline]
+    pub fn into_inner(self) -> T {
+        self.item
+    }
+
+    /// The item's hash, computing it if it wasn't computed yet.
+    #[inline]
+    fn hash(&self) -> u128 {
+        *self.hash.get_or_init(|| hash(&self.item))
+    }
+}
+
+impl<T: Hash + 'static> Deref for LazyPrehashed<T> {
+    type Target = T;
+
+    #[inline]
+    fn deref(&self) -> &Self::Target {
+        &self.item
+    }
+}
+
+impl<T: Hash + 'static> From<T> for LazyPrehashed<T> {
+    #[inline]
+    fn from(value: T) -> Self {
+        Self::new(value)
+    }
+}
+
+impl<T: Hash + 'static> Hash for LazyPrehashed<T> {
+    #[inline]
+    fn hash<H: Hasher>(&self, state: &mut H) {
+        state.write_u128(self.hash());
+    }
+}
+
+impl<T: Debug + Hash + 'static> Debug for LazyPrehashed<T> {
+    #[inline]
+    fn fmt(&self, f: &mut Formatter) -> fmt::Result {
+        self.item.fmt(f)
+    }
+}
+
+impl<T: Hash + 'static> Eq for LazyPrehashed<T> {}
+
+impl<T: Hash + 'static> PartialEq for LazyPrehashed<T> {
+    #[inline]
+    fn eq(&self, other: &Self) -> bool {
+        self.hash() == other.hash()
+    }
+}
@@ -0,0 +1,81 @@
+use parking_lot::RwLock;
+
+use crate::constraint::Join;
+
+/// Records the values emitted into a callback sink.
+///
+/// This is the input wrapper for `&mut dyn FnMut(T)` arguments of memoized
+/// functions. Every emitted value is forwarded to the underlying callback and
+/// recorded into a constraint so that it can be replayed on a cache hit.
+pub struct Sink<'a, T> {
+    /// The underlying callback.
+    pub(crate) emit: &'a mut dyn FnMut(T),
+    /// The recording of emitted values.
+    ///
+    /// Starts out as `None` and is set to a stack-stored recording in the
+    /// preamble of memoized functions.
+    pub(crate) constraint: Option<&'a SinkConstraint<T>>,
+}
+
+impl<'a, T: Clone> Sink<'a, T> {
+    /// Wrap a callback.
+    #[inline]
+    pub fn new(emit: &'a mut dyn FnMut(T)) -> Self {
+        Self { emit, constraint: None }
+    }
+
+    /// Emit a value into the sink, recording it if necessary.
+    #[inline]
+    pub fn emit(&mut self, value: T) {
+        if let Some(constraint) = self.constraint {
+            constraint.push(value.clone());
+        }
+        (self.emit)(value);
+    }
+}
+
+/// The sequence of values emitted into a sink during a memoized call.
+pub struct SinkConstraint<T>(RwLock<Vec<T>>);
+
+impl<T: Clone> SinkConstraint<T> {
+    /// Record an emitted value.
+    #[inline]
+    pub fn push(&self, value: T) {
+        self.0.write().push(value);
+    }
+
+    /// Replay all recorded values in order.
+    #[inline]
+    pub fn replay<F>(&self, mut f: F)
+    where
+        F: FnMut(T),
+    {
+        for value in self.0.read().iter() {
+            f(value.clone());
+        }
+    }
+}
+
+impl<T: Clone> Clone for SinkConstraint<T> {
+    fn clone(&self) -> Self {
+        Self(RwLock::new(self.0.read().clone()))
+    }
+}
+
+impl<T> Default for SinkConstraint<T> {
+    fn default() -> Self {
+        Self(RwLock::new(Vec::new()))
+    }
+}
+
+impl<T: Clone> Join for SinkConstraint<T> {
+    #[inline]
+    fn join(&self, inner: &Self) {
+        self.0.write().extend(inner.0.read().iter().cloned());
+    }
+
+    #[inline]
+    fn take(&self) -> Self {
+        Self(RwLock::new(std::mem::take(&mut *self.0.write())))
+    }
+}
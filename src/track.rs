@@ -1,14 +1,22 @@
+use std::borrow::Borrow;
 use std::fmt::{self, Debug, Formatter};
+use std::hash::Hash;
 use std::ops::{Deref, DerefMut};
 
 use crate::accelerate;
-use crate::constraint::Join;
+use crate::constraint::{hash, Join};
 
 /// A trackable type.
 ///
 /// This is implemented by types that have an implementation block annotated
 /// with `#[track]` and for trait objects whose traits are annotated with
 /// `#[track]`. For more details, see [its documentation](macro@crate::track).
+///
+/// Values behind smart pointers like `Arc<T>`, `Rc<T>` or `Box<T>` can be
+/// tracked directly: Through auto-deref, `arc.track()` yields a
+/// `Tracked<'_, T>` that borrows through the pointer. `Track` is deliberately
+/// not implemented for the pointers themselves as that would track the
+/// pointer instead of the value.
 pub trait Track: Validate + Surfaces {
     /// Start tracking all accesses to a value.
     #[inline]
@@ -17,9 +25,21 @@ pub trait Track: Validate + Surfaces {
             value: self,
             constraint: None,
             id: accelerate::id(),
+            label: None,
         }
     }
 
+    /// Start tracking all accesses to a value and attach a label to it.
+    ///
+    /// The label has no effect on caching. It only shows up in the `Debug`
+    /// output of the `Tracked`, in the spans of the `tracing` feature and can
+    /// be retrieved with [`Tracked::label`]. This helps to distinguish multiple
+    /// tracked values of the same type while debugging.
+    #[inline]
+    fn track_labeled(&self, label: &'static str) -> Tracked<'_, Self> {
+        Tracked { label: Some(label), ..self.track() }
+    }
+
     /// Start tracking all accesses and mutations to a value.
     #[inline]
     fn track_mut(&mut self) -> TrackedMut<Self> {
@@ -33,6 +53,7 @@ pub trait Track: Validate + Surfaces {
             value: self,
             constraint: Some(constraint),
             id: accelerate::id(),
+            label: None,
         }
     }
 
@@ -77,6 +98,92 @@ pub trait Validate {
     fn replay(&mut self, constraint: &Self::Constraint);
 }
 
+/// The recorded accesses to a tracked value of type `T`.
+///
+/// This is a typed wrapper around `T`'s [`Validate::Constraint`] that makes it
+/// possible to build custom caching on top of comemo's tracking. Accesses are
+/// recorded by tracking a value with [`track_with`](Track::track_with) and a
+/// fresh value can later be checked against them with
+/// [`validate`](Self::validate).
+///
+/// ```ignore
+/// let constraint = Constraint::<Files>::new();
+/// let output = compute(files.track_with(&constraint));
+///
+/// // Later, with a possibly changed `files`.
+/// if constraint.validate(&files) {
+///     return output;
+/// }
+/// ```
+pub struct Constraint<T>(T::Constraint)
+where
+    T: Validate + ?Sized;
+
+impl<T> Constraint<T>
+where
+    T: Validate + ?Sized,
+{
+    /// Create an empty constraint.
+    #[inline]
+    pub fn new() -> Self {
+        Self(T::Constraint::default())
+    }
+
+    /// Whether the value yields the same results for all recorded accesses.
+    #[inline]
+    pub fn validate(&self, value: &T) -> bool {
+        value.validate(&self.0)
+    }
+
+    /// Replay recorded mutations to the value.
+    #[inline]
+    pub fn replay(&self, value: &mut T) {
+        value.replay(&self.0)
+    }
+}
+
+impl<T> Deref for Constraint<T>
+where
+    T: Validate + ?Sized,
+{
+    type Target = T::Constraint;
+
+    #[inline]
+    fn deref(&self) -> &Self::Target {
+        &self.0
+    }
+}
+
+impl<T> Default for Constraint<T>
+where
+    T: Validate + ?Sized,
+{
+    #[inline]
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl<T> Clone for Constraint<T>
+where
+    T: Validate + ?Sized,
+{
+    #[inline]
+    fn clone(&self) -> Self {
+        Self(self.0.clone())
+    }
+}
+
+impl<T> Debug for Constraint<T>
+where
+    T: Validate + ?Sized,
+{
+    #[inline]
+    fn fmt(&self, f: &mut Formatter) -> fmt::Result {
+        f.pad("Constraint(..)")
+    }
+}
+
 /// This type's tracked surfaces.
 pub trait Surfaces {
     /// The tracked API surface of this type.
@@ -144,6 +251,31 @@ pub trait Surfaces {
 /// Notice the `'static` lifetime: This makes the compiler understand that no
 /// strange business that depends on `'a` is happening in the associated
 /// constraint type. (In fact, all constraints are `'static`.)
+///
+/// ## Nesting in hashed arguments
+/// A `Tracked<T>` is only tracked if it is passed to a memoized function
+/// directly, as an `Option<Tracked<T>>` or as part of a [`Zip`](crate::Zip).
+/// It does not implement [`Hash`](std::hash::Hash), so it cannot be hidden in
+/// a hashed argument, where its tracking would be lost. Such an argument is
+/// rejected with an error that it must be hashable or tracked:
+///
+/// ```compile_fail
+/// # use comemo::{memoize, track, Tracked};
+/// # struct Files;
+/// # #[track]
+/// # impl Files {
+/// #     fn read(&self, path: &str) -> String { String::new() }
+/// # }
+/// struct Context<'a> {
+///     files: Tracked<'a, Files>,
+///     path: &'a str,
+/// }
+///
+/// #[memoize]
+/// fn load(context: Context) -> String {
+///     context.files.read(context.path)
+/// }
+/// ```
 pub struct Tracked<'a, T, C = <T as Validate>::Constraint>
 where
     T: Track + ?Sized,
@@ -158,6 +290,9 @@ where
     pub(crate) constraint: Option<&'a C>,
     /// A unique ID for validation acceleration.
     pub(crate) id: usize,
+    /// A label for debugging, attached with
+    /// [`track_labeled`](Track::track_labeled).
+    pub(crate) label: Option<&'static str>,
 }
 
 // The type `Tracked<T>` automatically dereferences to T's generated surface
@@ -175,13 +310,46 @@ where
     }
 }
 
+impl<'a, T> Tracked<'a, T>
+where
+    T: Track + ?Sized,
+{
+    /// The label attached with [`track_labeled`](Track::track_labeled), if any.
+    ///
+    /// This is an associated function as to not interfere with any methods
+    /// defined on `T`. It should be called as `Tracked::label(...)`.
+    #[inline]
+    pub fn label(this: &Self) -> Option<&'static str> {
+        this.label
+    }
+
+    /// Access the underlying value without tracking.
+    ///
+    /// **This breaks comemo's guarantees:** Accesses through the returned
+    /// reference are not recorded, so a memoized result that depends on them
+    /// may be reused even though it's stale. It is only intended for
+    /// observability, for instance to log the tracked value when diagnosing
+    /// why a cache keeps missing.
+    ///
+    /// This is an associated function as to not interfere with any methods
+    /// defined on `T`. It should be called as `Tracked::untrack(...)`.
+    #[inline]
+    pub fn untrack(this: Self) -> &'a T {
+        this.value
+    }
+}
+
 impl<T> Debug for Tracked<'_, T>
 where
     T: Track + ?Sized,
 {
     #[inline]
     fn fmt(&self, f: &mut Formatter) -> fmt::Result {
-        f.pad("Tracked(..)")
+        let name = std::any::type_name::<T>();
+        match Tracked::label(self) {
+            Some(label) => write!(f, "Tracked<{name}>({label:?})"),
+            None => write!(f, "Tracked<{name}>(..)"),
+        }
     }
 }
 
@@ -233,6 +401,7 @@ where
             value: this.value,
             constraint: this.constraint,
             id: accelerate::id(),
+            label: None,
         }
     }
 
@@ -246,6 +415,7 @@ where
             value: this.value,
             constraint: this.constraint,
             id: accelerate::id(),
+            label: None,
         }
     }
 
@@ -287,10 +457,45 @@ where
 {
     #[inline]
     fn fmt(&self, f: &mut Formatter) -> fmt::Result {
-        f.pad("TrackedMut(..)")
+        write!(f, "TrackedMut<{}>(..)", std::any::type_name::<T>())
+    }
+}
+
+/// An argument to a tracked method and its owned version, whose round-trip
+/// through `ToOwned` and `Borrow` is checked in debug builds.
+pub struct RoundTrip<'a, T: ?Sized, O>(pub &'a T, pub &'a O);
+
+/// Checks the round-trip of a hashable argument.
+pub trait CheckRoundTrip {
+    /// Panic if the owned argument borrows as a different value.
+    fn check(&self);
+}
+
+impl<T, O> CheckRoundTrip for RoundTrip<'_, T, O>
+where
+    T: Hash + ?Sized,
+    O: Borrow<T>,
+{
+    #[track_caller]
+    fn check(&self) {
+        let borrowed: &T = self.1.borrow();
+        if hash(&self.0) != hash(&borrowed) {
+            panic!(
+                "comemo: the `ToOwned` and `Borrow` implementations of a \
+                 tracked method argument do not round-trip"
+            );
+        }
     }
 }
 
+/// Skips the round-trip check for arguments that aren't hashable.
+pub trait SkipRoundTrip {
+    /// Do nothing.
+    fn check(&self) {}
+}
+
+impl<T: ?Sized, O> SkipRoundTrip for &RoundTrip<'_, T, O> {}
+
 /// Destructure a `Tracked<_>` into its parts.
 #[inline]
 pub fn to_parts_ref<T>(tracked: Tracked<T>) -> (&T, Option<&T::Constraint>)
@@ -0,0 +1,78 @@
+//! Tests that count allocations. They have their own target because the
+//! counting allocator replaces the global one.
+//!
+//! Run with `cargo test --all-features`.
+
+use std::alloc::{GlobalAlloc, Layout, System};
+use std::cell::Cell;
+
+use comemo::memoize;
+use serial_test::serial;
+
+macro_rules! test {
+    (miss: $call:expr, $result:expr) => {{
+        assert_eq!($call, $result);
+        assert!(!comemo::internal::last_was_hit());
+    }};
+    (hit: $call:expr, $result:expr) => {{
+        assert_eq!($call, $result);
+        assert!(comemo::internal::last_was_hit());
+    }};
+}
+
+#[global_allocator]
+static ALLOCATOR: CountingAllocator = CountingAllocator;
+
+thread_local! {
+    /// How many allocations happened on this thread.
+    static ALLOCATIONS: Cell<usize> = const { Cell::new(0) };
+}
+
+/// An allocator that counts the allocations on each thread.
+struct CountingAllocator;
+
+unsafe impl GlobalAlloc for CountingAllocator {
+    unsafe fn alloc(&self, layout: Layout) -> *mut u8 {
+        let _ = ALLOCATIONS.try_with(|cell| cell.set(cell.get() + 1));
+        System.alloc(layout)
+    }
+
+    unsafe fn dealloc(&self, ptr: *mut u8, layout: Layout) {
+        System.dealloc(ptr, layout)
+    }
+}
+
+/// Test that hashing a large borrowed argument does not allocate.
+#[test]
+#[serial]
+fn test_streamed_key() {
+    #[memoize]
+    fn sum(data: &[u64]) -> u64 {
+        data.iter().sum()
+    }
+
+    let data: Vec<u64> = (0..100_000).collect();
+    test!(miss: sum(&data), 4999950000);
+    let before = ALLOCATIONS.with(|cell| cell.get());
+    test!(hit: sum(&data), 4999950000);
+    assert_eq!(ALLOCATIONS.with(|cell| cell.get()), before);
+}
+
+/// Test that hits of a function returning an `Arc` share the cached output.
+#[test]
+#[serial]
+fn test_arc_output() {
+    use std::sync::Arc;
+
+    #[memoize]
+    fn tokens(n: u64) -> Arc<Vec<u64>> {
+        Arc::new((0..n).collect())
+    }
+
+    let first = tokens(10_000);
+    let before = ALLOCATIONS.with(|cell| cell.get());
+    let second = tokens(10_000);
+    assert!(comemo::internal::last_was_hit());
+    assert_eq!(ALLOCATIONS.with(|cell| cell.get()), before);
+    assert!(Arc::ptr_eq(&first, &second));
+}
@@ -1,10 +1,14 @@
 //! Run with `cargo test --all-features`.
 
-use std::collections::HashMap;
+use std::cell::Cell;
+use std::collections::{BTreeMap, HashMap};
 use std::hash::Hash;
 use std::path::{Path, PathBuf};
 
-use comemo::{evict, memoize, track, Track, Tracked, TrackedMut, Validate};
+use comemo::{
+    evict, memoize, track, Constraint, LazyPrehashed, Prehashed, Track, Tracked,
+    TrackedMut, Validate, Zip,
+};
 use serial_test::serial;
 
 macro_rules! test {
@@ -118,6 +122,844 @@ impl Files {
     }
 }
 
+/// Test detecting whether a recomputed output changed.
+#[test]
+#[serial]
+fn test_cached_eq() {
+    #[memoize(cached_eq)]
+    fn line_count(path: &str, files: Tracked<Files>) -> usize {
+        files.read(path).lines().count()
+    }
+
+    let mut files = Files(HashMap::new());
+    files.write("a.txt", "one\ntwo");
+    test!(miss: line_count_cached_eq("a.txt", files.track()), (2, true));
+    files.write("b.txt", "three");
+    test!(hit: line_count_cached_eq("a.txt", files.track()), (2, false));
+    test!(hit: line_count("a.txt", files.track()), 2);
+
+    // The entry for the old contents isn't valid for the new ones, so it isn't
+    // replaced and there is nothing to compare with.
+    files.write("a.txt", "uno\ndos");
+    test!(miss: line_count_cached_eq("a.txt", files.track()), (2, true));
+    files.write("a.txt", "one");
+    test!(miss: line_count_cached_eq("a.txt", files.track()), (1, true));
+    test!(hit: line_count("a.txt", files.track()), 1);
+}
+
+/// Test caching only successful results.
+#[test]
+#[serial]
+fn test_result() {
+    /// An error that can't be cloned.
+    #[derive(Debug, PartialEq)]
+    struct NotANumber(std::rc::Rc<str>);
+
+    #[memoize(result)]
+    fn parse(text: &str) -> Result<u32, NotANumber> {
+        text.parse().map_err(|_| NotANumber(text.into()))
+    }
+
+    test!(miss: parse("12"), Ok(12));
+    test!(hit: parse("12"), Ok(12));
+    test!(miss: parse("x"), Err(NotANumber("x".into())));
+    test!(miss: parse("x"), Err(NotANumber("x".into())));
+}
+
+/// Test that concurrent identical calls execute the function only once.
+#[test]
+#[serial]
+fn test_single_flight() {
+    use std::sync::atomic::{AtomicUsize, Ordering};
+    use std::sync::Barrier;
+
+    static EXECUTIONS: AtomicUsize = AtomicUsize::new(0);
+
+    #[memoize]
+    fn slow(n: u64) -> u64 {
+        EXECUTIONS.fetch_add(1, Ordering::SeqCst);
+        std::thread::sleep(std::time::Duration::from_millis(50));
+        n * 2
+    }
+
+    let barrier = Barrier::new(8);
+    std::thread::scope(|s| {
+        for _ in 0..8 {
+            s.spawn(|| {
+                barrier.wait();
+                assert_eq!(slow(21), 42);
+            });
+        }
+    });
+
+    assert_eq!(EXECUTIONS.load(Ordering::SeqCst), 1);
+}
+
+/// Test the alternative spelling of `result`.
+#[test]
+#[serial]
+fn test_cache_errors() {
+    #[memoize(cache_errors = false)]
+    fn load(path: &str) -> Result<usize, String> {
+        if path.ends_with(".busy") {
+            Err(format!("{path} is busy"))
+        } else {
+            Ok(path.len())
+        }
+    }
+
+    test!(miss: load("a.txt"), Ok(5));
+    test!(hit: load("a.txt"), Ok(5));
+    test!(miss: load("a.busy"), Err("a.busy is busy".into()));
+    test!(miss: load("a.busy"), Err("a.busy is busy".into()));
+}
+
+/// Test that instantiations of generic functions don't share entries.
+#[test]
+#[serial]
+fn test_generic() {
+    trait Unit {
+        const FACTOR: u32;
+    }
+
+    struct Meters;
+    impl Unit for Meters {
+        const FACTOR: u32 = 1;
+    }
+
+    struct Millimeters;
+    impl Unit for Millimeters {
+        const FACTOR: u32 = 1000;
+    }
+
+    #[memoize(refresh)]
+    fn scale<T: Unit + 'static>(x: u32) -> u32 {
+        x * T::FACTOR
+    }
+
+    test!(miss: scale::<Meters>(2), 2);
+    test!(miss: scale::<Millimeters>(2), 2000);
+    test!(hit: scale::<Meters>(2), 2);
+    test!(hit: scale::<Millimeters>(2), 2000);
+    test!(miss: scale_refresh::<Meters>(2), 2);
+}
+
+/// Test memoizing a function with multiple outputs.
+#[test]
+#[serial]
+fn test_outputs() {
+    use std::sync::atomic::{AtomicUsize, Ordering};
+
+    static RUNS: AtomicUsize = AtomicUsize::new(0);
+
+    #[memoize(outputs(words, lengths))]
+    fn split(text: &str) -> (Vec<String>, Vec<usize>) {
+        RUNS.fetch_add(1, Ordering::SeqCst);
+        let words: Vec<String> = text.split(' ').map(Into::into).collect();
+        let lengths = words.iter().map(String::len).collect();
+        (words, lengths)
+    }
+
+    test!(miss: split_lengths("a bc"), [1, 2]);
+    test!(hit: split_words("a bc"), ["a", "bc"]);
+    test!(hit: split("a bc"), (vec!["a".into(), "bc".into()], vec![1, 2]));
+    test!(miss: split_words("d"), ["d"]);
+    assert_eq!(RUNS.load(Ordering::SeqCst), 2);
+}
+
+/// Test memoizing a function returning an iterator.
+#[test]
+#[serial]
+fn test_collect() {
+    #[memoize(collect)]
+    fn chars(text: &str) -> impl Iterator<Item = char> + '_ {
+        text.chars().filter(|c| !c.is_whitespace())
+    }
+
+    #[memoize(collect)]
+    fn evens(n: u32) -> impl IntoIterator<Item = u32> {
+        if n == 0 {
+            return vec![];
+        }
+        (0..n).filter(|x| x % 2 == 0).collect()
+    }
+
+    test!(miss: *chars("a b"), ['a', 'b']);
+    test!(hit: *chars("a b"), ['a', 'b']);
+    test!(miss: *evens(5), [0, 2, 4]);
+    test!(miss: evens(0).len(), 0);
+    let first = evens(5);
+    assert!(std::sync::Arc::ptr_eq(&first, &evens(5)));
+}
+
+/// Test memoizing a function with more than twelve arguments.
+#[test]
+#[serial]
+fn test_many_args() {
+    #[memoize]
+    #[allow(clippy::too_many_arguments)]
+    fn many(
+        a: u8,
+        b: u8,
+        c: u8,
+        d: u8,
+        e: u8,
+        f: u8,
+        g: u8,
+        h: u8,
+        i: u8,
+        j: u8,
+        k: u8,
+        l: u8,
+        files: Tracked<Files>,
+        emit: &mut dyn FnMut(u8),
+    ) -> usize {
+        emit(l);
+        let sum: usize =
+            [a, b, c, d, e, f, g, h, i, j, k, l].iter().map(|&x| x as usize).sum();
+        files.read("many.calc").len() + sum
+    }
+
+    let mut files = Files(HashMap::new());
+    files.write("many.calc", "abc");
+    let mut emitted = vec![];
+    let mut call = |l, files: &Files| {
+        many(1, 1, 1, 1, 1, 1, 1, 1, 1, 1, 1, l, files.track(), &mut |x| emitted.push(x))
+    };
+    test!(miss: call(1, &files), 15);
+    test!(hit: call(1, &files), 15);
+    test!(miss: call(2, &files), 16);
+    files.write("many.calc", "ab");
+    test!(miss: call(2, &files), 15);
+    assert_eq!(emitted, [1, 1, 2, 2]);
+}
+
+/// Test computing the cache key of a call.
+#[test]
+#[serial]
+fn test_key() {
+    #[memoize(key)]
+    fn keyed(text: &str, files: Tracked<Files>) -> usize {
+        files.read(text).len()
+    }
+
+    let mut files = Files(HashMap::new());
+    files.write("keyed.calc", "1 + 1");
+    let key = keyed_key("keyed.calc", files.track());
+    assert_eq!(keyed_key("keyed.calc", files.track()), key);
+    files.write("keyed.calc", "2");
+    assert_eq!(keyed_key("keyed.calc", files.track()), key);
+    assert_ne!(keyed_key("other.calc", files.track()), key);
+    test!(miss: keyed("keyed.calc", files.track()), 1);
+}
+
+/// Test bounding the number of cached results.
+#[test]
+#[serial]
+fn test_capacity() {
+    #[memoize(capacity = 2)]
+    fn triple(x: u32) -> u32 {
+        3 * x
+    }
+
+    test!(miss: triple(1), 3);
+    test!(miss: triple(2), 6);
+    test!(hit: triple(1), 3);
+    test!(miss: triple(3), 9);
+    test!(hit: triple(1), 3);
+    test!(hit: triple(3), 9);
+    test!(miss: triple(2), 6);
+    test!(miss: triple(1), 3);
+}
+
+/// Test leaving an argument out of the key.
+#[test]
+#[serial]
+fn test_ignore() {
+    use std::sync::atomic::{AtomicUsize, Ordering};
+
+    static LOGGED: AtomicUsize = AtomicUsize::new(0);
+
+    /// Settings that don't affect the result.
+    struct Verbosity(u8);
+
+    #[memoize(ignore = verbosity)]
+    fn count(text: &str, verbosity: &Verbosity) -> usize {
+        if verbosity.0 > 0 {
+            LOGGED.fetch_add(1, Ordering::SeqCst);
+        }
+        text.len()
+    }
+
+    test!(miss: count("abc", &Verbosity(0)), 3);
+    test!(hit: count("abc", &Verbosity(1)), 3);
+    assert_eq!(LOGGED.load(Ordering::SeqCst), 0);
+    test!(miss: count("abcd", &Verbosity(1)), 4);
+    assert_eq!(LOGGED.load(Ordering::SeqCst), 1);
+}
+
+/// Test computing keys with a custom hasher.
+#[test]
+#[serial]
+fn test_hasher() {
+    use std::sync::atomic::{AtomicUsize, Ordering};
+
+    static CREATED: AtomicUsize = AtomicUsize::new(0);
+
+    /// A 128-bit FNV-1a hasher.
+    struct Fnv(u128);
+
+    impl Default for Fnv {
+        fn default() -> Self {
+            CREATED.fetch_add(1, Ordering::SeqCst);
+            Self(0x6c62272e07bb014262b821756295c58d)
+        }
+    }
+
+    impl std::hash::Hasher for Fnv {
+        fn write(&mut self, bytes: &[u8]) {
+            for &byte in bytes {
+                self.0 =
+                    (self.0 ^ u128::from(byte)).wrapping_mul(0x1000000000000000000013b);
+            }
+        }
+
+        fn finish(&self) -> u64 {
+            self.0 as u64
+        }
+    }
+
+    impl comemo::KeyHasher for Fnv {
+        fn finish128(self) -> u128 {
+            self.0
+        }
+    }
+
+    #[memoize(hasher = Fnv)]
+    fn length(text: &str) -> usize {
+        text.len()
+    }
+
+    test!(miss: length("hello"), 5);
+    test!(hit: length("hello"), 5);
+    test!(miss: length("world!"), 6);
+    assert_eq!(CREATED.load(Ordering::SeqCst), 3);
+}
+
+/// Test memoizing an asynchronous function.
+#[test]
+#[serial]
+fn test_async() {
+    use std::future::Future;
+    use std::pin::pin;
+    use std::task::{Context, Poll, Waker};
+
+    /// Drive a future to completion on the current thread.
+    fn block_on<F: Future>(future: F) -> F::Output {
+        let mut future = pin!(future);
+        let mut cx = Context::from_waker(Waker::noop());
+        loop {
+            if let Poll::Ready(output) = future.as_mut().poll(&mut cx) {
+                return output;
+            }
+        }
+    }
+
+    #[memoize]
+    async fn load(path: &str, fs: Tracked<'_, Files>) -> String {
+        std::future::ready(()).await;
+        fs.read(path).to_uppercase()
+    }
+
+    let mut fs = Files(HashMap::new());
+    fs.write("a.txt", "hello");
+    test!(miss: block_on(load("a.txt", fs.track())), "HELLO");
+    test!(hit: block_on(load("a.txt", fs.track())), "HELLO");
+    fs.write("a.txt", "world");
+    test!(miss: block_on(load("a.txt", fs.track())), "WORLD");
+}
+
+/// Test forcibly refreshing a stale result.
+#[test]
+#[serial]
+fn test_refresh() {
+    use std::sync::atomic::{AtomicU32, Ordering};
+
+    static UNTRACKED: AtomicU32 = AtomicU32::new(1);
+
+    #[memoize(refresh)]
+    fn scaled(x: u32) -> u32 {
+        x * UNTRACKED.load(Ordering::SeqCst)
+    }
+
+    test!(miss: scaled(2), 2);
+    UNTRACKED.store(3, Ordering::SeqCst);
+    test!(hit: scaled(2), 2);
+    test!(miss: scaled_refresh(2), 6);
+    test!(hit: scaled(2), 6);
+    test!(hit: scaled(2), 6);
+}
+
+/// Test that refreshing a call doesn't refresh the memoized calls that are made
+/// while evaluating its arguments or its condition.
+#[test]
+#[serial]
+fn test_refresh_nested() {
+    use std::sync::atomic::{AtomicU32, Ordering};
+
+    static UNTRACKED: AtomicU32 = AtomicU32::new(1);
+
+    #[memoize]
+    fn factor(x: u32) -> u32 {
+        x * UNTRACKED.load(Ordering::SeqCst)
+    }
+
+    #[memoize(refresh, enabled = factor(0) == 0)]
+    fn shifted(x: u32) -> u32 {
+        x + UNTRACKED.load(Ordering::SeqCst)
+    }
+
+    test!(miss: factor(2), 2);
+    test!(miss: shifted(2), 3);
+    UNTRACKED.store(5, Ordering::SeqCst);
+    test!(miss: shifted_refresh(factor(2)), 7);
+    test!(hit: factor(2), 2);
+    test!(hit: factor(0), 0);
+    test!(hit: shifted(2), 7);
+}
+
+/// Test memoizing a function whose output isn't thread-safe.
+#[test]
+#[serial]
+fn test_assert_single_threaded() {
+    use std::rc::Rc;
+
+    #[memoize(assert_single_threaded)]
+    fn boxed(x: u32) -> Rc<u32> {
+        Rc::new(x * 2)
+    }
+
+    test!(miss: boxed(2), Rc::new(4));
+    test!(hit: boxed(2), Rc::new(4));
+    assert!(std::thread::spawn(|| *boxed(2)).join().is_err());
+    evict(0);
+    test!(miss: boxed(2), Rc::new(4));
+}
+
+/// Test that touching a single-threaded cache from another thread before the
+/// first call doesn't claim it.
+#[test]
+#[serial]
+fn test_assert_single_threaded_handle() {
+    use std::rc::Rc;
+
+    #[memoize(assert_single_threaded, handle)]
+    fn shared(x: u32) -> Rc<u32> {
+        Rc::new(x + 1)
+    }
+
+    let cleared = std::thread::spawn(|| shared_cache().clear()).join().unwrap();
+    assert_eq!(cleared, 0);
+    test!(miss: shared(2), Rc::new(3));
+    test!(hit: shared(2), Rc::new(3));
+    assert_eq!(shared_cache().clear(), 1);
+}
+
+/// Test exporting the dependencies of cached results.
+#[test]
+#[serial]
+fn test_export() {
+    #[memoize]
+    fn concat(a: &str, b: &str, files: Tracked<Files>) -> String {
+        files.read(a) + &files.read(b)
+    }
+
+    let mut files = Files(HashMap::new());
+    files.write("a.txt", "hi");
+    files.write("b.txt", "!");
+    test!(miss: concat("a.txt", "b.txt", files.track()), "hi!");
+    test!(miss: concat("b.txt", "b.txt", files.track()), "!!");
+
+    let mut exports: Vec<_> = comemo::export()
+        .into_iter()
+        .filter(|export| export.function == "concat")
+        .collect();
+    exports.sort_by_key(|export| export.calls.len());
+    assert_eq!(exports.len(), 2);
+    assert_eq!(exports[0].calls.len(), 1);
+    assert_eq!(exports[1].calls.len(), 2);
+    assert!(exports
+        .iter()
+        .flat_map(|export| &export.calls)
+        .all(|(label, _)| label.name == "read"));
+}
+
+/// Test counting the recorded calls of an in-flight memoized call.
+#[test]
+#[serial]
+fn test_current_recording_len() {
+    #[memoize]
+    fn inner(files: Tracked<Files>) -> usize {
+        files.read("c.txt");
+        comemo::current_recording_len()
+    }
+
+    #[memoize]
+    fn outer(files: Tracked<Files>) -> (usize, usize, usize) {
+        for _ in 0..100 {
+            files.read("a.txt");
+            files.read("b.txt");
+        }
+        let before = comemo::current_recording_len();
+        let nested = inner(files);
+        (before, nested, comemo::current_recording_len())
+    }
+
+    let files = Files(HashMap::new());
+    test!(miss: outer(files.track()), (2, 1, 3));
+    assert_eq!(comemo::current_recording_len(), 0);
+}
+
+/// Test reporting functions whose results are never reused.
+#[test]
+#[serial]
+fn test_overhead_report() {
+    #[memoize]
+    fn unique(x: u32) -> u32 {
+        x + 1
+    }
+
+    #[memoize]
+    fn reused(x: u32) -> u32 {
+        x + 2
+    }
+
+    let reported = |name| {
+        comemo::overhead_report(3)
+            .into_iter()
+            .find(|overhead| overhead.function == name)
+            .map(|overhead| overhead.entries)
+    };
+
+    evict(0);
+    for i in 0..5 {
+        test!(miss: unique(i), i + 1);
+        test!(miss: reused(i), i + 2);
+    }
+    test!(hit: reused(0), 2);
+    assert_eq!(reported("unique"), Some(5));
+    assert_eq!(reported("reused"), None);
+    evict(0);
+    test!(miss: unique(0), 1);
+    assert_eq!(reported("unique"), None);
+}
+
+/// Test collecting per-function cache statistics.
+#[test]
+#[serial]
+fn test_cache_stats() {
+    #[memoize]
+    fn halve(x: u32) -> u32 {
+        x / 2
+    }
+
+    let stats = || {
+        comemo::cache_stats()
+            .into_iter()
+            .find(|stats| stats.function == "halve")
+            .map(|stats| (stats.hits, stats.misses, stats.entries, stats.reused))
+    };
+
+    assert_eq!(stats(), None);
+    test!(miss: halve(4), 2);
+    test!(miss: halve(6), 3);
+    test!(hit: halve(4), 2);
+    test!(hit: halve(4), 2);
+    assert_eq!(stats(), Some((2, 2, 2, 1)));
+    assert!(evict(0) >= 2);
+    assert_eq!(evict(0), 0);
+    assert_eq!(stats(), Some((2, 2, 0, 0)));
+}
+
+/// Test generating an unmemoized twin.
+#[test]
+#[serial]
+fn test_emit_unmemoized() {
+    #[memoize(emit_unmemoized = evaluate_raw)]
+    fn evaluate(script: &str, files: Tracked<Files>) -> i32 {
+        script
+            .split('+')
+            .map(str::trim)
+            .map(|part| match part.strip_prefix("eval ") {
+                Some(path) => evaluate(&files.read(path), files),
+                None => part.parse::<i32>().unwrap(),
+            })
+            .sum()
+    }
+
+    let mut files = Files(HashMap::new());
+    files.write("alpha.calc", "2 + eval beta.calc");
+    files.write("beta.calc", "2 + 3");
+    test!(miss: evaluate_raw("eval alpha.calc", files.track()), 7);
+    test!(miss: evaluate("eval alpha.calc", files.track()), 7);
+    files.write("beta.calc", "4");
+    assert_eq!(
+        evaluate("eval alpha.calc", files.track()),
+        evaluate_raw("eval alpha.calc", files.track()),
+    );
+}
+
+/// Test that clearing the accelerators keeps memoized results.
+#[test]
+#[serial]
+fn test_clear_accelerators() {
+    let mut files = Files(HashMap::new());
+    files.write("a.calc", "1 + eval b.calc");
+    files.write("b.calc", "2");
+    let tracked = files.track();
+    test!(miss: evaluate("eval a.calc", tracked), 3);
+    test!(hit: evaluate("eval a.calc", tracked), 3);
+    comemo::clear_accelerators();
+    test!(hit: evaluate("eval a.calc", tracked), 3);
+    test!(hit: evaluate("eval a.calc", files.track()), 3);
+}
+
+/// Test limiting the number of accelerators.
+#[test]
+#[serial]
+fn test_accelerator_limit() {
+    comemo::set_accelerator_limit(1);
+    let mut files = Files(HashMap::new());
+    files.write("a.calc", "1 + eval b.calc");
+    files.write("b.calc", "2");
+    assert_eq!(evaluate("eval a.calc", files.track()), 3);
+    for _ in 0..3 {
+        test!(hit: evaluate("2", files.track()), 2);
+        test!(hit: evaluate("eval a.calc", files.track()), 3);
+    }
+    files.write("b.calc", "4");
+    test!(miss: evaluate("eval a.calc", files.track()), 5);
+    comemo::set_accelerator_limit(usize::MAX);
+}
+
+/// Test bounding the total size of the accelerators.
+#[test]
+#[serial]
+fn test_accelerator_capacity() {
+    comemo::set_accelerator_capacity(1);
+    let mut files = Files(HashMap::new());
+    files.write("p.calc", "1 + eval q.calc");
+    files.write("q.calc", "2");
+    test!(miss: evaluate("eval p.calc", files.track()), 3);
+    for _ in 0..3 {
+        test!(hit: evaluate("eval p.calc", files.track()), 3);
+        test!(hit: evaluate("1 + eval q.calc", files.track()), 3);
+    }
+    files.write("q.calc", "4");
+    test!(miss: evaluate("eval p.calc", files.track()), 5);
+    comemo::set_accelerator_capacity(usize::MAX);
+}
+
+/// Test disabling the acceleration of constraint validation.
+#[test]
+#[serial]
+fn test_disable_acceleration() {
+    comemo::set_acceleration(false);
+    let mut files = Files(HashMap::new());
+    files.write("x.calc", "1 + eval y.calc");
+    files.write("y.calc", "2");
+    test!(miss: evaluate("eval x.calc", files.track()), 3);
+    test!(hit: evaluate("eval x.calc", files.track()), 3);
+    files.write("y.calc", "4");
+    test!(miss: evaluate("eval x.calc", files.track()), 5);
+    comemo::set_acceleration(true);
+}
+
+/// Test disabling memoization for the extent of a closure.
+#[test]
+#[serial]
+fn test_without_cache() {
+    let mut files = Files(HashMap::new());
+    files.write("a.calc", "1 + eval b.calc");
+    files.write("b.calc", "2");
+    assert_eq!(evaluate("eval a.calc", files.track()), 3);
+    test!(hit: evaluate("eval a.calc", files.track()), 3);
+    comemo::without_cache(|| {
+        test!(miss: evaluate("eval a.calc", files.track()), 3);
+        test!(miss: evaluate("2", files.track()), 2);
+    });
+    test!(hit: evaluate("eval a.calc", files.track()), 3);
+}
+
+/// Test that a panic in a closure without caching doesn't leave caching off.
+#[test]
+#[serial]
+fn test_without_cache_panic() {
+    #[memoize]
+    fn halve(x: u32) -> u32 {
+        x / 2
+    }
+
+    test!(miss: halve(8), 4);
+    let result = std::panic::catch_unwind(|| {
+        comemo::without_cache(|| {
+            halve(8);
+            panic!("interrupted");
+        })
+    });
+    assert!(result.is_err());
+    test!(hit: halve(8), 4);
+}
+
+/// Test clearing all caches.
+#[test]
+#[serial]
+fn test_clear() {
+    #[memoize]
+    fn negate(x: i32) -> i32 {
+        -x
+    }
+
+    test!(miss: negate(1), -1);
+    test!(hit: negate(1), -1);
+    assert!(comemo::clear() >= 1);
+    test!(miss: negate(1), -1);
+    test!(hit: negate(1), -1);
+}
+
+/// Test evicting the cache of a single function through its handle.
+#[test]
+#[serial]
+fn test_cache_handle() {
+    #[memoize(handle)]
+    fn first(x: u8) -> u8 {
+        x
+    }
+
+    #[memoize]
+    fn second(x: u8) -> u8 {
+        x
+    }
+
+    test!(miss: first(1), 1);
+    test!(miss: second(1), 1);
+    assert_eq!(first_cache().evict(1), 0);
+    test!(hit: first(1), 1);
+    assert_eq!(first_cache().clear(), 1);
+    test!(miss: first(1), 1);
+    test!(hit: second(1), 1);
+}
+
+/// Test inserting outputs without executing the function.
+#[test]
+#[serial]
+fn test_prime() {
+    #[memoize(prime)]
+    fn square(x: u32) -> u32 {
+        x * x
+    }
+
+    square_prime(3, 10);
+    test!(hit: square(3), 10);
+    test!(miss: square(4), 16);
+    square_prime(4, 17);
+    test!(hit: square(4), 17);
+}
+
+/// Test looking up outputs without executing the function.
+#[test]
+#[serial]
+fn test_peek() {
+    #[memoize(peek)]
+    fn shout(path: &str, files: Tracked<Files>) -> String {
+        files.read(path).to_uppercase()
+    }
+
+    let mut files = Files(HashMap::new());
+    files.write("a.txt", "hi");
+    assert_eq!(shout_peek("a.txt", files.track()), None);
+    test!(miss: shout("a.txt", files.track()), "HI");
+    assert_eq!(shout_peek("a.txt", files.track()), Some("HI".into()));
+    files.write("a.txt", "ho");
+    assert_eq!(shout_peek("a.txt", files.track()), None);
+    test!(miss: shout("a.txt", files.track()), "HO");
+}
+
+/// Test detecting recursive calls with the same key.
+#[test]
+#[serial]
+fn test_detect_cycles() {
+    #[memoize(detect_cycles)]
+    fn eval_path(path: &str, files: Tracked<Files>) -> i32 {
+        let script = files.read(path);
+        match script.strip_prefix("eval ") {
+            Some(path) => eval_path(path, files),
+            None => script.parse().unwrap(),
+        }
+    }
+
+    let mut files = Files(HashMap::new());
+    files.write("a.calc", "eval b.calc");
+    files.write("b.calc", "eval c.calc");
+    files.write("c.calc", "2");
+    test!(miss: eval_path("a.calc", files.track()), 2);
+    test!(hit: eval_path("a.calc", files.track()), 2);
+
+    files.write("c.calc", "eval a.calc");
+    let result = std::panic::catch_unwind(|| eval_path("a.calc", files.track()));
+    let message = *result.unwrap_err().downcast::<String>().unwrap();
+    assert!(message.contains("detected a cycle"), "{message}");
+
+    files.write("c.calc", "3");
+    test!(miss: eval_path("a.calc", files.track()), 3);
+}
+
+/// Test that a panic in a tracked method doesn't break later calls.
+#[test]
+#[serial]
+fn test_panic_in_tracked_method() {
+    #[memoize]
+    fn unwrap_fragile(fragile: Tracked<Fragile>) -> u8 {
+        fragile.get()
+    }
+
+    test!(miss: unwrap_fragile(Fragile(Some(1)).track()), 1);
+    let result = std::panic::catch_unwind(|| unwrap_fragile(Fragile(None).track()));
+    assert!(result.is_err());
+    test!(hit: unwrap_fragile(Fragile(Some(1)).track()), 1);
+    test!(miss: unwrap_fragile(Fragile(Some(2)).track()), 2);
+}
+
+struct Fragile(Option<u8>);
+
+#[track]
+impl Fragile {
+    fn get(&self) -> u8 {
+        self.0.expect("fragile value is missing")
+    }
+}
+
+/// Test counting the hits and misses of a block of calls.
+#[test]
+#[serial]
+fn test_hit_and_miss_counts() {
+    #[memoize]
+    fn halve(x: u32) -> u32 {
+        x / 2
+    }
+
+    comemo::reset_counts();
+    for x in [1, 2, 3, 1, 2, 1] {
+        halve(x);
+    }
+    assert_eq!(comemo::miss_count(), 3);
+    assert_eq!(comemo::hit_count(), 3);
+
+    comemo::reset_counts();
+    assert_eq!(comemo::miss_count(), 0);
+    assert_eq!(comemo::hit_count(), 0);
+}
+
 /// Test cache eviction.
 #[test]
 #[serial]
@@ -144,6 +986,359 @@ fn test_evict() {
     test!(hit: null(), 0);
 }
 
+/// Test that an entry that is hit between all evictions is never evicted.
+#[test]
+#[serial]
+fn test_evict_hot_entry() {
+    #[memoize]
+    fn hot() -> u8 {
+        1
+    }
+
+    test!(miss: hot(), 1);
+    for _ in 0..1000 {
+        evict(1);
+        test!(hit: hot(), 1);
+    }
+}
+
+/// Test evicting with function-specific maximum ages.
+#[test]
+#[serial]
+fn test_max_age() {
+    #[memoize(max_age = 2)]
+    fn retained(x: u32) -> u32 {
+        x + 1
+    }
+
+    #[memoize]
+    fn defaulted(x: u32) -> u32 {
+        x + 2
+    }
+
+    comemo::set_default_max_age(0);
+    test!(miss: retained(1), 2);
+    test!(miss: defaulted(1), 3);
+    comemo::evict_configured();
+    comemo::evict_configured();
+    test!(hit: retained(1), 2);
+    test!(miss: defaulted(1), 3);
+    comemo::evict_configured();
+    comemo::evict_configured();
+    comemo::evict_configured();
+    test!(miss: retained(1), 2);
+    comemo::set_default_max_age(usize::MAX);
+}
+
+/// Test retaining a global budget of the most recently used results.
+#[test]
+#[serial]
+fn test_evict_to() {
+    #[memoize]
+    fn negate(x: i32) -> i32 {
+        -x
+    }
+
+    #[memoize]
+    fn halve_signed(x: i32) -> i32 {
+        x / 2
+    }
+
+    evict(0);
+    test!(miss: negate(1), -1);
+    test!(miss: halve_signed(4), 2);
+    test!(miss: negate(2), -2);
+    test!(miss: halve_signed(6), 3);
+    test!(hit: negate(1), -1);
+    assert_eq!(comemo::evict_to(4), 0);
+    assert_eq!(comemo::evict_to(2), 2);
+    test!(hit: negate(1), -1);
+    test!(hit: halve_signed(6), 3);
+    test!(miss: negate(2), -2);
+    test!(miss: halve_signed(4), 2);
+}
+
+/// Test observing the outputs of evicted entries.
+#[test]
+#[serial]
+fn test_on_evict() {
+    static EVICTED: std::sync::Mutex<Vec<u32>> = std::sync::Mutex::new(Vec::new());
+
+    fn record(output: &u32) {
+        EVICTED.lock().unwrap().push(*output);
+    }
+
+    #[memoize(capacity = 2, on_evict = record, handle)]
+    fn triple(x: u32) -> u32 {
+        3 * x
+    }
+
+    test!(miss: triple(1), 3);
+    test!(miss: triple(2), 6);
+    test!(miss: triple(3), 9);
+    assert_eq!(*EVICTED.lock().unwrap(), [3]);
+
+    test!(hit: triple(3), 9);
+    triple_cache().evict(0);
+    EVICTED.lock().unwrap().sort();
+    assert_eq!(*EVICTED.lock().unwrap(), [3, 6, 9]);
+}
+
+/// Test prewarming a cache from multiple threads.
+#[test]
+#[serial]
+fn test_parallel_prewarm() {
+    #[memoize]
+    fn collatz(n: u64, files: Tracked<Files>) -> u64 {
+        let offset: u64 = files.read("offset").parse().unwrap();
+        match n {
+            0 | 1 => offset,
+            n if n.is_multiple_of(2) => 1 + collatz(n / 2, files),
+            n => 1 + collatz(3 * n + 1, files),
+        }
+    }
+
+    let mut files = Files(HashMap::new());
+    files.write("offset", "10");
+
+    std::thread::scope(|scope| {
+        for i in 0..8 {
+            let files = &files;
+            scope.spawn(move || {
+                for n in (i * 10..i * 10 + 30).rev() {
+                    collatz(n, files.track());
+                }
+            });
+        }
+    });
+
+    let expected = |mut n: u64| {
+        let mut steps = 10;
+        while n > 1 {
+            n = if n.is_multiple_of(2) { n / 2 } else { 3 * n + 1 };
+            steps += 1;
+        }
+        steps
+    };
+
+    for n in 0..100 {
+        test!(hit: collatz(n, files.track()), expected(n));
+    }
+}
+
+/// Test warming up a cache with overlapping calls.
+#[test]
+#[serial]
+fn test_warm() {
+    use std::sync::atomic::{AtomicUsize, Ordering};
+
+    static EXECUTIONS: AtomicUsize = AtomicUsize::new(0);
+
+    #[memoize]
+    fn cube(x: u64) -> u64 {
+        EXECUTIONS.fetch_add(1, Ordering::SeqCst);
+        x * x * x
+    }
+
+    comemo::warm((0..200).map(|i| move || assert_eq!(cube(i % 50), (i % 50).pow(3))));
+    assert_eq!(EXECUTIONS.load(Ordering::SeqCst), 50);
+    for x in 0..50 {
+        test!(hit: cube(x), x.pow(3));
+    }
+}
+
+/// Test that configuring comemo after the first memoized call panics.
+#[test]
+#[serial]
+#[should_panic(expected = "`init` must be called before the first memoized call")]
+fn test_init_after_use() {
+    #[memoize]
+    fn square(x: u64) -> u64 {
+        x * x
+    }
+
+    test!(miss: square(7), 49);
+    comemo::init(comemo::Config { caches_capacity: 64, ..Default::default() });
+}
+
+/// Test swapping eviction policies.
+#[test]
+#[serial]
+fn test_eviction_policy() {
+    #[memoize]
+    fn global() -> u8 {
+        1
+    }
+
+    #[memoize(policy = comemo::LfuPolicy { min_hits: 1 })]
+    fn frequent() -> u8 {
+        2
+    }
+
+    evict(0);
+    comemo::set_eviction_policy(comemo::LfuPolicy { min_hits: 1 });
+    test!(miss: global(), 1);
+    evict(0);
+    test!(miss: global(), 1);
+    test!(hit: global(), 1);
+    evict(0);
+    test!(hit: global(), 1);
+
+    comemo::set_eviction_policy(comemo::LruPolicy(std::time::Duration::ZERO));
+    evict(5);
+    test!(miss: global(), 1);
+
+    comemo::set_eviction_policy(comemo::AgePolicy);
+    test!(miss: frequent(), 2);
+    test!(hit: frequent(), 2);
+    evict(0);
+    test!(hit: frequent(), 2);
+    test!(miss: global(), 1);
+
+    // Time-based eviction overrides the function-specific policy.
+    comemo::evict_older_than(std::time::Duration::from_secs(60));
+    test!(hit: frequent(), 2);
+    test!(hit: global(), 1);
+    comemo::evict_older_than(std::time::Duration::ZERO);
+    test!(miss: frequent(), 2);
+    test!(miss: global(), 1);
+}
+
+/// Test that tracked values are formatted with their type.
+#[test]
+fn test_tracked_debug() {
+    let mut files = Files(HashMap::new());
+    assert_eq!(format!("{:?}", files.track()), "Tracked<tests::Files>(..)");
+    assert_eq!(format!("{:?}", files.track_mut()), "TrackedMut<tests::Files>(..)");
+    let shape: &dyn Shape = &Circle;
+    assert_eq!(format!("{:?}", shape.track()), "Tracked<dyn tests::Shape>(..)");
+}
+
+/// Test attaching labels to tracked values.
+#[test]
+#[serial]
+fn test_track_labeled() {
+    let files = Files(HashMap::new());
+    let tracked = files.track_labeled("sources");
+    assert_eq!(Tracked::label(&tracked), Some("sources"));
+    assert_eq!(format!("{tracked:?}"), "Tracked<tests::Files>(\"sources\")");
+    assert_eq!(Tracked::label(&files.track()), None);
+    assert_eq!(format!("{:?}", files.track()), "Tracked<tests::Files>(..)");
+    test!(miss: evaluate("1", tracked), 1);
+    test!(hit: evaluate("1", files.track()), 1);
+    evict(0);
+    assert_eq!(Tracked::label(&tracked), Some("sources"));
+}
+
+/// Test recording and checking constraints manually.
+#[test]
+#[serial]
+fn test_manual_constraint() {
+    let mut files = Files(HashMap::new());
+    files.write("a.txt", "hi");
+    let constraint = Constraint::<Files>::new();
+    assert_eq!(files.track_with(&constraint).read("a.txt"), "hi");
+    assert!(constraint.validate(&files));
+    files.write("b.txt", "ho");
+    assert!(constraint.validate(&files));
+    files.write("a.txt", "ha");
+    assert!(!constraint.validate(&files));
+    assert!(Constraint::<Files>::new().validate(&files));
+}
+
+/// Test accessing a tracked value without tracking.
+#[test]
+#[serial]
+fn test_untrack() {
+    #[memoize]
+    fn file_count(files: Tracked<Files>) -> usize {
+        Tracked::untrack(files).0.len()
+    }
+
+    let mut files = Files(HashMap::new());
+    files.write("untracked.calc", "1");
+    test!(miss: file_count(files.track()), 1);
+    files.write("untracked2.calc", "2");
+    test!(hit: file_count(files.track()), 1);
+}
+
+/// Test leaving out arguments with defaults.
+#[test]
+#[serial]
+fn test_track_default() {
+    #[memoize]
+    fn greeting(words: Tracked<Words>) -> String {
+        format!("{} {}", words.word_default(0), words.word(1, Some('!')))
+    }
+
+    let mut words = Words(vec!["hello".into(), "world".into()]);
+    test!(miss: greeting(words.track()), "hello world!");
+    test!(hit: greeting(words.track()), "hello world!");
+    words.0[0] = "bye".into();
+    test!(miss: greeting(words.track()), "bye world!");
+}
+
+/// A list of words.
+struct Words(Vec<String>);
+
+#[track]
+impl Words {
+    fn word(
+        &self,
+        index: usize,
+        #[track(default = None)] suffix: Option<char>,
+    ) -> String {
+        let mut word = self.0[index].clone();
+        word.extend(suffix);
+        word
+    }
+}
+
+/// Test tracking values behind smart pointers.
+#[test]
+#[serial]
+fn test_track_through_pointer() {
+    #[memoize]
+    fn pointee_count(files: Tracked<Files>) -> usize {
+        files.read("pointee.calc").len()
+    }
+
+    let files = || {
+        let mut files = Files(HashMap::new());
+        files.write("pointee.calc", "1 + 2");
+        files
+    };
+
+    let arc = std::sync::Arc::new(files());
+    let rc = std::rc::Rc::new(files());
+    let boxed = Box::new(files());
+    test!(miss: pointee_count(arc.track()), 5);
+    test!(hit: pointee_count(rc.track()), 5);
+    test!(hit: pointee_count(boxed.track()), 5);
+}
+
+/// Test combining two tracked values into one argument.
+#[test]
+#[serial]
+fn test_zip() {
+    #[memoize]
+    fn lookup(key: &str, deps: Zip<Tracked<Files>, Tracked<Settings>>) -> String {
+        let Zip(files, settings) = deps;
+        let count = settings.entries().into_iter().filter(|(k, _)| *k == key).count();
+        format!("{} {count}", files.read(key))
+    }
+
+    let mut files = Files(HashMap::new());
+    let mut settings = Settings(BTreeMap::new());
+    files.write("zip.calc", "1");
+    test!(miss: lookup("zip.calc", Zip(files.track(), settings.track())), "1 0");
+    test!(hit: lookup("zip.calc", Zip(files.track(), settings.track())), "1 0");
+    files.write("zip.calc", "2");
+    test!(miss: lookup("zip.calc", Zip(files.track(), settings.track())), "2 0");
+    settings.0.insert("zip.calc".into(), 1);
+    test!(miss: lookup("zip.calc", Zip(files.track(), settings.track())), "2 1");
+}
+
 /// Test tracking a trait object.
 #[test]
 #[serial]
@@ -157,22 +1352,67 @@ fn test_tracked_trait() {
         traity(loader.track(), path)
     }
 
-    let loader: &(dyn Loader) = &StaticLoader;
-    test!(miss: traity(loader.track(), Path::new("hi.rs")), [1, 2, 3]);
-    test!(hit: traity(loader.track(), Path::new("hi.rs")), [1, 2, 3]);
-    test!(miss: traity(loader.track(), Path::new("bye.rs")), [1, 2, 3]);
-    wrapper(loader, Path::new("hi.rs"));
+    let loader: &(dyn Loader) = &StaticLoader;
+    test!(miss: traity(loader.track(), Path::new("hi.rs")), [1, 2, 3]);
+    test!(hit: traity(loader.track(), Path::new("hi.rs")), [1, 2, 3]);
+    test!(miss: traity(loader.track(), Path::new("bye.rs")), [1, 2, 3]);
+    wrapper(loader, Path::new("hi.rs"));
+}
+
+#[track]
+trait Loader: Send + Sync {
+    fn load(&self, path: &Path) -> Result<Vec<u8>, String>;
+}
+
+struct StaticLoader;
+impl Loader for StaticLoader {
+    fn load(&self, _: &Path) -> Result<Vec<u8>, String> {
+        Ok(vec![1, 2, 3])
+    }
+}
+
+/// Test tracking a trait with a defaulted method.
+#[test]
+#[serial]
+fn test_tracked_trait_default() {
+    #[memoize]
+    fn describe(shape: Tracked<dyn Shape + '_>) -> String {
+        shape.describe()
+    }
+
+    let mut polygon = Polygon(3);
+    test!(miss: describe((&polygon as &dyn Shape).track()), "3 sides");
+    test!(hit: describe((&polygon as &dyn Shape).track()), "3 sides");
+    polygon.0 = 4;
+    test!(miss: describe((&polygon as &dyn Shape).track()), "4 sides");
+    test!(miss: describe((&Circle as &dyn Shape).track()), "round");
+    test!(hit: describe((&Circle as &dyn Shape).track()), "round");
 }
 
 #[track]
-trait Loader: Send + Sync {
-    fn load(&self, path: &Path) -> Result<Vec<u8>, String>;
+trait Shape {
+    fn sides(&self) -> u32;
+
+    fn describe(&self) -> String {
+        format!("{} sides", self.sides())
+    }
 }
 
-struct StaticLoader;
-impl Loader for StaticLoader {
-    fn load(&self, _: &Path) -> Result<Vec<u8>, String> {
-        Ok(vec![1, 2, 3])
+struct Polygon(u32);
+impl Shape for Polygon {
+    fn sides(&self) -> u32 {
+        self.0
+    }
+}
+
+struct Circle;
+impl Shape for Circle {
+    fn sides(&self) -> u32 {
+        0
+    }
+
+    fn describe(&self) -> String {
+        "round".into()
     }
 }
 
@@ -220,9 +1460,16 @@ fn test_kinds() {
         }
     }
 
+    #[memoize]
+    fn explicit(tester: Tracky) -> usize {
+        tester.explicit()
+    }
+
     let mut tester = Tester { data: "Hi".to_string() };
 
     let tracky = tester.track();
+    test!(miss: explicit(tracky), 2);
+    test!(hit: explicit(tracky), 2);
     test!(miss: selfie(tracky), "Hi");
     test!(miss: unconditional(tracky), "Short");
     test!(hit: unconditional(tracky), "Short");
@@ -231,147 +1478,626 @@ fn test_kinds() {
     tester.data.push('!');
 
     let tracky = tester.track();
+    test!(miss: explicit(tracky), 3);
     test!(miss: selfie(tracky), "Hi!");
     test!(miss: unconditional(tracky), "Short");
 
     tester.data.push_str(" Let's go.");
 
-    let tracky = tester.track();
-    test!(miss: unconditional(tracky), "Long");
+    let tracky = tester.track();
+    test!(miss: unconditional(tracky), "Long");
+}
+
+/// Test an optional tracked argument.
+#[test]
+#[serial]
+fn test_optional_tracked() {
+    #[memoize]
+    fn length(files: Option<Tracked<Files>>) -> usize {
+        files.map_or(0, |files| files.read("a.txt").len())
+    }
+
+    let mut files = Files(HashMap::new());
+    test!(miss: length(None), 0);
+    test!(miss: length(Some(files.track())), 0);
+    test!(hit: length(None), 0);
+    files.write("a.txt", "hello");
+    test!(hit: length(None), 0);
+    test!(miss: length(Some(files.track())), 5);
+    files.write("b.txt", "world");
+    test!(hit: length(Some(files.track())), 5);
+}
+
+/// Test hashing an argument upfront.
+#[test]
+#[serial]
+fn test_prehash() {
+    #[memoize(prehash(words))]
+    fn count(words: &[String], min: usize) -> usize {
+        words.iter().filter(|word| word.len() >= min).count()
+    }
+
+    let mut words = vec!["alpha".to_string(), "be".to_string()];
+    test!(miss: count(&words, 3), 1);
+    test!(hit: count(&words, 3), 1);
+    test!(miss: count(&words, 2), 2);
+    words.push("gamma".into());
+    test!(miss: count(&words, 3), 2);
+}
+
+/// Test with type alias.
+type Tracky<'a> = comemo::Tracked<'a, Tester>;
+
+/// A struct with some data.
+struct Tester {
+    data: String,
+}
+
+/// Tests different kinds of arguments.
+#[track]
+impl Tester {
+    /// Return value can borrow from self.
+    #[allow(clippy::needless_lifetimes)]
+    fn self_ref<'a>(&'a self) -> &'a str {
+        &self.data
+    }
+
+    /// Return value can borrow from argument.
+    fn arg_ref<'a>(&self, name: &'a str) -> &'a str {
+        name
+    }
+
+    /// Return value can borrow from both.
+    fn double_ref<'a>(&'a self, name: &'a str) -> &'a str {
+        if name.len() > self.data.len() {
+            name
+        } else {
+            &self.data
+        }
+    }
+
+    /// Normal method with owned argument.
+    fn by_value(&self, heavy: Heavy) -> usize {
+        self.data.len() + heavy.0.len()
+    }
+
+    /// Method with an explicitly typed receiver.
+    #[allow(clippy::needless_arbitrary_self_type)]
+    fn explicit(self: &Self) -> usize {
+        self.data.len()
+    }
+}
+
+/// Test empty type without methods.
+struct Empty;
+
+#[track]
+impl Empty {}
+
+/// Test tracking a type with a lifetime.
+#[test]
+#[serial]
+fn test_lifetime() {
+    #[comemo::memoize]
+    fn contains_hello(lifeful: Tracked<Lifeful>) -> bool {
+        lifeful.contains("hello")
+    }
+
+    let lifeful = Lifeful("hey");
+    test!(miss: contains_hello(lifeful.track()), false);
+    test!(hit: contains_hello(lifeful.track()), false);
+
+    let lifeful = Lifeful("hello");
+    test!(miss: contains_hello(lifeful.track()), true);
+    test!(hit: contains_hello(lifeful.track()), true);
+}
+
+/// Test tracked with lifetime.
+struct Lifeful<'a>(&'a str);
+
+#[track]
+impl<'a> Lifeful<'a> {
+    fn contains(&self, text: &str) -> bool {
+        self.0 == text
+    }
+}
+
+/// Test tracking a type with a type parameter.
+#[test]
+#[serial]
+fn test_type_generic() {
+    #[comemo::memoize]
+    fn describe(store: Tracked<Table<String>>, key: u32) -> String {
+        format!("{key} = {:?}", store.get(&key))
+    }
+
+    #[comemo::memoize]
+    fn total(store: Tracked<Table<u64>>) -> u64 {
+        (0..3).filter_map(|key| store.get(&key)).sum()
+    }
+
+    let mut names = Table(HashMap::new());
+    names.0.insert(1, "one".to_string());
+    test!(miss: describe(names.track(), 1), "1 = Some(\"one\")");
+    test!(hit: describe(names.track(), 1), "1 = Some(\"one\")");
+    names.0.insert(2, "two".to_string());
+    test!(hit: describe(names.track(), 1), "1 = Some(\"one\")");
+    test!(miss: describe(names.track(), 2), "2 = Some(\"two\")");
+
+    let mut counts = Table(HashMap::new());
+    counts.0.insert(0, 5);
+    test!(miss: total(counts.track()), 5);
+    counts.0.insert(7, 1);
+    test!(hit: total(counts.track()), 5);
+    counts.0.insert(2, 1);
+    test!(miss: total(counts.track()), 6);
+}
+
+/// A tracked type with a type parameter.
+struct Table<T>(HashMap<u32, T>);
+
+#[track]
+impl<T: Clone + Hash + Send + Sync> Table<T> {
+    fn get(&self, key: &u32) -> Option<T> {
+        self.0.get(key).cloned()
+    }
+}
+
+/// Test tracking a type with a const parameter.
+#[test]
+#[serial]
+fn test_const_generic() {
+    #[comemo::memoize]
+    fn diagonal(grid: Tracked<Grid<3>>) -> u32 {
+        (0..3).map(|i| grid.cell(i, i)).sum()
+    }
+
+    #[comemo::memoize]
+    fn first_row(grid: Tracked<Grid<2>>) -> [u32; 2] {
+        grid.row(0)
+    }
+
+    let mut grid = Grid([[1, 2, 3], [4, 5, 6], [7, 8, 9]]);
+    test!(miss: diagonal(grid.track()), 15);
+    grid.0[0][1] = 0;
+    test!(hit: diagonal(grid.track()), 15);
+    grid.0[1][1] = 0;
+    test!(miss: diagonal(grid.track()), 10);
+
+    let mut grid = Grid([[1, 2], [3, 4]]);
+    test!(miss: first_row(grid.track()), [1, 2]);
+    grid.0[1][0] = 0;
+    test!(hit: first_row(grid.track()), [1, 2]);
+}
+
+/// A tracked type with a const parameter.
+struct Grid<const N: usize>([[u32; N]; N]);
+
+#[track]
+impl<const N: usize> Grid<N> {
+    fn cell(&self, x: usize, y: usize) -> u32 {
+        self.0[y][x]
+    }
+
+    fn row(&self, y: usize) -> [u32; N] {
+        self.0[y]
+    }
+}
+
+/// Test a lifetime that is shared between a hashed and a tracked argument.
+#[test]
+#[serial]
+fn test_shared_lifetime() {
+    #[comemo::memoize]
+    fn greet<'a>(greeting: &'a str, lifeful: Tracked<'a, Lifeful<'a>>) -> bool {
+        lifeful.contains(greeting)
+    }
+
+    let lifeful = Lifeful("hello");
+    test!(miss: greet("hello", lifeful.track()), true);
+    test!(hit: greet("hello", lifeful.track()), true);
+    test!(miss: greet("hey", lifeful.track()), false);
+
+    let lifeful = Lifeful("hey");
+    test!(miss: greet("hello", lifeful.track()), false);
+    test!(hit: greet("hello", lifeful.track()), false);
+    test!(miss: greet("hey", lifeful.track()), true);
+}
+
+/// Test tracking a type with a chain of tracked values.
+#[test]
+#[serial]
+fn test_chain() {
+    #[comemo::memoize]
+    fn process(chain: Tracked<Chain>, value: u32) -> bool {
+        chain.contains(value)
+    }
+
+    let chain1 = Chain::new(1);
+    let chain3 = Chain::new(3);
+    let chain12 = Chain::insert(chain1.track(), 2);
+    let chain123 = Chain::insert(chain12.track(), 3);
+    let chain124 = Chain::insert(chain12.track(), 4);
+    let chain1245 = Chain::insert(chain124.track(), 5);
+
+    test!(miss: process(chain1.track(), 0), false);
+    test!(miss: process(chain1.track(), 1), true);
+    test!(miss: process(chain123.track(), 2), true);
+    test!(hit: process(chain124.track(), 2), true);
+    test!(hit: process(chain12.track(), 2), true);
+    test!(hit: process(chain1245.track(), 2), true);
+    test!(miss: process(chain1.track(), 2), false);
+    test!(hit: process(chain3.track(), 2), false);
+}
+
+/// Test that `Tracked<T>` is covariant over `T`.
+#[test]
+#[serial]
+#[allow(unused, clippy::needless_lifetimes)]
+fn test_variance() {
+    fn foo<'a>(_: Tracked<'a, Chain<'a>>) {}
+    fn bar<'a>(chain: Tracked<'a, Chain<'static>>) {
+        foo(chain);
+    }
+}
+
+/// Test tracked with lifetime.
+struct Chain<'a> {
+    // Need to override the lifetime here so that a `Tracked` is covariant over
+    // `Chain`.
+    outer: Option<Tracked<'a, Self, <Chain<'static> as Validate>::Constraint>>,
+    value: u32,
+}
+
+impl<'a> Chain<'a> {
+    /// Create a new chain entry point.
+    fn new(value: u32) -> Self {
+        Self { outer: None, value }
+    }
+
+    /// Insert a link into the chain.
+    fn insert(outer: Tracked<'a, Self>, value: u32) -> Self {
+        Chain { outer: Some(outer), value }
+    }
+}
+
+#[track]
+impl<'a> Chain<'a> {
+    fn contains(&self, value: u32) -> bool {
+        self.value == value || self.outer.map_or(false, |outer| outer.contains(value))
+    }
+}
+
+/// Test deriving `Track` for a struct.
+#[test]
+#[serial]
+fn test_derive_track() {
+    #[comemo::memoize]
+    fn area(image: Tracked<Image>) -> u32 {
+        image.width() * image.height()
+    }
+
+    let mut image = Image { width: 2, height: 3, pixels: vec![0; 6] };
+    test!(miss: area(image.track()), 6);
+    image.pixels.fill(1);
+    test!(hit: area(image.track()), 6);
+    image.height = 4;
+    test!(miss: area(image.track()), 8);
+}
+
+/// Test tracking the public fields of a struct.
+#[test]
+#[serial]
+fn test_track_struct() {
+    #[comemo::memoize]
+    fn describe(window: Tracked<Window>) -> String {
+        format!("{} ({}x{})", window.title(), window.width(), window.height())
+    }
+
+    let mut window = Window {
+        title: "Editor".into(),
+        width: 80,
+        height: 24,
+        redraws: 0,
+    };
+    test!(miss: describe(window.track()), "Editor (80x24)");
+    window.redraws += 1;
+    test!(hit: describe(window.track()), "Editor (80x24)");
+    window.width = 100;
+    test!(miss: describe(window.track()), "Editor (100x24)");
+}
+
+/// A struct whose public fields are tracked.
+#[track]
+struct Window {
+    pub title: String,
+    pub width: u32,
+    pub height: u32,
+    redraws: u32,
+}
+
+/// A struct with derived tracked getters.
+#[derive(Track)]
+struct Image {
+    width: u32,
+    height: u32,
+    pixels: Vec<u8>,
+}
+
+/// Test tracking a method that returns an iterator.
+#[test]
+#[serial]
+fn test_collect_hash() {
+    #[comemo::memoize]
+    fn total(settings: Tracked<Settings>) -> u32 {
+        settings.entries().into_iter().map(|(_, v)| *v).sum()
+    }
+
+    let mut settings = Settings(BTreeMap::new());
+    settings.0.insert("a".into(), 1);
+    settings.0.insert("b".into(), 2);
+    test!(miss: total(settings.track()), 3);
+    test!(hit: total(settings.track()), 3);
+    settings.0.insert("c".into(), 3);
+    test!(miss: total(settings.track()), 6);
+    settings.0.insert("c".into(), 4);
+    test!(miss: total(settings.track()), 7);
+}
+
+/// A map-like tracked type.
+struct Settings(BTreeMap<String, u32>);
+
+#[track]
+impl Settings {
+    #[track(collect_hash)]
+    fn entries(&self) -> impl Iterator<Item = (&str, &u32)> {
+        self.0.iter().map(|(k, v)| (k.as_str(), v))
+    }
+}
+
+/// Test using content hashes instead of `Hash`.
+#[test]
+#[serial]
+fn test_content_hash() {
+    #[memoize(content_hash(blob))]
+    fn size(blob: &Blob) -> usize {
+        blob.data.len()
+    }
+
+    #[memoize]
+    fn total(store: Tracked<Store>) -> usize {
+        store.blob(0).data.len() + store.blob(1).data.len()
+    }
+
+    let a = Blob { id: 1, data: vec![1, 2] };
+    let b = Blob { id: 2, data: vec![1, 2, 3] };
+    test!(miss: size(&a), 2);
+    test!(hit: size(&a), 2);
+    test!(miss: size(&b), 3);
+
+    let mut store = Store(vec![a, b]);
+    test!(miss: total(store.track()), 5);
+    store.0[1].data.push(4);
+    test!(hit: total(store.track()), 5);
+    store.0[1].id = 3;
+    test!(miss: total(store.track()), 6);
 }
 
-/// Test with type alias.
-type Tracky<'a> = comemo::Tracked<'a, Tester>;
+/// A blob that is identified by its id.
+#[derive(Clone)]
+struct Blob {
+    id: u128,
+    data: Vec<u8>,
+}
 
-/// A struct with some data.
-struct Tester {
-    data: String,
+impl comemo::ContentHash for Blob {
+    fn content_hash(&self) -> u128 {
+        self.id
+    }
 }
 
-/// Tests different kinds of arguments.
+/// A store of blobs.
+struct Store(Vec<Blob>);
+
 #[track]
-impl Tester {
-    /// Return value can borrow from self.
-    #[allow(clippy::needless_lifetimes)]
-    fn self_ref<'a>(&'a self) -> &'a str {
-        &self.data
+impl Store {
+    #[track(content_hash)]
+    fn blob(&self, index: usize) -> Blob {
+        self.0[index].clone()
     }
+}
 
-    /// Return value can borrow from argument.
-    fn arg_ref<'a>(&self, name: &'a str) -> &'a str {
-        name
+/// Test validating a method through a rolling hash.
+#[test]
+#[serial]
+fn test_rolling_hash() {
+    #[memoize]
+    fn size(log: Tracked<Log>) -> usize {
+        log.entries().len()
     }
 
-    /// Return value can borrow from both.
-    fn double_ref<'a>(&'a self, name: &'a str) -> &'a str {
-        if name.len() > self.data.len() {
-            name
-        } else {
-            &self.data
+    let mut log = Log::default();
+    log.append(b"ab");
+    test!(miss: size(log.track()), 2);
+    test!(hit: size(log.track()), 2);
+    log.append(b"c");
+    test!(miss: size(log.track()), 3);
+}
+
+/// An append-only log that maintains its own hash.
+#[derive(Default)]
+struct Log {
+    data: Vec<u8>,
+    hash: u64,
+}
+
+impl Log {
+    fn append(&mut self, bytes: &[u8]) {
+        for &byte in bytes {
+            self.hash = (self.hash ^ u64::from(byte)).wrapping_mul(0x100000001b3);
         }
+        self.data.extend_from_slice(bytes);
     }
+}
 
-    /// Normal method with owned argument.
-    fn by_value(&self, heavy: Heavy) -> usize {
-        self.data.len() + heavy.0.len()
+#[track]
+impl Log {
+    #[track(rolling_hash = self.hash)]
+    fn entries(&self) -> Vec<u8> {
+        self.data.clone()
     }
 }
 
-/// Test empty type without methods.
-struct Empty;
+/// Test that calls to untracked methods don't invalidate.
+#[test]
+#[serial]
+fn test_untracked() {
+    #[memoize]
+    fn describe(counter: Tracked<Visits>) -> String {
+        format!("{} after {} visits", counter.name(), counter.visits())
+    }
+
+    let mut visits = Visits { name: "home".into(), visits: 0 };
+    test!(miss: describe(visits.track()), "home after 0 visits");
+    visits.visits = 5;
+    test!(hit: describe(visits.track()), "home after 0 visits");
+    visits.name = "about".into();
+    test!(miss: describe(visits.track()), "about after 5 visits");
+}
+
+/// A page with a visit counter.
+struct Visits {
+    name: String,
+    visits: usize,
+}
 
 #[track]
-impl Empty {}
+impl Visits {
+    fn name(&self) -> String {
+        self.name.clone()
+    }
 
-/// Test tracking a type with a lifetime.
+    #[track(untracked)]
+    fn visits(&self) -> usize {
+        self.visits
+    }
+}
+
+/// Test tracking an interner that returns `&'static` handles.
 #[test]
 #[serial]
-fn test_lifetime() {
+fn test_interner() {
     #[comemo::memoize]
-    fn contains_hello(lifeful: Tracked<Lifeful>) -> bool {
-        lifeful.contains("hello")
+    fn roundtrip(name: &str, interner: Tracked<Interner>) -> Option<&'static str> {
+        interner.symbol(name).map(|id| interner.resolve(id))
     }
 
-    let lifeful = Lifeful("hey");
-    test!(miss: contains_hello(lifeful.track()), false);
-    test!(hit: contains_hello(lifeful.track()), false);
+    let mut interner = Interner::default();
+    let id = interner.intern("alpha");
+    interner.intern("beta");
+    test!(miss: roundtrip("alpha", interner.track()), Some("alpha"));
+    test!(hit: roundtrip("alpha", interner.track()), Some("alpha"));
+    test!(miss: roundtrip("gamma", interner.track()), None);
+    interner.intern("gamma");
+    test!(hit: roundtrip("alpha", interner.track()), Some("alpha"));
+    test!(miss: roundtrip("gamma", interner.track()), Some("gamma"));
+    interner.names[id.0 as usize] = "ALPHA";
+    test!(miss: roundtrip("alpha", interner.track()), Some("ALPHA"));
+}
 
-    let lifeful = Lifeful("hello");
-    test!(miss: contains_hello(lifeful.track()), true);
-    test!(hit: contains_hello(lifeful.track()), true);
+/// An interned symbol.
+#[derive(Debug, Copy, Clone, Eq, PartialEq, Hash)]
+struct SymbolId(u32);
+
+/// Interns static strings.
+#[derive(Default)]
+struct Interner {
+    ids: HashMap<&'static str, SymbolId>,
+    names: Vec<&'static str>,
 }
 
-/// Test tracked with lifetime.
-struct Lifeful<'a>(&'a str);
+impl Interner {
+    fn intern(&mut self, name: &'static str) -> SymbolId {
+        *self.ids.entry(name).or_insert_with(|| {
+            self.names.push(name);
+            SymbolId(self.names.len() as u32 - 1)
+        })
+    }
+}
 
 #[track]
-impl<'a> Lifeful<'a> {
-    fn contains(&self, text: &str) -> bool {
-        self.0 == text
+impl Interner {
+    fn symbol(&self, name: &str) -> Option<SymbolId> {
+        self.ids.get(name).copied()
+    }
+
+    fn resolve(&self, id: SymbolId) -> &'static str {
+        self.names[id.0 as usize]
     }
 }
 
-/// Test tracking a type with a chain of tracked values.
+/// Test that inert method attributes are kept on the tracked surface.
 #[test]
 #[serial]
-fn test_chain() {
+#[allow(deprecated)]
+fn test_surface_attributes() {
     #[comemo::memoize]
-    fn process(chain: Tracked<Chain>, value: u32) -> bool {
-        chain.contains(value)
+    fn describe(counter: Tracked<Counter>) -> String {
+        format!("{} ({})", counter.count(), counter.legacy_count())
     }
 
-    let chain1 = Chain::new(1);
-    let chain3 = Chain::new(3);
-    let chain12 = Chain::insert(chain1.track(), 2);
-    let chain123 = Chain::insert(chain12.track(), 3);
-    let chain124 = Chain::insert(chain12.track(), 4);
-    let chain1245 = Chain::insert(chain124.track(), 5);
+    let counter = Counter(3);
+    test!(miss: describe(counter.track()), "3 (3)");
+    test!(hit: describe(counter.track()), "3 (3)");
+}
 
-    test!(miss: process(chain1.track(), 0), false);
-    test!(miss: process(chain1.track(), 1), true);
-    test!(miss: process(chain123.track(), 2), true);
-    test!(hit: process(chain124.track(), 2), true);
-    test!(hit: process(chain12.track(), 2), true);
-    test!(hit: process(chain1245.track(), 2), true);
-    test!(miss: process(chain1.track(), 2), false);
-    test!(hit: process(chain3.track(), 2), false);
+/// A simple counter.
+struct Counter(u32);
+
+#[track]
+impl Counter {
+    /// The current count.
+    #[must_use]
+    fn count(&self) -> u32 {
+        self.0
+    }
+
+    #[deprecated = "use `count` instead"]
+    fn legacy_count(&self) -> u32 {
+        self.0
+    }
 }
 
-/// Test that `Tracked<T>` is covariant over `T`.
+/// Test tracking a pinned value through a wrapper.
 #[test]
 #[serial]
-#[allow(unused, clippy::needless_lifetimes)]
-fn test_variance() {
-    fn foo<'a>(_: Tracked<'a, Chain<'a>>) {}
-    fn bar<'a>(chain: Tracked<'a, Chain<'static>>) {
-        foo(chain);
+fn test_pinned() {
+    #[comemo::memoize]
+    fn double(pinned: Tracked<Pinned>) -> u32 {
+        2 * pinned.value()
     }
-}
 
-/// Test tracked with lifetime.
-struct Chain<'a> {
-    // Need to override the lifetime here so that a `Tracked` is covariant over
-    // `Chain`.
-    outer: Option<Tracked<'a, Self, <Chain<'static> as Validate>::Constraint>>,
-    value: u32,
+    let state = Box::pin(Unmovable(4, std::marker::PhantomPinned));
+    test!(miss: double(Pinned(state.as_ref()).track()), 8);
+    test!(hit: double(Pinned(state.as_ref()).track()), 8);
 }
 
-impl<'a> Chain<'a> {
-    /// Create a new chain entry point.
-    fn new(value: u32) -> Self {
-        Self { outer: None, value }
-    }
+/// A value that must stay pinned.
+struct Unmovable(u32, std::marker::PhantomPinned);
 
-    /// Insert a link into the chain.
-    fn insert(outer: Tracked<'a, Self>, value: u32) -> Self {
-        Chain { outer: Some(outer), value }
+impl Unmovable {
+    fn value(self: std::pin::Pin<&Self>) -> u32 {
+        self.0
     }
 }
 
+/// A trackable wrapper around a pinned reference.
+struct Pinned<'a>(std::pin::Pin<&'a Unmovable>);
+
 #[track]
-impl<'a> Chain<'a> {
-    fn contains(&self, value: u32) -> bool {
-        self.value == value || self.outer.map_or(false, |outer| outer.contains(value))
+impl<'a> Pinned<'a> {
+    fn value(&self) -> u32 {
+        self.0.as_ref().value()
     }
 }
 
@@ -420,6 +2146,143 @@ impl Emitter {
     }
 }
 
+/// Test that validation only clones for mutable calls.
+#[test]
+#[serial]
+fn test_mixed_mutability() {
+    #[comemo::memoize]
+    fn log(mut journal: TrackedMut<Journal>, msg: &str) -> usize {
+        if journal.peek() != Some(msg.into()) {
+            journal.write(msg);
+        }
+        journal.peek().map_or(0, |last| last.len())
+    }
+
+    let mut journal = Journal(vec![]);
+    test!(miss: log(journal.track_mut(), "hi"), 2);
+    test!(miss: log(journal.track_mut(), "hi"), 2);
+    let clones = JOURNAL_CLONES.with(Cell::get);
+    test!(hit: log(journal.track_mut(), "hi"), 2);
+    assert_eq!(JOURNAL_CLONES.with(Cell::get), clones);
+    journal.0.clear();
+    test!(hit: log(journal.track_mut(), "hi"), 2);
+    assert_eq!(JOURNAL_CLONES.with(Cell::get), clones + 1);
+    assert_eq!(journal.0, ["hi"]);
+}
+
+thread_local! {
+    /// How often a `Journal` was cloned on this thread.
+    static JOURNAL_CLONES: Cell<usize> = const { Cell::new(0) };
+}
+
+/// A tracked type with both mutable and immutable methods.
+struct Journal(Vec<String>);
+
+impl Clone for Journal {
+    fn clone(&self) -> Self {
+        JOURNAL_CLONES.with(|cell| cell.set(cell.get() + 1));
+        Self(self.0.clone())
+    }
+}
+
+#[track]
+impl Journal {
+    fn write(&mut self, msg: &str) {
+        self.0.push(msg.into());
+    }
+
+    fn peek(&self) -> Option<String> {
+        self.0.last().cloned()
+    }
+}
+
+/// Test a mutable tracked method with a return value.
+#[test]
+#[serial]
+fn test_mutable_return() {
+    #[comemo::memoize]
+    fn pair(mut ticker: TrackedMut<Ticker>) -> [u64; 2] {
+        [ticker.next(), ticker.next()]
+    }
+
+    let mut ticker = Ticker(0);
+    test!(miss: pair(ticker.track_mut()), [0, 1]);
+    test!(miss: pair(ticker.track_mut()), [2, 3]);
+    ticker.0 = 0;
+    test!(hit: pair(ticker.track_mut()), [0, 1]);
+    assert_eq!(ticker.0, 2);
+    test!(hit: pair(ticker.track_mut()), [2, 3]);
+    assert_eq!(ticker.0, 4);
+}
+
+/// A tracked type whose mutable method returns a value.
+#[derive(Clone)]
+struct Ticker(u64);
+
+#[track]
+impl Ticker {
+    fn next(&mut self) -> u64 {
+        self.0 += 1;
+        self.0 - 1
+    }
+}
+
+/// Test emitting into a callback sink.
+#[test]
+#[serial]
+fn test_sink() {
+    #[comemo::memoize]
+    fn walk(tree: Tracked<Tree>, depth: usize, emit: &mut dyn FnMut(String)) {
+        emit(format!("enter {depth}"));
+        if depth < tree.depth() {
+            walk(tree, depth + 1, emit);
+        }
+        emit(format!("exit {depth}"));
+    }
+
+    let mut tree = Tree(1);
+    let mut first = vec![];
+    test!(miss: walk(tree.track(), 0, &mut |node| first.push(node)), ());
+
+    let mut second = vec![];
+    test!(hit: walk(tree.track(), 0, &mut |node| second.push(node)), ());
+    assert_eq!(first, ["enter 0", "enter 1", "exit 1", "exit 0"]);
+    assert_eq!(first, second);
+
+    tree.0 = 2;
+    let mut third = vec![];
+    test!(miss: walk(tree.track(), 0, &mut |node| third.push(node)), ());
+    assert_eq!(third, ["enter 0", "enter 1", "enter 2", "exit 2", "exit 1", "exit 0"]);
+}
+
+/// Test that a sink can call the memoized function while it is replayed.
+#[test]
+#[serial]
+fn test_sink_reentrant() {
+    // A bounded cache has a single shard, so the nested call always needs the
+    // same lock as the replayed one.
+    #[comemo::memoize(capacity = 16)]
+    fn announce(n: usize, emit: &mut dyn FnMut(usize)) -> usize {
+        emit(n);
+        n
+    }
+
+    test!(miss: announce(1, &mut |_| {}), 1);
+    let mut nested = vec![];
+    test!(hit: announce(1, &mut |n| nested.push(announce(n + 1, &mut |_| {}))), 1);
+    assert_eq!(nested, [2]);
+}
+
+/// A tree that only exposes its depth.
+struct Tree(usize);
+
+#[track]
+impl Tree {
+    fn depth(&self) -> usize {
+        self.0
+    }
+}
+
 /// A non-copy struct that is passed by value to a tracked method.
 #[derive(Clone, PartialEq, Hash)]
 struct Heavy(String);
@@ -441,6 +2304,113 @@ fn test_impure_tracked_method() {
     call(Impure.track());
 }
 
+/// Test a tracked method argument whose `Borrow` doesn't round-trip.
+#[test]
+#[serial]
+#[cfg(debug_assertions)]
+#[should_panic(expected = "comemo: the `ToOwned` and `Borrow` implementations of a \
+                           tracked method argument do not round-trip")]
+fn test_broken_borrow() {
+    #[comemo::memoize]
+    fn call(registry: Tracked<Registry>) -> usize {
+        registry.lookup(Name::new("alpha"))
+    }
+
+    call(Registry.track());
+}
+
+/// A borrowed name.
+#[derive(PartialEq, Hash)]
+#[repr(transparent)]
+struct Name(str);
+
+impl Name {
+    fn new(name: &str) -> &Self {
+        // Safety: `Name` is a transparent wrapper around `str`.
+        unsafe { &*(name as *const str as *const Name) }
+    }
+}
+
+impl ToOwned for Name {
+    type Owned = NameBuf;
+
+    fn to_owned(&self) -> NameBuf {
+        NameBuf(self.0.to_string())
+    }
+}
+
+/// An owned name whose `Borrow` implementation is broken.
+#[derive(Clone, PartialEq, Hash)]
+struct NameBuf(String);
+
+impl std::borrow::Borrow<Name> for NameBuf {
+    fn borrow(&self) -> &Name {
+        Name::new(&self.0[1..])
+    }
+}
+
+impl std::ops::Deref for NameBuf {
+    type Target = Name;
+
+    fn deref(&self) -> &Name {
+        Name::new(&self.0)
+    }
+}
+
+/// Resolves names.
+struct Registry;
+
+#[track]
+impl Registry {
+    fn lookup(&self, name: &Name) -> usize {
+        name.0.len()
+    }
+}
+
+/// Test bypassing the cache of a function that calls an impure tracked method.
+#[test]
+#[serial]
+#[cfg(debug_assertions)]
+fn test_impure_log_and_bypass() {
+    #[comemo::memoize]
+    fn call(impure: Tracked<Impure>) -> bool {
+        impure.impure() != impure.impure()
+    }
+
+    comemo::set_impurity_policy(comemo::ImpurityPolicy::LogAndBypass);
+    test!(miss: call(Impure.track()), true);
+    test!(miss: call(Impure.track()), true);
+    comemo::set_impurity_policy(comemo::ImpurityPolicy::Panic);
+}
+
+/// Test that the diagnostic hook is informed about impurities.
+#[test]
+#[serial]
+#[cfg(debug_assertions)]
+fn test_diagnostic_hook() {
+    static DIAGNOSTICS: std::sync::Mutex<Vec<comemo::Diagnostic>> =
+        std::sync::Mutex::new(Vec::new());
+
+    #[comemo::memoize]
+    fn audited(impure: Tracked<Impure>) -> bool {
+        impure.impure() != impure.impure()
+    }
+
+    comemo::set_diagnostic_hook(Some(|diagnostic| {
+        DIAGNOSTICS.lock().unwrap().push(diagnostic)
+    }));
+    comemo::set_impurity_policy(comemo::ImpurityPolicy::LogAndBypass);
+    test!(miss: audited(Impure.track()), true);
+    comemo::set_impurity_policy(comemo::ImpurityPolicy::Panic);
+    comemo::set_diagnostic_hook(None);
+
+    let diagnostics = DIAGNOSTICS.lock().unwrap();
+    assert_eq!(diagnostics.len(), 2);
+    assert!(diagnostics.iter().all(|d| d.function.ends_with("audited")));
+    assert_eq!(diagnostics[0].kind, comemo::DiagnosticKind::ConflictingReturn);
+    assert_eq!(diagnostics[1].kind, comemo::DiagnosticKind::Bypassed);
+}
+
 struct Impure;
 
 #[track]
@@ -467,3 +2437,84 @@ fn test_with_disabled() {
     test!(miss: disabled(2000), 2000);
     test!(hit: disabled(2000), 2000);
 }
+
+/// Test a condition that accesses `self` and moved arguments.
+#[test]
+#[serial]
+fn test_enabled_with_self() {
+    #[derive(Hash)]
+    struct Page(Vec<u8>);
+
+    impl Page {
+        #[memoize(enabled = self.0.len() >= 3)]
+        fn checksum(&self) -> u32 {
+            self.0.iter().map(|&b| b as u32).sum()
+        }
+
+        #[memoize(enabled = self.0.len() + extra.len() >= 3)]
+        fn join(self, extra: Vec<u8>) -> Vec<u8> {
+            self.0.into_iter().chain(extra).collect()
+        }
+    }
+
+    test!(miss: Page(vec![1]).checksum(), 1);
+    test!(miss: Page(vec![1]).checksum(), 1);
+    test!(miss: Page(vec![1, 2, 3]).checksum(), 6);
+    test!(hit: Page(vec![1, 2, 3]).checksum(), 6);
+    test!(miss: Page(vec![1]).join(vec![2]), vec![1, 2]);
+    test!(miss: Page(vec![1]).join(vec![2]), vec![1, 2]);
+    test!(miss: Page(vec![1]).join(vec![2, 3]), vec![1, 2, 3]);
+    test!(hit: Page(vec![1]).join(vec![2, 3]), vec![1, 2, 3]);
+}
+
+/// Test comparing a prehashed value against a raw one.
+#[test]
+fn test_prehashed_hash_eq() {
+    let prehashed = Prehashed::new(vec![1, 2, 3]);
+    assert!(prehashed.hash_eq(&vec![1, 2, 3]));
+    assert!(!prehashed.hash_eq(&vec![1, 2]));
+}
+
+/// Test hashing a prehashed value lazily.
+#[test]
+fn test_prehashed_lazy() {
+    use std::collections::hash_map::DefaultHasher;
+    use std::hash::Hasher;
+
+    fn hash<T: Hash>(value: &T) -> u64 {
+        let mut state = DefaultHasher::new();
+        value.hash(&mut state);
+        state.finish()
+    }
+
+    let eager = Prehashed::new(vec![1, 2, 3]);
+    let lazy = LazyPrehashed::new(vec![1, 2, 3]);
+    assert!(lazy == LazyPrehashed::new(vec![1, 2, 3]));
+    assert!(lazy != LazyPrehashed::new(vec![1, 2]));
+    assert_eq!(hash(&lazy), hash(&eager));
+}
+
+/// Test mutating a prehashed value through a guard.
+#[test]
+fn test_prehashed_get_mut() {
+    let mut prehashed = Prehashed::new(vec![1, 2]);
+    {
+        let mut items = prehashed.get_mut();
+        items.push(3);
+        items.push(4);
+    }
+    assert!(prehashed == Prehashed::new(vec![1, 2, 3, 4]));
+    assert!(prehashed.hash_eq(&vec![1, 2, 3, 4]));
+}
+
+/// Test serializing a prehashed value.
+#[test]
+#[cfg(feature = "serde")]
+fn test_prehashed_serde() {
+    let prehashed = Prehashed::new(vec![1, 2, 3]);
+    let json = serde_json::to_string(&prehashed).unwrap();
+    assert_eq!(json, "[1,2,3]");
+    let restored: Prehashed<Vec<i32>> = serde_json::from_str(&json).unwrap();
+    assert_eq!(restored, prehashed);
+    assert_eq!(*restored, [1, 2, 3]);
+}